@@ -0,0 +1,36 @@
+@group(0) @binding(3) var<storage, read> resolution: vec2<f32>;
+@group(0) @binding(8) var accumulation: texture_storage_2d<rgba32float, read_write>;
+@group(0) @binding(9) var<storage, read> frame_count: u32;
+@group(0) @binding(17) var<uniform> render_settings: RenderSettings;
+@group(0) @binding(18) var radiance: texture_2d<f32>;
+@group(0) @binding(19) var hdr: texture_storage_2d<rgba32float, write>;
+
+struct RenderSettings {
+    max_bounces: u32,
+    samples_per_pixel: u32,
+    samples_per_frame: u32,
+}
+
+// Averages this frame's samples into the accumulation texture and copies the average to `hdr`, the
+// post-processing passes work on that copy so the history stays untouched
+@compute @workgroup_size(8, 8, 1)
+fn accumulate(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
+    if (f32(location.x) >= resolution.x || f32(location.y) >= resolution.y) {
+        return;
+    }
+
+    // Alpha holds the number of samples averaged so far, see `trace_pixel` in raytracer.wgsl
+    let previous = textureLoad(accumulation, location);
+    let sample_count = select(previous.a, 0.0, frame_count == 0u);
+    var average = vec4<f32>(previous.rgb, sample_count);
+    if (render_settings.samples_per_pixel == 0u || sample_count < f32(render_settings.samples_per_pixel)) {
+        // The average of this frame's samples and their count, see `trace_pixel`
+        let color = textureLoad(radiance, location, 0);
+        let total = sample_count + color.a;
+        average = vec4<f32>((previous.rgb * sample_count + color.rgb * color.a) / total, total);
+        textureStore(accumulation, location, average);
+    }
+
+    textureStore(hdr, location, average);
+}
@@ -0,0 +1,43 @@
+@group(0) @binding(3) var<storage, read> resolution: vec2<f32>;
+@group(0) @binding(8) var accumulation: texture_2d<f32>;
+@group(0) @binding(19) var hdr: texture_storage_2d<rgba32float, write>;
+
+const RADIUS = 2;
+
+// Squashes radiance into [0, 1) so bright pixels do not dominate the color distance
+fn compress(color: vec3<f32>) -> vec3<f32> {
+    return color / (vec3<f32>(1.0) + color);
+}
+
+// Edge aware blur of the running average. Neighbours are weighted by their distance and by how
+// close their color is to the center, the tolerated difference shrinks with the number of samples
+// so a converged image is left nearly untouched.
+@compute @workgroup_size(8, 8, 1)
+fn denoise(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let size = vec2<i32>(resolution);
+    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
+    if (location.x >= size.x || location.y >= size.y) {
+        return;
+    }
+
+    let center = textureLoad(accumulation, location, 0);
+    // Monte Carlo noise falls off with the square root of the sample count
+    let sigma = 0.5 / sqrt(max(center.a, 1.0));
+
+    var sum = vec3<f32>(0.0);
+    var weight_sum = 0.0;
+    for (var y = -RADIUS; y <= RADIUS; y = y + 1) {
+        for (var x = -RADIUS; x <= RADIUS; x = x + 1) {
+            let neighbour_location = clamp(location + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            let neighbour = textureLoad(accumulation, neighbour_location, 0).rgb;
+            let difference = compress(neighbour) - compress(center.rgb);
+            let spatial = f32(x * x + y * y) / f32(2 * RADIUS * RADIUS);
+            let range = dot(difference, difference) / (2.0 * sigma * sigma);
+            let weight = exp(-spatial - range);
+            sum += neighbour * weight;
+            weight_sum += weight;
+        }
+    }
+
+    textureStore(hdr, location, vec4<f32>(sum / weight_sum, center.a));
+}
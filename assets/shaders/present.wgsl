@@ -0,0 +1,17 @@
+@group(0) @binding(0) var texture: texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(3) var<storage, read> resolution: vec2<f32>;
+@group(0) @binding(19) var hdr: texture_2d<f32>;
+
+// Copies `hdr` into the texture the sprite shows, whatever is still above one clips. The values stay
+// linear, bevy samples the texture as linear and the sRGB swapchain encodes them once, with the
+// same curve the png output uses.
+@compute @workgroup_size(8, 8, 1)
+fn present(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
+    if (f32(location.x) >= resolution.x || f32(location.y) >= resolution.y) {
+        return;
+    }
+
+    let color = textureLoad(hdr, location, 0).rgb;
+    textureStore(texture, location, vec4<f32>(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0));
+}
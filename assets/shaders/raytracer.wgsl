@@ -1,50 +1,440 @@
-@group(0) @binding(0) var texture: texture_storage_2d<rgba8unorm, read_write>;
 @group(0) @binding(1) var<storage, read> camera_position: vec3<f32>;
 @group(0) @binding(2) var<storage, read> camera_direction: vec3<f32>;
 @group(0) @binding(3) var<storage, read> resolution: vec2<f32>;
 @group(0) @binding(4) var<storage, read> aspect_ratio: f32;
 @group(0) @binding(5) var<storage, read> inverse_view_matrix: mat4x4<f32>;
 @group(0) @binding(6) var<storage, read> spheres: array<Sphere>;
+@group(0) @binding(7) var<storage, read> materials: array<Material>;
+@group(0) @binding(8) var accumulation: texture_2d<f32>;
+@group(0) @binding(9) var<storage, read> frame_count: u32;
+@group(0) @binding(10) var<storage, read> bvh_nodes: array<BvhNode>;
+@group(0) @binding(11) var<storage, read> bvh_primitives: array<PrimitiveRef>;
+@group(0) @binding(12) var<storage, read> vertices: array<vec4<f32>>;
+@group(0) @binding(13) var<storage, read> normals: array<vec4<f32>>;
+@group(0) @binding(14) var<storage, read> triangles: array<Triangle>;
+@group(0) @binding(15) var<storage, read> lights: array<Light>;
+@group(0) @binding(16) var<storage, read> environment: Environment;
+@group(0) @binding(17) var<uniform> render_settings: RenderSettings;
+@group(0) @binding(18) var radiance: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(20) var<uniform> camera_lens: CameraLens;
+// Seconds on the animation timeline
+@group(0) @binding(21) var<uniform> render_time: f32;
+// Texels in rows from the top with the conditional distribution in alpha, followed by the marginal
+// distribution, see `EnvironmentMap::gpu_texels`
+@group(0) @binding(22) var<storage, read> environment_map: array<vec4<f32>>;
+@group(0) @binding(23) var<storage, read> mesh_motions: array<Motion>;
 
-// textureLoad to read from texture
-// let value: vec4<f32> = textureLoad(texture, fragCoord + vec2<i32>(offset_x, offset_y)); 
+const PI = 3.141592653589793238462643;
+const EPSILON = 0.0001;
+const FAR_AWAY = 1e30;
+// Deep enough for any tree, `Bvh::build` stops at `MAX_DEPTH`
+const BVH_STACK_SIZE = 32;
 
-@compute @workgroup_size(8, 8, 1)
-fn init(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
-    let fragCoord = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
-    let color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+const PRIMITIVE_NONE = 0u;
+const PRIMITIVE_SPHERE = 1u;
+const PRIMITIVE_TRIANGLE = 2u;
 
-    textureStore(texture, fragCoord, color);
-}
-
-const PI = 3.141592653589793238462643;
+const LIGHT_POINT = 0u;
+const LIGHT_DIRECTIONAL = 1u;
+const LIGHT_SPOT = 2u;
 
 struct Ray {
     origin: vec3<f32>,
-    direction: vec3<f32>
+    direction: vec3<f32>,
+    // How far the camera's shutter is from opening to closing, from 0 to 1. Moving primitives are
+    // intersected where they are at that moment.
+    time: f32
+}
+
+// Scales and rotates around the pivot of its `Motion`, then translates
+struct MotionTransform {
+    translation: vec3<f32>,
+    scale: vec3<f32>,
+    // Quaternion
+    rotation: vec4<f32>,
+}
+
+// `start` while the shutter opens and `end` when it closes, see `Motion` in motion.rs
+struct Motion {
+    pivot: vec3<f32>,
+    start: MotionTransform,
+    end: MotionTransform,
 }
 
 struct Sphere {
     position: vec3<f32>,
-    radius: f32
+    radius: f32,
+    motion: Motion,
+    material_index: u32
+}
+
+// Indices point into `vertices` and `normals`, a zero normal means the mesh is flat shaded
+struct Triangle {
+    indices: vec3<u32>,
+    material_index: u32,
+    // Into `mesh_motions`
+    mesh_index: u32
+}
+
+struct Material {
+    albedo: vec3<f32>,
+    roughness: f32,
+    emission: vec3<f32>,
+    metallic: f32,
+    ior: f32,
+    transmission: f32
+}
+
+// Punctual light, point and spot intensities are in candela and directional ones in lux
+struct Light {
+    position: vec3<f32>,
+    light_type: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    inner_cone_cos: f32,
+    outer_cone_cos: f32
+}
+
+// Interior nodes have no primitives and `left_first` is the left child, the right one follows it.
+// Leaves point `left_first` at their first entry in `bvh_primitives`.
+struct BvhNode {
+    aabb_min: vec3<f32>,
+    left_first: u32,
+    aabb_max: vec3<f32>,
+    primitive_count: u32
+}
+
+struct PrimitiveRef {
+    primitive_type: u32,
+    primitive_id: u32
 }
 
+struct Environment {
+    sky_horizon: vec3<f32>,
+    intensity: f32,
+    sky_zenith: vec3<f32>,
+    // Radians around +Y, from +Z towards +X
+    rotation: f32,
+    ground: vec3<f32>,
+    // Zero without an environment map
+    map_width: u32,
+    map_height: u32,
+    // Physical sky, see `PhysicalSky::apply`. The sun has no size without one.
+    sun_direction: vec3<f32>,
+    sun_cos_radius: f32,
+    sun_radiance: vec3<f32>,
+    perez_a: vec3<f32>,
+    perez_b: vec3<f32>,
+    perez_c: vec3<f32>,
+    perez_d: vec3<f32>,
+    perez_e: vec3<f32>,
+    sky_zenith_yxy: vec3<f32>,
+}
+
+// `CameraProjection` in camera_update.rs
+const PROJECTION_PERSPECTIVE = 0u;
+const PROJECTION_ORTHOGRAPHIC = 1u;
+const PROJECTION_EQUIRECTANGULAR = 2u;
+const PROJECTION_FISHEYE = 3u;
+
+// Projection and thin lens of the camera, see `SceneCamera`
+struct CameraLens {
+    projection: u32,
+    // Radians
+    vertical_fov: f32,
+    orthographic_height: f32,
+    aperture_radius: f32,
+    focal_distance: f32,
+    blade_count: u32,
+    // Seconds relative to the frame
+    shutter_open: f32,
+    shutter_close: f32,
+}
+
+// The options compiled in as shader defs are not part of it, see `RenderSettings::shader_defs`
+struct RenderSettings {
+    max_bounces: u32,
+    // Zero accumulates forever
+    samples_per_pixel: u32,
+    samples_per_frame: u32,
+}
+
+// Result of `trace`, the one place that knows which primitive a ray hit. `normal` always points
+// against the incoming ray, `front_face` tells whether that is the outward side of the surface.
+struct HitRecord {
+    t: f32,
+    // Barycentric coordinates of the second and third vertex, only set for triangles
+    barycentric: vec2<f32>,
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    front_face: bool,
+    primitive_type: u32,
+    primitive_id: u32,
+    material_id: u32
+}
+
+// Random numbers
+// --------------
+
+var<private> rng_state: u32;
+
+// PCG hash, see "Hash Functions for GPU Rendering" (Jarzynski, Olano)
+fn pcg_hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random_float() -> f32 {
+    rng_state = pcg_hash(rng_state);
+    return f32(rng_state) / 4294967295.0;
+}
+
+fn random_unit_vector() -> vec3<f32> {
+    let z = random_float() * 2.0 - 1.0;
+    let angle = random_float() * 2.0 * PI;
+    let r = sqrt(max(1.0 - z * z, 0.0));
+    return vec3<f32>(r * cos(angle), r * sin(angle), z);
+}
+
+fn cosine_weighted_direction(normal: vec3<f32>) -> vec3<f32> {
+    let direction = normal + random_unit_vector();
+    if (dot(direction, direction) < EPSILON) {
+        return normal;
+    }
+    return normalize(direction);
+}
+
+// Scene
+// -----
+
 fn get_environment_light(ray: Ray) -> vec3<f32> {
-    let skyColorHorizon = vec3<f32>(0.9, 1.0, 1.0);
-    let skyColorZenith = vec3<f32>(0.37, 0.47, 0.81);
-    let groundColor = vec3<f32>(0.41, 0.39, 0.37);
+    if (environment.map_width > 0u) {
+        return environment_map_radiance(ray.direction) * environment.intensity;
+    }
+    if (environment.sun_cos_radius > 0.0) {
+        let sky_t = smoothstep(-0.001, 0.0, ray.direction.y);
+        let sky = mix(physical_sky_ground(), physical_sky_radiance(ray.direction), sky_t);
+        return sky * environment.intensity;
+    }
 
     let skyGradientT = smoothstep(0.0, 1.0, ray.direction.y);
-    let skyGradient = mix(skyColorHorizon, skyColorZenith, skyGradientT);
+    let skyGradient = mix(environment.sky_horizon, environment.sky_zenith, skyGradientT);
 
     let groundToSkyT = smoothstep(-0.001, 0.0, -ray.direction.y);
-    return mix(groundColor, skyGradient, groundToSkyT);
+    return mix(environment.ground, skyGradient, groundToSkyT) * environment.intensity;
+}
+
+// Motion
+// ------
+
+fn rotate(rotation: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(rotation.xyz, v);
+    return v + rotation.w * t + cross(rotation.xyz, t);
 }
 
-fn sphere_intersection(ray: Ray, index: i32) -> vec3<f32> {
-    let sphere = spheres[index];
-    let epsilon = 0.00001;
+// Along the shorter arc, like glam's `Quat::slerp`
+fn slerp(start: vec4<f32>, end: vec4<f32>, fraction: f32) -> vec4<f32> {
+    var end_rotation = end;
+    var cos_angle = dot(start, end);
+    if (cos_angle < 0.0) {
+        end_rotation = -end;
+        cos_angle = -cos_angle;
+    }
+    if (cos_angle > 0.9995) {
+        return normalize(mix(start, end_rotation, fraction));
+    }
+    let angle = acos(cos_angle);
+    return (start * sin(angle * (1.0 - fraction)) + end_rotation * sin(angle * fraction)) / sin(angle);
+}
 
+fn motion_at(motion: Motion, time: f32) -> MotionTransform {
+    return MotionTransform(
+        mix(motion.start.translation, motion.end.translation, time),
+        mix(motion.start.scale, motion.end.scale, time),
+        slerp(motion.start.rotation, motion.end.rotation, time),
+    );
+}
+
+// `ray` in the space the primitive's geometry is given in, at the ray's time. The direction is
+// not normalized so distances along both rays are the same.
+fn ray_to_rest(ray: Ray, motion: Motion) -> Ray {
+    let transform = motion_at(motion, ray.time);
+    let inverse_rotation = vec4<f32>(-transform.rotation.xyz, transform.rotation.w);
+    let origin = motion.pivot
+        + rotate(inverse_rotation, ray.origin - motion.pivot - transform.translation) / transform.scale;
+    let direction = rotate(inverse_rotation, ray.direction) / transform.scale;
+    return Ray(origin, direction, ray.time);
+}
+
+// A normal of the geometry given in rest space to world space, at `time`
+fn normal_from_rest(normal: vec3<f32>, motion: Motion, time: f32) -> vec3<f32> {
+    let transform = motion_at(motion, time);
+    return normalize(rotate(transform.rotation, normal / transform.scale));
+}
+
+// Physical sky
+// ------------
+
+// Relative brightness of a direction `cos_theta` from the zenith and `gamma` from the sun, for
+// luminance and both chromaticities
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32) -> vec3<f32> {
+    return (1.0 + environment.perez_a * exp(environment.perez_b / max(cos_theta, 0.001)))
+        * (1.0 + environment.perez_c * exp(environment.perez_d * gamma)
+            + environment.perez_e * cos_gamma * cos_gamma);
+}
+
+// Linear rgb without the sun disc
+fn physical_sky_radiance(direction: vec3<f32>) -> vec3<f32> {
+    let cos_gamma = clamp(dot(direction, environment.sun_direction), -1.0, 1.0);
+    let yxy = environment.sky_zenith_yxy * perez(direction.y, acos(cos_gamma), cos_gamma);
+    let xyz = vec3<f32>(yxy.y / yxy.z * yxy.x, yxy.x, (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x);
+    let rgb = vec3<f32>(
+        dot(vec3<f32>(3.2406, -1.5372, -0.4986), xyz),
+        dot(vec3<f32>(-0.9689, 1.8758, 0.0415), xyz),
+        dot(vec3<f32>(0.0557, -0.2040, 1.0570), xyz)
+    );
+    return max(rgb, vec3<f32>(0.0));
+}
+
+// Below the horizon, the ground colour lit by the sun and the sky straight above it
+fn physical_sky_ground() -> vec3<f32> {
+    let sun_irradiance = environment.sun_radiance / sun_pdf() * max(environment.sun_direction.y, 0.0);
+    return environment.ground * (sun_irradiance / PI + physical_sky_radiance(vec3<f32>(0.0, 1.0, 0.0)));
+}
+
+// Zero outside the disc and without a physical sky
+fn sun_light(direction: vec3<f32>) -> vec3<f32> {
+    if (environment.sun_cos_radius <= 0.0 || dot(direction, environment.sun_direction) < environment.sun_cos_radius) {
+        return vec3<f32>(0.0);
+    }
+    return environment.sun_radiance * environment.intensity;
+}
+
+// `sample_sun` picks directions uniformly inside the disc
+fn sun_pdf() -> f32 {
+    return 1.0 / (2.0 * PI * (1.0 - environment.sun_cos_radius));
+}
+
+fn sample_sun() -> vec3<f32> {
+    let cos_theta = 1.0 - random_float() * (1.0 - environment.sun_cos_radius);
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = random_float() * 2.0 * PI;
+
+    let w = environment.sun_direction;
+    let helper = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), abs(w.x) > 0.9);
+    let u = normalize(cross(helper, w));
+    let v = cross(w, u);
+    return normalize((u * cos(phi) + v * sin(phi)) * sin_theta + w * cos_theta);
+}
+
+// Environment map
+// ---------------
+
+fn environment_marginal_cdf(row: u32) -> f32 {
+    return environment_map[environment.map_width * environment.map_height + row].x;
+}
+
+fn environment_conditional_cdf(index: u32) -> f32 {
+    return environment_map[index].a;
+}
+
+// Texel `direction` falls into, longitude runs across the map with +Z in the middle like the
+// equirectangular projection
+fn environment_texel(direction: vec3<f32>) -> vec2<u32> {
+    let longitude = atan2(direction.x, direction.z) - environment.rotation;
+    let latitude = asin(clamp(direction.y, -1.0, 1.0));
+    let uv = vec2<f32>(fract(longitude / (2.0 * PI) + 0.5), 0.5 - latitude / PI);
+    let size = vec2<u32>(environment.map_width, environment.map_height);
+    return min(vec2<u32>(uv * vec2<f32>(size)), size - 1u);
+}
+
+fn environment_map_radiance(direction: vec3<f32>) -> vec3<f32> {
+    let texel = environment_texel(direction);
+    return environment_map[texel.y * environment.map_width + texel.x].rgb;
+}
+
+// Solid angle density of picking a direction inside `texel`, which is `cos_latitude` away from
+// the poles. Texels near them get squeezed into less solid angle.
+fn environment_texel_pdf(texel: vec2<u32>, cos_latitude: f32) -> f32 {
+    if (cos_latitude <= 0.0) {
+        return 0.0;
+    }
+    let row = texel.y * environment.map_width;
+    let marginal = environment_marginal_cdf(texel.y)
+        - select(0.0, environment_marginal_cdf(texel.y - 1u), texel.y > 0u);
+    let conditional = environment_conditional_cdf(row + texel.x)
+        - select(0.0, environment_conditional_cdf(row + texel.x - 1u), texel.x > 0u);
+    let texels = f32(environment.map_width) * f32(environment.map_height);
+    return marginal * conditional * texels / (2.0 * PI * PI * cos_latitude);
+}
+
+// Density `sample_environment` picks `direction` with
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let cos_latitude = sqrt(max(1.0 - direction.y * direction.y, 0.0));
+    return environment_texel_pdf(environment_texel(direction), cos_latitude);
+}
+
+// First row whose cumulative probability is above `value`, rows that can not be picked never are
+fn search_marginal_cdf(value: f32) -> u32 {
+    var low = 0u;
+    var high = environment.map_height - 1u;
+    while (low < high) {
+        let middle = (low + high) / 2u;
+        if (environment_marginal_cdf(middle) <= value) {
+            low = middle + 1u;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+fn search_conditional_cdf(row: u32, value: f32) -> u32 {
+    let offset = row * environment.map_width;
+    var low = 0u;
+    var high = environment.map_width - 1u;
+    while (low < high) {
+        let middle = (low + high) / 2u;
+        if (environment_conditional_cdf(offset + middle) <= value) {
+            low = middle + 1u;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+// Picks a direction in proportion to the light the map sends from it, returns its density
+fn sample_environment(direction: ptr<function, vec3<f32>>) -> f32 {
+    let y = search_marginal_cdf(random_float());
+    let x = search_conditional_cdf(y, random_float());
+    let uv = (vec2<f32>(f32(x), f32(y)) + vec2<f32>(random_float(), random_float()))
+        / vec2<f32>(f32(environment.map_width), f32(environment.map_height));
+
+    let longitude = (uv.x - 0.5) * 2.0 * PI + environment.rotation;
+    let latitude = (0.5 - uv.y) * PI;
+    *direction = vec3<f32>(
+        sin(longitude) * cos(latitude),
+        sin(latitude),
+        cos(longitude) * cos(latitude)
+    );
+    return environment_texel_pdf(vec2<u32>(x, y), cos(latitude));
+}
+
+// Weight of a sample taken with density `pdf` when another strategy could have taken it with
+// `other_pdf`
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let squared = pdf * pdf;
+    let total = squared + other_pdf * other_pdf;
+    return select(0.0, squared / total, total > 0.0);
+}
+
+// Returns the distance to the nearest intersection inside (t_min, t_max), or -1.0
+fn sphere_intersection(world_ray: Ray, sphere: Sphere, t_min: f32, t_max: f32) -> f32 {
+    let ray = ray_to_rest(world_ray, sphere.motion);
     let oc = ray.origin - sphere.position;
     let a = dot(ray.direction, ray.direction);
     let b = 2.0 * dot(oc, ray.direction);
@@ -52,42 +442,552 @@ fn sphere_intersection(ray: Ray, index: i32) -> vec3<f32> {
 
     var disc = b*b - 4.0*a*c;
     if (disc < 0.0) {
-        return get_environment_light(ray);
+        return -1.0;
     }
 
     disc = sqrt(disc);
-    let sol1 = (-b + disc) / (2.0 * a);
-    let sol2 = (-b - disc) / (2.0 * a);
-    let solMin = min(sol1, sol2);
+    let sol1 = (-b - disc) / (2.0 * a);
+    let sol2 = (-b + disc) / (2.0 * a);
 
-    if (solMin > epsilon) {
-        return vec3<f32>(1.0);
+    // The far solution is the exit point when the ray starts inside the sphere (refraction)
+    if (sol1 > t_min && sol1 < t_max) {
+        return sol1;
+    }
+    if (sol2 > t_min && sol2 < t_max) {
+        return sol2;
     }
-    return get_environment_light(ray);
+    return -1.0;
 }
 
-@compute @workgroup_size(8, 8, 1)
-fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
-    let fragCoord = vec2<f32>(location);
+// Möller–Trumbore, returns (t, u, v) with t = -1.0 when there is no hit inside (t_min, t_max)
+fn triangle_intersection(world_ray: Ray, triangle: Triangle, t_min: f32, t_max: f32) -> vec3<f32> {
+    let ray = ray_to_rest(world_ray, mesh_motions[triangle.mesh_index]);
+    let v0 = vertices[triangle.indices.x].xyz;
+    let edge1 = vertices[triangle.indices.y].xyz - v0;
+    let edge2 = vertices[triangle.indices.z].xyz - v0;
+
+    let p = cross(ray.direction, edge2);
+    let determinant = dot(edge1, p);
+    // Both faces are hit, refraction needs to see the back of closed meshes
+    if (abs(determinant) < 1e-8) {
+        return vec3<f32>(-1.0);
+    }
+
+    let inverse_determinant = 1.0 / determinant;
+    let s = ray.origin - v0;
+    let u = dot(s, p) * inverse_determinant;
+    if (u < 0.0 || u > 1.0) {
+        return vec3<f32>(-1.0);
+    }
+
+    let q = cross(s, edge1);
+    let v = dot(ray.direction, q) * inverse_determinant;
+    if (v < 0.0 || u + v > 1.0) {
+        return vec3<f32>(-1.0);
+    }
+
+    let t = dot(edge2, q) * inverse_determinant;
+    if (t <= t_min || t >= t_max) {
+        return vec3<f32>(-1.0);
+    }
+    return vec3<f32>(t, u, v);
+}
+
+fn no_hit() -> HitRecord {
+    return HitRecord(FAR_AWAY, vec2<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), false, PRIMITIVE_NONE, 0u, 0u);
+}
+
+// Slab test, returns the entry distance or FAR_AWAY when the box is missed or further than t_max
+fn aabb_intersection(ray: Ray, inverse_direction: vec3<f32>, aabb_min: vec3<f32>, aabb_max: vec3<f32>, t_max: f32) -> f32 {
+    // An inverted box, the root of an empty scene, would span all of space in the slab test
+    if (any(aabb_min > aabb_max)) {
+        return FAR_AWAY;
+    }
+    let t0 = (aabb_min - ray.origin) * inverse_direction;
+    let t1 = (aabb_max - ray.origin) * inverse_direction;
+    let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+
+    if (t_near > t_far || t_far < 0.0 || t_near > t_max) {
+        return FAR_AWAY;
+    }
+    return t_near;
+}
+
+fn intersect_primitive(ray: Ray, primitive: PrimitiveRef, record: ptr<function, HitRecord>) {
+    var t = -1.0;
+    var barycentric = vec2<f32>(0.0);
+    if (primitive.primitive_type == PRIMITIVE_SPHERE) {
+        t = sphere_intersection(ray, spheres[primitive.primitive_id], EPSILON, (*record).t);
+    } else if (primitive.primitive_type == PRIMITIVE_TRIANGLE) {
+        let hit = triangle_intersection(ray, triangles[primitive.primitive_id], EPSILON, (*record).t);
+        t = hit.x;
+        barycentric = hit.yz;
+    }
+
+    if (t > 0.0) {
+        (*record).t = t;
+        (*record).barycentric = barycentric;
+        (*record).primitive_type = primitive.primitive_type;
+        (*record).primitive_id = primitive.primitive_id;
+    }
+}
+
+fn trace(ray: Ray) -> HitRecord {
+    return trace_range(ray, FAR_AWAY);
+}
+
+// Finds the closest intersection of `ray` closer than `t_max` by walking the BVH, always
+// descending into the nearer child first so far subtrees get culled by the current hit.
+fn trace_range(ray: Ray, t_max: f32) -> HitRecord {
+    var record = no_hit();
+    record.t = t_max;
+    let inverse_direction = 1.0 / ray.direction;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 0;
+    var node_index = 0u;
+
+    let root = bvh_nodes[0];
+    if (aabb_intersection(ray, inverse_direction, root.aabb_min, root.aabb_max, record.t) == FAR_AWAY) {
+        return record;
+    }
+
+    loop {
+        let node = bvh_nodes[node_index];
+
+        if (node.primitive_count > 0u) {
+            for (var i = 0u; i < node.primitive_count; i = i + 1u) {
+                intersect_primitive(ray, bvh_primitives[node.left_first + i], &record);
+            }
+
+            if (stack_size == 0) {
+                break;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+            continue;
+        }
+
+        var near_index = node.left_first;
+        var far_index = node.left_first + 1u;
+        let near_node = bvh_nodes[near_index];
+        let far_node = bvh_nodes[far_index];
+        var near_t = aabb_intersection(ray, inverse_direction, near_node.aabb_min, near_node.aabb_max, record.t);
+        var far_t = aabb_intersection(ray, inverse_direction, far_node.aabb_min, far_node.aabb_max, record.t);
+        if (far_t < near_t) {
+            let swap_index = near_index;
+            near_index = far_index;
+            far_index = swap_index;
+            let swap_t = near_t;
+            near_t = far_t;
+            far_t = swap_t;
+        }
+
+        if (near_t == FAR_AWAY) {
+            if (stack_size == 0) {
+                break;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size];
+            continue;
+        }
+
+        node_index = near_index;
+        // `Bvh::build` limits the depth of the tree so this never overflows
+        if (far_t != FAR_AWAY) {
+            stack[stack_size] = far_index;
+            stack_size += 1;
+        }
+    }
+
+    if (record.primitive_type == PRIMITIVE_NONE) {
+        return record;
+    }
+
+    // Surface attributes are only resolved once, for the winning primitive
+    record.position = ray.origin + ray.direction * record.t;
+    var outward_normal = vec3<f32>(0.0);
+    var shading_normal = vec3<f32>(0.0);
+    if (record.primitive_type == PRIMITIVE_SPHERE) {
+        let sphere = spheres[record.primitive_id];
+        let rest_ray = ray_to_rest(ray, sphere.motion);
+        let rest_normal = (rest_ray.origin + rest_ray.direction * record.t - sphere.position) / sphere.radius;
+        outward_normal = normal_from_rest(rest_normal, sphere.motion, ray.time);
+        shading_normal = outward_normal;
+        record.material_id = sphere.material_index;
+    } else if (record.primitive_type == PRIMITIVE_TRIANGLE) {
+        let triangle = triangles[record.primitive_id];
+        let motion = mesh_motions[triangle.mesh_index];
+        let v0 = vertices[triangle.indices.x].xyz;
+        let rest_normal = cross(vertices[triangle.indices.y].xyz - v0, vertices[triangle.indices.z].xyz - v0);
+        outward_normal = normal_from_rest(rest_normal, motion, ray.time);
+
+        let w = vec3<f32>(1.0 - record.barycentric.x - record.barycentric.y, record.barycentric);
+        var interpolated = normals[triangle.indices.x].xyz * w.x
+            + normals[triangle.indices.y].xyz * w.y
+            + normals[triangle.indices.z].xyz * w.z;
+        if (dot(interpolated, interpolated) > EPSILON) {
+            interpolated = normal_from_rest(interpolated, motion, ray.time);
+        }
+        shading_normal = outward_normal;
+        if (dot(interpolated, interpolated) > EPSILON) {
+            // Keep the smooth normal on the same side as the geometry it belongs to
+            shading_normal = normalize(interpolated) * sign(dot(interpolated, outward_normal) + EPSILON);
+        }
+        record.material_id = triangle.material_index;
+    }
+
+    // The side is decided by the true surface, smooth normals only bend the shading
+    record.front_face = dot(ray.direction, outward_normal) < 0.0;
+    record.normal = select(-shading_normal, shading_normal, record.front_face);
+    return record;
+}
+
+// Shading
+// -------
+
+fn schlick_fresnel(cos_theta: f32, f0: f32) -> f32 {
+    return f0 + (1.0 - f0) * pow(1.0 - clamp(cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Picks the next bounce direction for `ray` hitting `material` and scales `throughput` by the
+// surface response. Lobes are chosen stochastically in proportion to the material weights.
+// Returns the density the diffuse lobe had of picking the direction, zero when another lobe
+// picked it. Light sampling in `direct_light` already covers what the diffuse lobe finds.
+fn scatter(ray: ptr<function, Ray>, throughput: ptr<function, vec3<f32>>, hit: HitRecord, material: Material) -> f32 {
+    let direction = (*ray).direction;
+    let front_face = hit.front_face;
+    let facing_normal = hit.normal;
+    let diffuse_direction = cosine_weighted_direction(facing_normal);
+    let roughness = material.roughness * material.roughness;
+
+    var next_direction: vec3<f32>;
+    var diffuse_pdf = 0.0;
+    if (random_float() < material.transmission) {
+        let eta = select(material.ior, 1.0 / material.ior, front_face);
+        let cos_theta = min(dot(-direction, facing_normal), 1.0);
+        let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+        let f0 = pow((1.0 - material.ior) / (1.0 + material.ior), 2.0);
+
+        var ideal: vec3<f32>;
+        if (eta * sin_theta > 1.0 || random_float() < schlick_fresnel(cos_theta, f0)) {
+            ideal = reflect(direction, facing_normal);
+        } else {
+            ideal = refract(direction, facing_normal, eta);
+        }
+        let rough = normalize(ideal + random_unit_vector() * roughness);
+        next_direction = select(ideal, rough, roughness > 0.0);
+        *throughput *= material.albedo;
+    } else {
+        let cos_theta = dot(-direction, facing_normal);
+        let specular_chance = mix(schlick_fresnel(cos_theta, 0.04), 1.0, material.metallic);
+
+        if (random_float() < specular_chance) {
+            let specular_direction = reflect(direction, facing_normal);
+            next_direction = normalize(mix(specular_direction, diffuse_direction, roughness));
+            *throughput *= mix(vec3<f32>(1.0), material.albedo, material.metallic);
+        } else {
+            next_direction = diffuse_direction;
+            *throughput *= material.albedo;
+            diffuse_pdf = (1.0 - material.transmission) * (1.0 - specular_chance)
+                * max(dot(next_direction, facing_normal), 0.0) / PI;
+        }
+    }
 
-    let fov = PI / 2.0;
-    let tan_fov = 1.0 / tan(fov * 0.5 * PI / 180.0);
+    // Offset along the side of the surface the new ray leaves from to avoid self intersection
+    let offset = select(-facing_normal, facing_normal, dot(next_direction, facing_normal) > 0.0);
+    *ray = Ray(hit.position + offset * EPSILON * 10.0, next_direction, (*ray).time);
+    return diffuse_pdf;
+}
+
+// Light arriving from the punctual lights, the environment map and the sun, already weighted by
+// the diffuse lobe of `scatter`. Punctual lights can never be hit by a scattered ray, so this is
+// their only contribution.
+fn direct_light(ray: Ray, hit: HitRecord, material: Material) -> vec3<f32> {
+    let cos_view = dot(-ray.direction, hit.normal);
+    let specular_chance = mix(schlick_fresnel(cos_view, 0.04), 1.0, material.metallic);
+    let diffuse_weight = (1.0 - material.transmission) * (1.0 - specular_chance);
+    if (diffuse_weight <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    var irradiance = vec3<f32>(0.0);
+    for (var i = 0u; i < arrayLength(&lights); i = i + 1u) {
+        let light = lights[i];
+        if (light.intensity <= 0.0) {
+            continue;
+        }
+
+        var to_light = -light.direction;
+        var distance = FAR_AWAY;
+        var falloff = 1.0;
+        if (light.light_type != LIGHT_DIRECTIONAL) {
+            let offset = light.position - hit.position;
+            distance = length(offset);
+            to_light = offset / distance;
+            falloff = 1.0 / max(distance * distance, EPSILON);
+
+            // Smooth range cutoff recommended by KHR_lights_punctual
+            if (light.range > 0.0) {
+                let ratio = distance / light.range;
+                falloff *= pow(clamp(1.0 - pow(ratio, 4.0), 0.0, 1.0), 2.0);
+            }
+            if (light.light_type == LIGHT_SPOT) {
+                falloff *= smoothstep(light.outer_cone_cos, light.inner_cone_cos, dot(light.direction, -to_light));
+            }
+        }
+
+        let cos_theta = dot(hit.normal, to_light);
+        if (cos_theta <= 0.0 || falloff <= 0.0) {
+            continue;
+        }
+
+        let shadow_ray = Ray(hit.position + hit.normal * EPSILON * 10.0, to_light, ray.time);
+        if (trace_range(shadow_ray, distance - EPSILON * 20.0).primitive_type != PRIMITIVE_NONE) {
+            continue;
+        }
+
+        irradiance += light.color * light.intensity * falloff * cos_theta;
+    }
+
+    // The environment map is sampled as a light too, weighed against the diffuse lobe finding it
+    if (environment.map_width > 0u) {
+        var to_light: vec3<f32>;
+        let light_pdf = sample_environment(&to_light);
+        let cos_theta = dot(hit.normal, to_light);
+        if (cos_theta > 0.0 && light_pdf > 0.0) {
+            let shadow_ray = Ray(hit.position + hit.normal * EPSILON * 10.0, to_light, ray.time);
+            if (trace(shadow_ray).primitive_type == PRIMITIVE_NONE) {
+                let weight = power_heuristic(light_pdf, diffuse_weight * cos_theta / PI);
+                irradiance += get_environment_light(shadow_ray) * cos_theta * weight / light_pdf;
+            }
+        }
+    }
+
+    // Likewise the sun disc of the physical sky
+    if (environment.sun_cos_radius > 0.0) {
+        let to_light = sample_sun();
+        let cos_theta = dot(hit.normal, to_light);
+        if (cos_theta > 0.0) {
+            let shadow_ray = Ray(hit.position + hit.normal * EPSILON * 10.0, to_light, ray.time);
+            if (trace(shadow_ray).primitive_type == PRIMITIVE_NONE) {
+                let light_pdf = sun_pdf();
+                let weight = power_heuristic(light_pdf, diffuse_weight * cos_theta / PI);
+                irradiance += environment.sun_radiance * environment.intensity * cos_theta * weight
+                    / light_pdf;
+            }
+        }
+    }
+
+    return irradiance * material.albedo / PI * diffuse_weight;
+}
+
+fn trace_path(primary_ray: Ray) -> vec3<f32> {
+    var ray = primary_ray;
+    var radiance = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    // Of the bounce that led here, see `scatter`
+    var diffuse_pdf = 0.0;
+
+    for (var bounce = 0u; bounce <= render_settings.max_bounces; bounce = bounce + 1u) {
+        let hit = trace(ray);
+        if (hit.primitive_type == PRIMITIVE_NONE) {
+            var weight = 1.0;
+            var sun_weight = 1.0;
+#ifdef NEXT_EVENT_ESTIMATION
+            if (diffuse_pdf > 0.0) {
+                if (environment.map_width > 0u) {
+                    weight = power_heuristic(diffuse_pdf, environment_pdf(ray.direction));
+                }
+                sun_weight = power_heuristic(diffuse_pdf, sun_pdf());
+            }
+#endif
+            radiance += throughput
+                * (get_environment_light(ray) * weight + sun_light(ray.direction) * sun_weight);
+            break;
+        }
+
+        let material = materials[hit.material_id];
+#ifdef NEXT_EVENT_ESTIMATION
+        radiance += throughput * (material.emission + direct_light(ray, hit, material));
+#else
+        radiance += throughput * material.emission;
+#endif
+        diffuse_pdf = scatter(&ray, &throughput, hit, material);
+
+        // Russian roulette, paths that carry little energy are terminated early without bias
+        if (bounce > 2u) {
+            let survival = clamp(max(throughput.x, max(throughput.y, throughput.z)), 0.05, 1.0);
+            if (random_float() > survival) {
+                break;
+            }
+            throughput /= survival;
+        }
+    }
+
+    return radiance;
+}
+
+#if DEBUG_VIEW != 0
+// What the first hit looks like to the shading code, black where the ray escapes
+fn debug_view(ray: Ray) -> vec3<f32> {
+    let hit = trace(ray);
+    if (hit.primitive_type == PRIMITIVE_NONE) {
+        return vec3<f32>(0.0);
+    }
+#if DEBUG_VIEW == 1
+    return hit.normal * 0.5 + 0.5;
+#else
+    return materials[hit.material_id].albedo;
+#endif
+}
+#endif
 
+// Ray through `frag_coord`, in pixels from the top left corner of the image, for the camera's
+// projection. It starts at the camera position, or on the image plane for the orthographic
+// projection, and `thin_lens_ray` moves it onto the aperture. Mirrors `SceneCamera::primary_ray`.
+fn primary_ray(frag_coord: vec2<f32>) -> Ray {
+    // Pixel rows go down while the camera's up axis goes up
     let ndc = vec2<f32>(
-        ((2.0 * fragCoord.x - resolution.x) / resolution.x) * aspect_ratio,
-        (2.0 * fragCoord.y - resolution.y) / resolution.y
+        2.0 * frag_coord.x / resolution.x - 1.0,
+        1.0 - 2.0 * frag_coord.y / resolution.y
     );
 
-    let ray_target = (inverse_view_matrix * vec4<f32>(ndc, tan_fov, 1.0)).xyz;
-    let ray_direction = normalize(ray_target - camera_position);
-    var ray = Ray(camera_position, ray_direction);
+    var view_direction: vec3<f32>;
+    if (camera_lens.projection == PROJECTION_ORTHOGRAPHIC) {
+        let half_height = camera_lens.orthographic_height * 0.5;
+        let offset = vec3<f32>(ndc.x * aspect_ratio * half_height, ndc.y * half_height, 0.0);
+        let origin = camera_position + (inverse_view_matrix * vec4<f32>(offset, 0.0)).xyz;
+        let forward = (inverse_view_matrix * vec4<f32>(0.0, 0.0, 1.0, 0.0)).xyz;
+        return Ray(origin, normalize(forward), 0.0);
+    } else if (camera_lens.projection == PROJECTION_EQUIRECTANGULAR) {
+        let longitude = (frag_coord.x / resolution.x - 0.5) * 2.0 * PI;
+        let latitude = (0.5 - frag_coord.y / resolution.y) * PI;
+        view_direction = vec3<f32>(
+            sin(longitude) * cos(latitude),
+            sin(latitude),
+            cos(longitude) * cos(latitude)
+        );
+    } else if (camera_lens.projection == PROJECTION_FISHEYE) {
+        let image_point = vec2<f32>(ndc.x * aspect_ratio, ndc.y);
+        let radius = length(image_point);
+        let angle = radius * camera_lens.vertical_fov * 0.5;
+        var axis = vec2<f32>(0.0);
+        if (radius > 0.0) {
+            axis = image_point / radius;
+        }
+        view_direction = vec3<f32>(axis * sin(angle), cos(angle));
+    } else {
+        let tan_half_fov = tan(camera_lens.vertical_fov * 0.5);
+        view_direction = vec3<f32>(
+            ndc.x * aspect_ratio * tan_half_fov,
+            ndc.y * tan_half_fov,
+            1.0
+        );
+    }
+    let direction = (inverse_view_matrix * vec4<f32>(view_direction, 0.0)).xyz;
+    return Ray(camera_position, normalize(direction), 0.0);
+}
 
-    var color = vec3<f32>(0.0, 0.0, 0.0);
-    for (var i: i32 = 0; i < i32(arrayLength(&spheres)); i = i + 1) {
-        color = sphere_intersection(ray, i);
+#ifdef DEPTH_OF_FIELD
+// Uniform point on the unit aperture, a disk or a regular polygon with `blade_count` corners
+fn sample_aperture() -> vec2<f32> {
+    if (camera_lens.blade_count < 3u) {
+        let angle = 2.0 * PI * random_float();
+        let radius = sqrt(random_float());
+        return vec2<f32>(cos(angle), sin(angle)) * radius;
     }
 
-    storageBarrier();
-    textureStore(texture, location, vec4<f32>(color, 1.0));
-}
\ No newline at end of file
+    // A uniform point in the triangle between the center and two neighbouring corners
+    let blades = f32(camera_lens.blade_count);
+    let blade = floor(random_float() * blades);
+    let first = 2.0 * PI * blade / blades;
+    let second = 2.0 * PI * (blade + 1.0) / blades;
+    var u = random_float();
+    var v = random_float();
+    if (u + v > 1.0) {
+        u = 1.0 - u;
+        v = 1.0 - v;
+    }
+    return vec2<f32>(cos(first), sin(first)) * u + vec2<f32>(cos(second), sin(second)) * v;
+}
+
+// Starts the pinhole `ray` from a random point on the lens instead, aimed at where it crosses the
+// focal plane. Only that plane stays sharp.
+fn thin_lens_ray(ray: Ray) -> Ray {
+    let forward = normalize(camera_direction);
+    let focus_point = ray.origin + ray.direction * (camera_lens.focal_distance / dot(ray.direction, forward));
+    let aperture = sample_aperture() * camera_lens.aperture_radius;
+    let origin = ray.origin
+        + normalize(inverse_view_matrix[0].xyz) * aperture.x
+        + normalize(inverse_view_matrix[1].xyz) * aperture.y;
+    return Ray(origin, normalize(focus_point - origin), ray.time);
+}
+#endif
+
+// One path through a random point of the pixel at `location`
+fn sample_pixel(location: vec2<i32>) -> vec3<f32> {
+    // Jitter inside the pixel so accumulated frames also anti-alias edges
+    let frag_coord = vec2<f32>(location) + vec2<f32>(random_float(), random_float());
+    var ray = primary_ray(frag_coord);
+    // A random moment while the shutter is open, an instant one takes no random number
+    ray.time = 0.0;
+    if (camera_lens.shutter_close > camera_lens.shutter_open) {
+        ray.time = random_float();
+    }
+#ifdef DEPTH_OF_FIELD
+    // The panoramic projections have no focus plane
+    let has_thin_lens = camera_lens.projection == PROJECTION_PERSPECTIVE
+        || camera_lens.projection == PROJECTION_ORTHOGRAPHIC;
+    if (camera_lens.aperture_radius > 0.0 && has_thin_lens) {
+        ray = thin_lens_ray(ray);
+    }
+#endif
+
+#if DEBUG_VIEW != 0
+    var color = debug_view(ray);
+#else
+    var color = trace_path(ray);
+#endif
+    // A NaN sample would poison the pixel for the rest of the accumulation
+    if (any(color != color)) {
+        color = vec3<f32>(0.0);
+    }
+    return color;
+}
+
+// Traces this frame's samples into `radiance`, their average in rgb and their count in alpha. The
+// accumulate pass averages them into the history.
+@compute @workgroup_size(8, 8, 1)
+fn trace_pixel(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
+    if (f32(location.x) >= resolution.x || f32(location.y) >= resolution.y) {
+        return;
+    }
+
+    // The alpha channel of the accumulation texture holds the number of samples averaged so far,
+    // a frame count of zero means the camera or scene changed and the history is discarded.
+    // The accumulate pass makes the same decision, so pixels skipped here are not averaged in.
+    let previous = textureLoad(accumulation, location, 0);
+    let sample_count = select(previous.a, 0.0, frame_count == 0u);
+    if (render_settings.samples_per_pixel > 0u && sample_count >= f32(render_settings.samples_per_pixel)) {
+        return;
+    }
+
+    // The time keeps the frames of an animation from sharing one noise pattern
+    let frame_seed = frame_count ^ bitcast<u32>(render_time);
+    rng_state = pcg_hash(invocation_id.x + invocation_id.y * u32(resolution.x) + pcg_hash(frame_seed));
+
+    // Never more than the pixel still needs
+    var samples = max(render_settings.samples_per_frame, 1u);
+    if (render_settings.samples_per_pixel > 0u) {
+        samples = min(samples, render_settings.samples_per_pixel - u32(sample_count));
+    }
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < samples; i = i + 1u) {
+        color += sample_pixel(location);
+    }
+
+    textureStore(radiance, location, vec4<f32>(color / f32(samples), f32(samples)));
+}
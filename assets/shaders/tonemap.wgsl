@@ -0,0 +1,29 @@
+@group(0) @binding(3) var<storage, read> resolution: vec2<f32>;
+@group(0) @binding(19) var hdr: texture_storage_2d<rgba32float, read_write>;
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((color * (a * color + b)) / (color * (c * color + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Maps the radiance in `hdr` to [0, 1] in place, bright lights roll off instead of clipping
+@compute @workgroup_size(8, 8, 1)
+fn tonemap(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
+    if (f32(location.x) >= resolution.x || f32(location.y) >= resolution.y) {
+        return;
+    }
+
+    let color = textureLoad(hdr, location);
+#if DEBUG_VIEW != 0
+    // Debug views show their values as they are
+    textureStore(hdr, location, color);
+#else
+    textureStore(hdr, location, vec4<f32>(aces(color.rgb), color.a));
+#endif
+}
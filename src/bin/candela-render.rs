@@ -0,0 +1,220 @@
+use std::{
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::*,
+    window::{ExitCondition, WindowPlugin},
+    winit::WinitPlugin,
+};
+
+use candela::{
+    camera::camera_update::SceneCamera,
+    compute_shader::{
+        render_settings::{DebugView, RenderSettings},
+        shader_watcher::ShaderWatcherPlugin,
+    },
+    offline::{
+        cpu_renderer::CpuRenderer,
+        headless::{resolve_samples_per_pixel, HeadlessRenderPlugin},
+        image_output::{write_image, OutputFormat},
+        sequence::{frame_path, FrameSequence, SequenceManifest, DEFAULT_SEQUENCE_OUTPUT},
+    },
+    scene::{
+        animation::{animation_end_time, seek},
+        scene::{Scene, ScenePath},
+        scene_watcher::SceneWatcherPlugin,
+    },
+    RaytracerPlugins,
+};
+
+const USAGE: &str = "usage: candela-render <scene> [--width <pixels>] [--height <pixels>] \
+                     [--spp <samples>] [--output <image.png|image.exr>] [--cpu] \
+                     [--frames <count>] [--fps <rate>]";
+
+const DEFAULT_FRAMES_PER_SECOND: u32 = 24;
+
+struct Options {
+    scene: PathBuf,
+    resolution: UVec2,
+    samples_per_pixel: Option<u32>,
+    // render.png, or frames/frame.png for a sequence
+    output: PathBuf,
+    // Render with the CPU reference renderer instead of the compute shader
+    cpu: bool,
+    // Step through the animation and write one numbered image per frame plus a manifest
+    frames: Option<FrameSequence>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut scene = None;
+    let mut resolution = UVec2::new(1280, 720);
+    let mut samples_per_pixel = None;
+    let mut output = None;
+    let mut cpu = false;
+    let mut frame_count = None;
+    let mut frames_per_second = DEFAULT_FRAMES_PER_SECOND;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        let number = |name: &str, value: String| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|&number| number > 0)
+                .ok_or(format!("{name} needs a positive integer, got \"{value}\""))
+        };
+
+        match arg.as_str() {
+            "--width" => resolution.x = number("--width", value("--width")?)?,
+            "--height" => resolution.y = number("--height", value("--height")?)?,
+            "--spp" => samples_per_pixel = Some(number("--spp", value("--spp")?)?),
+            "--output" | "-o" => output = Some(PathBuf::from(value("--output")?)),
+            "--cpu" => cpu = true,
+            "--frames" => frame_count = Some(number("--frames", value("--frames")?)?),
+            "--fps" => frames_per_second = number("--fps", value("--fps")?)?,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    let output = output.unwrap_or_else(|| {
+        PathBuf::from(match frame_count {
+            Some(_) => DEFAULT_SEQUENCE_OUTPUT,
+            None => "render.png",
+        })
+    });
+    if OutputFormat::from_path(&output).is_none() {
+        return Err(format!(
+            "{}: output must be a .png or .exr file",
+            output.display()
+        ));
+    }
+    Ok(Options {
+        scene: scene.ok_or("missing scene path")?,
+        resolution,
+        samples_per_pixel,
+        output,
+        cpu,
+        frames: frame_count.map(|count| FrameSequence {
+            count,
+            frames_per_second,
+        }),
+    })
+}
+
+fn render_on_cpu(options: Options) -> Result<(), String> {
+    let mut scene = Scene::load(&options.scene).map_err(|error| error.to_string())?;
+    let mut camera = scene.camera.unwrap_or_default();
+    let mut settings = scene.render_settings.unwrap_or_default();
+    settings.samples_per_pixel =
+        resolve_samples_per_pixel(options.samples_per_pixel, settings.samples_per_pixel);
+
+    let Some(frames) = options.frames else {
+        // The keyframes at the start of the timeline, like the window shows it
+        scene.animate(0.0, &camera);
+        return render_frame_on_cpu(&scene, &camera, &settings, &options, 0.0, &options.output);
+    };
+    let camera_path = scene.camera_path.clone();
+    if animation_end_time(&scene, camera_path.as_ref()).is_none() {
+        return Err("nothing in the scene is animated, there are no frames to render".to_string());
+    }
+
+    let mut manifest = SequenceManifest::create(
+        &options.output,
+        &options.scene,
+        options.resolution,
+        settings.samples_per_pixel,
+        &frames,
+    )
+    .map_err(|error| format!("{}: {error}", options.output.display()))?;
+    for frame in 0..frames.count {
+        let time = frames.time(frame);
+        seek(time, &mut scene, &mut camera, camera_path.as_ref());
+        println!("frame {}/{}", frame + 1, frames.count);
+        let output = frame_path(&options.output, frame);
+        render_frame_on_cpu(&scene, &camera, &settings, &options, time, &output)?;
+        manifest
+            .add_frame(&output, time)
+            .map_err(|error| format!("{}: {error}", manifest.path().display()))?;
+    }
+    println!("wrote {}", manifest.path().display());
+    Ok(())
+}
+
+fn render_frame_on_cpu(
+    scene: &Scene,
+    camera: &SceneCamera,
+    settings: &RenderSettings,
+    options: &Options,
+    render_time: f32,
+    output: &Path,
+) -> Result<(), String> {
+    let mut renderer =
+        CpuRenderer::new(scene, camera, settings, options.resolution).with_render_time(render_time);
+    let samples_per_frame = settings.samples_per_frame.max(1);
+    for frame in 1..=settings.samples_per_pixel.div_ceil(samples_per_frame) {
+        renderer.render_frame();
+        let samples = (frame * samples_per_frame).min(settings.samples_per_pixel);
+        println!("{samples}/{} samples", settings.samples_per_pixel);
+    }
+
+    let resolution = renderer.resolution();
+    // Debug views are written as they are, like the tonemap pass leaves them
+    let tonemap = settings.debug_view == DebugView::Off;
+    write_image(
+        output,
+        resolution.x,
+        resolution.y,
+        renderer.accumulation(),
+        tonemap,
+    )
+    .map_err(|error| error.to_string())?;
+    println!("wrote {}", output.display());
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options(std::env::args().skip(1)).unwrap_or_else(|error| {
+        println!("{error}\n{USAGE}");
+        process::exit(2);
+    });
+
+    if options.cpu {
+        if let Err(error) = render_on_cpu(options) {
+            println!("{error}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut app = App::new();
+    app.insert_resource(ScenePath(Some(options.scene)))
+        .add_plugins((
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .disable::<WinitPlugin>(),
+            ScheduleRunnerPlugin::run_loop(Duration::ZERO),
+            RaytracerPlugins
+                .build()
+                .disable::<SceneWatcherPlugin>()
+                .disable::<ShaderWatcherPlugin>(),
+            HeadlessRenderPlugin {
+                resolution: options.resolution,
+                samples_per_pixel: options.samples_per_pixel,
+                output: options.output,
+                frames: options.frames,
+            },
+        ));
+
+    app.run();
+}
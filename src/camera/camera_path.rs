@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+
+use crate::{
+    camera::camera_update::SceneCamera,
+    scene::animation::{advance_time, catmull_rom, spline_segment, Playback, RenderTime},
+};
+
+// Seconds per revolution of the turntable started with O
+const TURNTABLE_DURATION: f32 = 8.0;
+
+// Degrees between the keyframes of a generated orbit, the spline bulges out of the circle
+// between keyframes that are further apart
+const ORBIT_KEYFRAME_STEP: f32 = 10.0;
+
+pub struct CameraPathPlugin;
+impl Plugin for CameraPathPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActiveCameraPath>();
+        app.add_systems(
+            Update,
+            (start_turntable, follow_camera_path)
+                .chain()
+                .after(advance_time),
+        );
+    }
+}
+
+// The path the camera follows through the animation. Loading a scene with a camera path replaces
+// it, O replaces it with a turntable of the current view.
+#[derive(Resource, Default)]
+pub struct ActiveCameraPath(pub Option<CameraPath>);
+
+// Where the camera is at `time`, everything else about it stays as it is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraKeyframe {
+    // Seconds
+    pub time: f32,
+    pub position: Vec3,
+    pub front: Vec3,
+    pub up: Vec3,
+    // Degrees
+    pub vertical_fov: f32,
+    pub focal_distance: f32,
+}
+
+// Camera animation through timed keyframes, interpolated with a Catmull-Rom spline
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPath {
+    // Sorted by time
+    pub keyframes: Vec<CameraKeyframe>,
+    // Starts over after the last keyframe, which should then repeat the first one
+    pub looping: bool,
+}
+
+impl CameraPath {
+    // Circles `center` at `radius`, `height` above it and looking at it, from `start_angle` to
+    // `end_angle` degrees over `duration` seconds. Angle zero is on the -Z side, where the default
+    // camera looks from, and angles grow counterclockwise seen from above. A full circle loops.
+    pub fn orbit(
+        center: Vec3,
+        radius: f32,
+        height: f32,
+        start_angle: f32,
+        end_angle: f32,
+        duration: f32,
+        vertical_fov: f32,
+    ) -> Self {
+        let sweep = end_angle - start_angle;
+        let steps = (sweep.abs() / ORBIT_KEYFRAME_STEP).ceil().max(1.0) as u32;
+        let keyframes = (0..=steps)
+            .map(|step| {
+                let fraction = step as f32 / steps as f32;
+                let angle = (start_angle + sweep * fraction).to_radians();
+                let position =
+                    center + Vec3::new(angle.sin() * radius, height, -angle.cos() * radius);
+                CameraKeyframe {
+                    time: duration * fraction,
+                    position,
+                    front: (center - position).normalize(),
+                    up: Vec3::Y,
+                    vertical_fov,
+                    focal_distance: center.distance(position),
+                }
+            })
+            .collect();
+
+        CameraPath {
+            keyframes,
+            looping: sweep.abs() >= 360.0,
+        }
+    }
+
+    // One revolution around the point `camera` is focused on, at the camera's distance and height
+    pub fn turntable(camera: &SceneCamera, duration: f32) -> Self {
+        let center = camera.position + camera.front.normalize() * camera.focal_distance;
+        let offset = camera.position - center;
+        let start_angle = offset.x.atan2(-offset.z).to_degrees();
+        CameraPath::orbit(
+            center,
+            Vec2::new(offset.x, offset.z).length(),
+            offset.y,
+            start_angle,
+            start_angle + 360.0,
+            duration,
+            camera.vertical_fov,
+        )
+    }
+
+    pub fn start_time(&self) -> f32 {
+        self.keyframes.first().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    // The keyframes interpolated at `time`, held at the ends unless the path loops
+    pub fn sample(&self, time: f32) -> Option<CameraKeyframe> {
+        let first = self.keyframes.first()?;
+        let duration = self.end_time() - self.start_time();
+        let time = if self.looping && duration > 0.0 {
+            first.time + (time - first.time).rem_euclid(duration)
+        } else {
+            time.clamp(first.time, self.end_time())
+        };
+        let last = self.keyframes.len() - 1;
+        if last == 0 {
+            return Some(CameraKeyframe { time, ..*first });
+        }
+
+        let times: Vec<f32> = self
+            .keyframes
+            .iter()
+            .map(|keyframe| keyframe.time)
+            .collect();
+        let points = spline_segment(&times, time, self.looping)
+            .map(|(index, time)| (&self.keyframes[index], time));
+        let vector = |value: fn(&CameraKeyframe) -> Vec3| {
+            catmull_rom(points.map(|(keyframe, time)| (value(keyframe), time)), time)
+        };
+        let scalar = |value: fn(&CameraKeyframe) -> f32| {
+            catmull_rom(points.map(|(keyframe, time)| (value(keyframe), time)), time)
+        };
+
+        Some(CameraKeyframe {
+            time,
+            position: vector(|keyframe| keyframe.position),
+            front: vector(|keyframe| keyframe.front).normalize(),
+            up: vector(|keyframe| keyframe.up).normalize(),
+            vertical_fov: scalar(|keyframe| keyframe.vertical_fov),
+            focal_distance: scalar(|keyframe| keyframe.focal_distance),
+        })
+    }
+
+    // `camera` moved to where the path is at `time`, with its matrices up to date so a frame can be
+    // rendered with it right away
+    pub fn camera_at(&self, time: f32, camera: &SceneCamera) -> Option<SceneCamera> {
+        let keyframe = self.sample(time)?;
+        let looking = SceneCamera::new(keyframe.position, keyframe.front, keyframe.up);
+        let mut moved = SceneCamera {
+            position: looking.position,
+            front: looking.front,
+            up: looking.up,
+            right: looking.right,
+            vertical_fov: keyframe.vertical_fov,
+            focal_distance: keyframe.focal_distance,
+            ..*camera
+        };
+        moved.inverse_view_matrix = moved.compute_inverse_view_matrix();
+        Some(moved)
+    }
+}
+
+// O starts a turntable around the point in focus from where the camera is now
+fn start_turntable(
+    input_keyboard: Res<Input<KeyCode>>,
+    camera: Res<SceneCamera>,
+    render_time: Res<RenderTime>,
+    mut camera_path: ResMut<ActiveCameraPath>,
+    mut playback: ResMut<Playback>,
+) {
+    if !input_keyboard.just_pressed(KeyCode::O) {
+        return;
+    }
+    let mut path = CameraPath::turntable(&camera, TURNTABLE_DURATION);
+    for keyframe in path.keyframes.iter_mut() {
+        keyframe.time += render_time.0;
+    }
+    camera_path.0 = Some(path);
+    playback.playing = true;
+    println!("turntable around the focus point");
+}
+
+// The camera is only moved when the clock or the path changed, so it can be navigated by hand
+// while the animation is paused
+fn follow_camera_path(
+    render_time: Res<RenderTime>,
+    camera_path: Res<ActiveCameraPath>,
+    mut camera: ResMut<SceneCamera>,
+) {
+    if !render_time.is_changed() && !camera_path.is_changed() {
+        return;
+    }
+    let Some(moved) = camera_path
+        .0
+        .as_ref()
+        .and_then(|path| path.camera_at(render_time.0, &camera))
+    else {
+        return;
+    };
+    // Only write back real changes, the accumulation resets whenever the camera is marked changed
+    camera.set_if_neq(moved);
+}
@@ -3,19 +3,26 @@ use std::f32::consts::PI;
 use bevy::{
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
+    render::render_resource::ShaderType,
 };
 
 use crate::{
-    compute_shader::{compute_buffers::BufferType, lib::buffers_interface::ComputeBuffers},
-    update_window_buffers,
+    compute_shader::{
+        compute_buffers::{
+            CAMERA_DIRECTION_BUFFER, CAMERA_LENS_BUFFER, CAMERA_POSITION_BUFFER,
+            INVERSE_VIEW_MATRIX_BUFFER,
+        },
+        lib::buffers_interface::ComputeBuffers,
+    },
+    offline::cpu_renderer::focal_distance_at,
     scene::{
-        scene::Scene,
-        spheres::*
-    }
+        bvh::bvh::Bvh,
+        meshes::mesh::MeshBuffers,
+        scene::{update_scene_buffers, Scene},
+    },
+    update_window_buffers, WindowSize,
 };
 
-use self::sphere::Sphere;
-
 pub struct CameraPlugin;
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
@@ -26,21 +33,52 @@ impl Plugin for CameraPlugin {
                 update_camera,
                 move_camera,
                 rotate_camera,
+                adjust_fov,
+                cycle_projection,
                 update_camera_buffers,
                 update_window_buffers,
-                test,
             ),
         );
+        // Casts against the BVH and mesh buffers, which are only rebuilt for a scene changed this
+        // frame in PostUpdate. Before that their primitive ids can point past the end of the
+        // scene's lists. The new focal distance is uploaded with the next frame's camera.
+        app.add_systems(PostUpdate, focus_on_click.after(update_scene_buffers));
     }
 }
 
-#[derive(Resource, Copy, Clone)]
+// Millimeters, the 35mm film format most focal lengths are quoted for
+pub const FULL_FRAME_SENSOR_HEIGHT: f32 = 24.0;
+
+const MIN_VERTICAL_FOV: f32 = 1.0;
+const MAX_VERTICAL_FOV: f32 = 160.0;
+
+#[derive(Resource, Copy, Clone, PartialEq)]
 pub struct SceneCamera {
     pub position: Vec3,
     pub front: Vec3,
     pub up: Vec3,
     pub right: Vec3,
     pub inverse_view_matrix: Mat4,
+    pub projection: CameraProjection,
+    // Angle between the top and bottom edge of the image in degrees, for the perspective and
+    // fisheye projections
+    pub vertical_fov: f32,
+    // World units between the top and bottom edge of the image, for the orthographic projection
+    pub orthographic_height: f32,
+    // Height of the film in millimeters, only used to convert between the field of view and a
+    // focal length
+    pub sensor_height: f32,
+    // Thin lens, a zero radius is a pinhole camera with everything in focus
+    pub aperture_radius: f32,
+    // Distance along `front` to the plane that is in focus
+    pub focal_distance: f32,
+    // Corners of the aperture shape, bokeh is round below three
+    pub blade_count: u32,
+    // Seconds relative to the frame's render time, every sample is taken at a random moment in
+    // between. Primitives are at the start of their `Motion` when it opens and at the end when it
+    // closes, keyframed spheres get theirs from these times.
+    pub shutter_open: f32,
+    pub shutter_close: f32,
 }
 
 impl Default for SceneCamera {
@@ -51,26 +89,193 @@ impl Default for SceneCamera {
             up: Vec3::new(0.0, 1.0, 0.0),
             right: Vec3::new(1.0, 0.0, 0.0),
             inverse_view_matrix: Mat4::default(),
+            projection: CameraProjection::Perspective,
+            vertical_fov: 45.0,
+            orthographic_height: 10.0,
+            sensor_height: FULL_FRAME_SENSOR_HEIGHT,
+            aperture_radius: 0.0,
+            focal_distance: 10.0,
+            blade_count: 0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+}
+
+// How primary rays leave the camera, `PROJECTION_*` in raytracer.wgsl
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CameraProjection {
+    #[default]
+    Perspective = 0,
+    // Parallel rays along `front`, starting on a plane through the camera
+    Orthographic = 1,
+    // The whole sphere around the camera, longitude across and latitude down the image. Meant for
+    // images twice as wide as they are high.
+    Equirectangular = 2,
+    // Equidistant fisheye, the angle to `front` grows linearly with the distance from the image
+    // center and reaches half of `vertical_fov` at the top and bottom edge
+    Fisheye = 3,
+}
+
+impl CameraProjection {
+    fn next(self) -> Self {
+        match self {
+            CameraProjection::Perspective => CameraProjection::Orthographic,
+            CameraProjection::Orthographic => CameraProjection::Equirectangular,
+            CameraProjection::Equirectangular => CameraProjection::Fisheye,
+            CameraProjection::Fisheye => CameraProjection::Perspective,
+        }
+    }
+}
+
+shader_types! {
+    // Mirrors `struct CameraLens` in raytracer.wgsl
+    #[derive(Debug, Clone, Copy, Default, ShaderType)]
+    pub struct GpuCameraLens {
+        pub projection: u32,
+        // Radians
+        pub vertical_fov: f32,
+        pub orthographic_height: f32,
+        pub aperture_radius: f32,
+        pub focal_distance: f32,
+        pub blade_count: u32,
+        pub shutter_open: f32,
+        pub shutter_close: f32,
+    }
+}
+
+impl From<&SceneCamera> for GpuCameraLens {
+    fn from(camera: &SceneCamera) -> Self {
+        GpuCameraLens {
+            projection: camera.projection as u32,
+            vertical_fov: camera.vertical_fov.to_radians(),
+            orthographic_height: camera.orthographic_height,
+            aperture_radius: camera.aperture_radius,
+            focal_distance: camera.focal_distance,
+            blade_count: camera.blade_count,
+            shutter_open: camera.shutter_open,
+            shutter_close: camera.shutter_close,
         }
     }
 }
 
+impl SceneCamera {
+    pub fn new(position: Vec3, front: Vec3, up: Vec3) -> Self {
+        let front = front.normalize();
+        let up = up.normalize();
+        SceneCamera {
+            position,
+            front,
+            up,
+            right: Vec3::normalize(Vec3::cross(front, up)),
+            ..default()
+        }
+    }
+
+    // Camera to world transform of the left handed view, what `inverse_view_matrix` holds
+    pub fn compute_inverse_view_matrix(&self) -> Mat4 {
+        Mat4::inverse(&Mat4::look_at_lh(
+            self.position,
+            self.position + self.front,
+            self.up,
+        ))
+    }
+
+    pub fn tan_half_fov(&self) -> f32 {
+        (self.vertical_fov.to_radians() * 0.5).tan()
+    }
+
+    // Millimeters, the focal length that gives `vertical_fov` on `sensor_height`
+    pub fn focal_length(&self) -> f32 {
+        self.sensor_height * 0.5 / self.tan_half_fov()
+    }
+
+    pub fn set_focal_length(&mut self, focal_length: f32) {
+        self.vertical_fov = (2.0 * (self.sensor_height * 0.5 / focal_length).atan()).to_degrees();
+    }
+
+    // World space origin and direction of the primary ray through `frag_coord`, in pixels from the
+    // top left corner of an image of `resolution`. `primary_ray` in raytracer.wgsl does the same
+    // with the uploaded camera.
+    pub fn primary_ray(&self, resolution: Vec2, frag_coord: Vec2) -> (Vec3, Vec3) {
+        // Pixel rows go down while the camera's up axis goes up
+        let ndc = Vec2::new(
+            2.0 * frag_coord.x / resolution.x - 1.0,
+            1.0 - 2.0 * frag_coord.y / resolution.y,
+        );
+        let aspect_ratio = resolution.x / resolution.y;
+
+        let view_direction = match self.projection {
+            CameraProjection::Perspective => {
+                let tan_half_fov = self.tan_half_fov();
+                Vec3::new(
+                    ndc.x * aspect_ratio * tan_half_fov,
+                    ndc.y * tan_half_fov,
+                    1.0,
+                )
+            }
+            CameraProjection::Orthographic => {
+                let half_height = self.orthographic_height * 0.5;
+                let offset =
+                    Vec3::new(ndc.x * aspect_ratio * half_height, ndc.y * half_height, 0.0);
+                return (
+                    self.position + self.inverse_view_matrix.transform_vector3(offset),
+                    self.inverse_view_matrix
+                        .transform_vector3(Vec3::Z)
+                        .normalize(),
+                );
+            }
+            CameraProjection::Equirectangular => {
+                let longitude = (frag_coord.x / resolution.x - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - frag_coord.y / resolution.y) * PI;
+                Vec3::new(
+                    longitude.sin() * latitude.cos(),
+                    latitude.sin(),
+                    longitude.cos() * latitude.cos(),
+                )
+            }
+            CameraProjection::Fisheye => {
+                let image_point = Vec2::new(ndc.x * aspect_ratio, ndc.y);
+                let radius = image_point.length();
+                let angle = radius * self.vertical_fov.to_radians() * 0.5;
+                let axis = if radius > 0.0 {
+                    image_point / radius
+                } else {
+                    Vec2::ZERO
+                };
+                (axis * angle.sin()).extend(angle.cos())
+            }
+        };
+        (
+            self.position,
+            self.inverse_view_matrix
+                .transform_vector3(view_direction)
+                .normalize(),
+        )
+    }
+}
+
 fn update_camera(mut camera: ResMut<SceneCamera>) {
-    camera.right = Vec3::normalize(Vec3::cross(camera.front, camera.up));
-    camera.inverse_view_matrix = Mat4::inverse(&Mat4::look_at_lh(
-        camera.position,
-        camera.position + camera.front,
-        camera.up,
-    ));
+    // Only write back real changes, the accumulation resets whenever the camera is marked changed
+    let mut updated = *camera;
+    updated.right = Vec3::normalize(Vec3::cross(updated.front, updated.up));
+    updated.inverse_view_matrix = updated.compute_inverse_view_matrix();
+    camera.set_if_neq(updated);
 }
 
 fn move_camera(
     mut ev_motion: EventReader<MouseMotion>,
     mut ev_scroll: EventReader<MouseWheel>,
     input_mouse: Res<Input<MouseButton>>,
+    input_keyboard: Res<Input<KeyCode>>,
     mut camera: ResMut<SceneCamera>,
 ) {
     let move_button = MouseButton::Middle;
+    // Scrolling with Ctrl held changes the field of view instead, see `adjust_fov`
+    let control = input_keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if control {
+        ev_scroll.clear();
+    }
     if input_mouse.pressed(move_button) {
         let cursor_offset: Vec2 = ev_motion.iter().map(|ev| ev.delta).sum();
 
@@ -79,13 +284,17 @@ fn move_camera(
         let bitangent = Vec3::cross(camera.front, tangent);
 
         let movement = (tangent * cursor_offset.x + bitangent * cursor_offset.y) * camera_speed;
-        camera.position += movement;
+        if movement != Vec3::ZERO {
+            camera.position += movement;
+        }
     } else {
         let scroll_offset: f32 = ev_scroll.iter().map(|ev| ev.y).sum();
         let zoom_speed = 5.0;
 
-        let camera_clone = camera.clone();
-        camera.position += camera_clone.front * scroll_offset * zoom_speed;
+        if scroll_offset != 0.0 {
+            let camera_clone = *camera;
+            camera.position += camera_clone.front * scroll_offset * zoom_speed;
+        }
     }
 
     ev_motion.clear();
@@ -105,9 +314,12 @@ fn rotate_camera(
     let mouse_sensitivity = 0.0001;
     let cursor_offset = ev_motion.iter().map(|ev| ev.delta).sum::<Vec2>() * -mouse_sensitivity;
     let rotation_speed = 5.0;
+    if cursor_offset == Vec2::ZERO {
+        return;
+    }
 
     camera.front = Vec3::normalize(camera.front);
-    let camera_clone = camera.clone();
+    let camera_clone = *camera;
 
     let original_front = Vec3::new(0.0, 0.0, 1.0);
 
@@ -119,43 +331,83 @@ fn rotate_camera(
     println!("ANGLE IS: {angle}");
 }
 
-fn test(
-    mut commands: Commands,
-    input_mouse: Res<Input<MouseButton>>,
-    mut compute_buffers: ResMut<ComputeBuffers>,
-    camera: Res<SceneCamera>,
-    mut scene: ResMut<Scene>,
-) {
-    /*if input_mouse.pressed(MouseButton::Left) {
-        /*scene.add_sphere(
-            Sphere::new(camera.position, 2.0)
-        );*/
-        scene.spheres.push(Sphere::new(camera.position + Vec3::new(0.0, 0.0, 2.0), 0.1));
-    }*/
+// P cycles through the projections
+fn cycle_projection(input_keyboard: Res<Input<KeyCode>>, mut camera: ResMut<SceneCamera>) {
+    if input_keyboard.just_pressed(KeyCode::P) {
+        camera.projection = camera.projection.next();
+        println!("{:?} projection", camera.projection);
+    }
 }
 
-fn update_camera_buffers(
-    mut commands: Commands,
-    mut compute_buffers: ResMut<ComputeBuffers>,
-    camera: Res<SceneCamera>,
+// Ctrl+scroll or the +/- keys widen and narrow the field of view
+fn adjust_fov(
+    mut ev_scroll: EventReader<MouseWheel>,
+    input_keyboard: Res<Input<KeyCode>>,
+    mut camera: ResMut<SceneCamera>,
 ) {
-    compute_buffers.set_value_at(
-        BufferType::CameraPosition as u32,
-        vec![camera.position],
-        &mut commands,
-    );
+    let control = input_keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let scroll_offset: f32 = ev_scroll.iter().map(|ev| ev.y).sum();
+    let mut steps = if control { -scroll_offset } else { 0.0 };
+    if input_keyboard.any_just_pressed([KeyCode::Equals, KeyCode::NumpadAdd]) {
+        steps -= 1.0;
+    }
+    if input_keyboard.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        steps += 1.0;
+    }
+    if steps == 0.0 {
+        return;
+    }
 
-    compute_buffers.set_value_at(
-        BufferType::CameraDirection as u32,
-        vec![camera.front],
-        &mut commands,
+    let fov_step = 5.0;
+    camera.vertical_fov =
+        (camera.vertical_fov + steps * fov_step).clamp(MIN_VERTICAL_FOV, MAX_VERTICAL_FOV);
+    println!(
+        "vertical fov {:.0} degrees, {:.0}mm lens",
+        camera.vertical_fov,
+        camera.focal_length()
     );
+}
 
-    compute_buffers.set_value_at(
-        BufferType::InverseViewMatrix as u32,
-        vec![camera.inverse_view_matrix],
-        &mut commands,
-    );
+// A left click focuses the lens on the surface under the cursor
+fn focus_on_click(
+    input_mouse: Res<Input<MouseButton>>,
+    windows: Query<&Window>,
+    window_size: Res<WindowSize>,
+    scene: Res<Scene>,
+    bvh: Res<Bvh>,
+    mesh_buffers: Res<MeshBuffers>,
+    mut camera: ResMut<SceneCamera>,
+) {
+    if !input_mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+    // The compute texture fills the window, so the cursor is at the same pixel of it
+    let Some(cursor) = windows
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+    else {
+        return;
+    };
+
+    let resolution = window_size.0.as_uvec2();
+    match focal_distance_at(&scene, &bvh, &mesh_buffers, &camera, resolution, cursor) {
+        Some(distance) => {
+            println!("focused at {distance:.2}");
+            camera.focal_distance = distance;
+        }
+        None => println!("nothing to focus on under the cursor"),
+    }
+}
+
+fn update_camera_buffers(mut compute_buffers: ResMut<ComputeBuffers>, camera: Res<SceneCamera>) {
+    compute_buffers.set_value_at(CAMERA_POSITION_BUFFER, camera.position);
+
+    compute_buffers.set_value_at(CAMERA_DIRECTION_BUFFER, camera.front);
+
+    compute_buffers.set_value_at(INVERSE_VIEW_MATRIX_BUFFER, camera.inverse_view_matrix);
+
+    compute_buffers.set_value_at(CAMERA_LENS_BUFFER, GpuCameraLens::from(&*camera));
 
     //println!("CAMERA POSITION IS: {}", camera.position);
     //println!("CAMERA DIRECTION IS: {}", camera.front);
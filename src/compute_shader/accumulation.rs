@@ -0,0 +1,50 @@
+use bevy::prelude::*;
+
+use crate::{
+    camera::camera_update::SceneCamera,
+    compute_shader::compute_pass::ComputePassStatus,
+    scene::{environment::environment::EnvironmentChangedEvent, scene::Scene},
+    ComputeBuffers, RenderSettings, ResizedWindowEvent, FRAME_COUNT_BUFFER,
+};
+
+pub struct AccumulationPlugin;
+impl Plugin for AccumulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AccumulatedFrames>();
+        // PostUpdate so every change made to the camera or scene during Update is seen this frame
+        app.add_systems(PostUpdate, update_accumulated_frames);
+    }
+}
+
+// Number of frames averaged into the accumulation texture, zero tells the shader to start over.
+// Held at zero while the compute passes are not running, so a recompiled shader starts afresh.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct AccumulatedFrames(pub u32);
+
+#[allow(clippy::too_many_arguments)]
+fn update_accumulated_frames(
+    mut frames: ResMut<AccumulatedFrames>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+    mut ev_window_resized: EventReader<ResizedWindowEvent>,
+    mut ev_environment_changed: EventReader<EnvironmentChangedEvent>,
+    camera: Res<SceneCamera>,
+    scene: Res<Scene>,
+    settings: Res<RenderSettings>,
+    status: Res<ComputePassStatus>,
+) {
+    if !status.ready
+        || camera.is_changed()
+        || scene.is_changed()
+        || settings.is_changed()
+        || !ev_window_resized.is_empty()
+        || !ev_environment_changed.is_empty()
+    {
+        frames.0 = 0;
+    } else {
+        frames.0 = frames.0.saturating_add(1);
+    }
+    ev_window_resized.clear();
+    ev_environment_changed.clear();
+
+    compute_buffers.set_value_at(FRAME_COUNT_BUFFER, frames.0);
+}
@@ -1,58 +1,122 @@
 use bevy::{
     prelude::*,
-    render::{MainWorld, RenderApp},
+    render::{
+        render_resource::{encase::internal::WriteInto, BufferUsages, ShaderType},
+        MainWorld, RenderApp,
+    },
 };
 
-use crate::scene::scene::Scene;
+use crate::{
+    camera::camera_update::GpuCameraLens,
+    compute_shader::render_settings::GpuRenderSettings,
+    scene::{
+        bvh::bvh::{BvhNode, PrimitiveRef},
+        environment::environment::Environment,
+        lights::light::Light,
+        materials::material::Material,
+        meshes::mesh::Triangle,
+        motion::Motion,
+        spheres::sphere::Sphere,
+    },
+};
 
-use super::lib::buffers_interface::*;
+use super::lib::{buffers_interface::*, buffers_layout::check_buffer_layouts};
 
 pub struct ComputeBuffersUpdatePlugin;
 
-#[allow(dead_code)]
-pub enum BufferType {
-    MainTexture = 0,
-    CameraPosition = 1,
-    CameraDirection = 2,
-    ScreenResolution = 3,
-    ScreenAspectRatio = 4,
-    InverseViewMatrix = 5,
-    Spheres = 6,
-}
+// The variable each buffer is bound to and the type it holds, `check_buffer_layouts` compares them
+// against the shaders
+pub const CAMERA_POSITION_BUFFER: TypedComputeBuffer<Vec3> =
+    TypedComputeBuffer::new("camera_position");
+pub const CAMERA_DIRECTION_BUFFER: TypedComputeBuffer<Vec3> =
+    TypedComputeBuffer::new("camera_direction");
+pub const SCREEN_RESOLUTION_BUFFER: TypedComputeBuffer<Vec2> =
+    TypedComputeBuffer::new("resolution");
+pub const SCREEN_ASPECT_RATIO_BUFFER: TypedComputeBuffer<f32> =
+    TypedComputeBuffer::new("aspect_ratio");
+pub const INVERSE_VIEW_MATRIX_BUFFER: TypedComputeBuffer<Mat4> =
+    TypedComputeBuffer::new("inverse_view_matrix");
+pub const SPHERES_BUFFER: TypedComputeBuffer<Vec<Sphere>> = TypedComputeBuffer::new("spheres");
+pub const MATERIALS_BUFFER: TypedComputeBuffer<Vec<Material>> =
+    TypedComputeBuffer::new("materials");
+pub const FRAME_COUNT_BUFFER: TypedComputeBuffer<u32> = TypedComputeBuffer::new("frame_count");
+pub const BVH_NODES_BUFFER: TypedComputeBuffer<Vec<BvhNode>> = TypedComputeBuffer::new("bvh_nodes");
+pub const BVH_PRIMITIVES_BUFFER: TypedComputeBuffer<Vec<PrimitiveRef>> =
+    TypedComputeBuffer::new("bvh_primitives");
+pub const VERTICES_BUFFER: TypedComputeBuffer<Vec<Vec4>> = TypedComputeBuffer::new("vertices");
+pub const NORMALS_BUFFER: TypedComputeBuffer<Vec<Vec4>> = TypedComputeBuffer::new("normals");
+pub const TRIANGLES_BUFFER: TypedComputeBuffer<Vec<Triangle>> =
+    TypedComputeBuffer::new("triangles");
+// One per mesh, see `Triangle::mesh_index`
+pub const MESH_MOTIONS_BUFFER: TypedComputeBuffer<Vec<Motion>> =
+    TypedComputeBuffer::new("mesh_motions");
+pub const LIGHTS_BUFFER: TypedComputeBuffer<Vec<Light>> = TypedComputeBuffer::new("lights");
+pub const ENVIRONMENT_BUFFER: TypedComputeBuffer<Environment> =
+    TypedComputeBuffer::new("environment");
+// See `EnvironmentMap::gpu_texels`
+pub const ENVIRONMENT_MAP_BUFFER: TypedComputeBuffer<Vec<Vec4>> =
+    TypedComputeBuffer::new("environment_map");
+pub const CAMERA_LENS_BUFFER: TypedComputeBuffer<GpuCameraLens> =
+    TypedComputeBuffer::new("camera_lens");
+// Seconds, see `RenderTime`
+pub const RENDER_TIME_BUFFER: TypedComputeBuffer<f32> = TypedComputeBuffer::new("render_time");
+pub const RENDER_SETTINGS_BUFFER: TypedComputeBuffer<GpuRenderSettings> =
+    TypedComputeBuffer::new("render_settings");
 
 impl Plugin for ComputeBuffersUpdatePlugin {
     fn build(&self, app: &mut App) {
-        init_buffers(&mut app.world);
+        register_raytracer_buffers(app);
+        app.add_systems(Update, check_buffer_layouts);
 
         let render_app = app.sub_app_mut(RenderApp);
-        init_buffers(&mut render_app.world);
 
-        render_app
-            .add_systems(ExtractSchedule, update_buffers)
-            .insert_resource(ShaderPath("shaders/raytracer.wgsl"));
+        render_app.add_systems(ExtractSchedule, update_buffers);
     }
 }
 
-pub fn update_buffers(mut commands: Commands, main_world: Res<MainWorld>) {
-    let compute_buffers_main_world = main_world.get_resource::<ComputeBuffers>().unwrap().clone();
-    commands.insert_resource(compute_buffers_main_world);
+// Only the buffers changed since the last extract are copied over
+pub fn update_buffers(
+    mut main_world: ResMut<MainWorld>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+) {
+    let mut main_buffers = main_world.resource_mut::<ComputeBuffers>();
+    for buffer in main_buffers.bypass_change_detection().0.iter_mut() {
+        if buffer.dirty.take().is_some() {
+            compute_buffers.set_bytes_at(buffer.name, buffer.bytes.clone());
+        }
+    }
 }
 
-pub fn init_buffers(world: &mut World) {
-    let compute_buffers = ComputeBuffers::new(
-        vec![
-            ComputeBuffer::new(BufferType::CameraPosition as u32, vec![Vec3::splat(0.0)]),
-            ComputeBuffer::new(BufferType::CameraDirection as u32, vec![Vec3::splat(0.0)]),
-            ComputeBuffer::new(
-                BufferType::ScreenResolution as u32,
-                vec![1920.0 as f32, 1080.0],
-            ),
-            ComputeBuffer::new(BufferType::InverseViewMatrix as u32, vec![Mat4::default()]),
-            ComputeBuffer::new(BufferType::ScreenAspectRatio as u32, vec![1920.0 as f32 / 1080.0]),
-            ComputeBuffer::new(BufferType::Spheres as u32, Scene::default().spheres),
-        ],
-        world,
-    );
-
-    world.insert_resource(compute_buffers);
+// Registers the buffers raytracer.wgsl reads, the systems that own them fill them in on their first
+// update before anything is rendered
+fn register_raytracer_buffers(app: &mut App) {
+    // Takes the name and type from the handle, so the two can not disagree
+    fn register<T>(app: &mut App, buffer: TypedComputeBuffer<T>, usage: BufferUsages)
+    where
+        T: ShaderType + WriteInto + Default,
+    {
+        app.register_compute_buffer::<T>(buffer.name, usage);
+    }
+
+    register(app, CAMERA_POSITION_BUFFER, BufferUsages::STORAGE);
+    register(app, CAMERA_DIRECTION_BUFFER, BufferUsages::STORAGE);
+    register(app, SCREEN_RESOLUTION_BUFFER, BufferUsages::STORAGE);
+    register(app, SCREEN_ASPECT_RATIO_BUFFER, BufferUsages::STORAGE);
+    register(app, INVERSE_VIEW_MATRIX_BUFFER, BufferUsages::STORAGE);
+    register(app, SPHERES_BUFFER, BufferUsages::STORAGE);
+    register(app, MATERIALS_BUFFER, BufferUsages::STORAGE);
+    register(app, FRAME_COUNT_BUFFER, BufferUsages::STORAGE);
+    register(app, BVH_NODES_BUFFER, BufferUsages::STORAGE);
+    register(app, BVH_PRIMITIVES_BUFFER, BufferUsages::STORAGE);
+    register(app, VERTICES_BUFFER, BufferUsages::STORAGE);
+    register(app, NORMALS_BUFFER, BufferUsages::STORAGE);
+    register(app, TRIANGLES_BUFFER, BufferUsages::STORAGE);
+    register(app, MESH_MOTIONS_BUFFER, BufferUsages::STORAGE);
+    register(app, LIGHTS_BUFFER, BufferUsages::STORAGE);
+    register(app, ENVIRONMENT_BUFFER, BufferUsages::STORAGE);
+    register(app, ENVIRONMENT_MAP_BUFFER, BufferUsages::STORAGE);
+    // Small and read by every invocation, uniform buffers
+    register(app, RENDER_SETTINGS_BUFFER, BufferUsages::UNIFORM);
+    register(app, CAMERA_LENS_BUFFER, BufferUsages::UNIFORM);
+    register(app, RENDER_TIME_BUFFER, BufferUsages::UNIFORM);
 }
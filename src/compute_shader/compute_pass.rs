@@ -0,0 +1,597 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use bevy::{
+    ecs::event::ManualEventReader,
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        main_graph::node::CAMERA_DRIVER,
+        render_graph::{self, RenderGraph},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        MainWorld, RenderApp,
+    },
+};
+
+use crate::{
+    compute_shader::lib::{
+        buffers_interface::{ComputeBuffers, ComputeTextures},
+        buffers_layout::{
+            load_shader_module, reflect_bind_group_layout, shader_bindings, shader_error_message,
+            ShaderBinding,
+        },
+    },
+    ErrorOverlay, WindowSize,
+};
+
+pub const SHADER_ERROR_SOURCE: &str = "shader";
+
+// A compute shader entry point that runs every frame as the render graph node `name`. It is
+// dispatched once per pixel of the render target and gets the registered buffers and textures its
+// shader declares, each bound to the variable of the same name at the binding the shader gives it.
+#[derive(Clone, Debug)]
+pub struct ComputePass {
+    pub name: &'static str,
+    // Asset path of the WGSL file
+    pub shader: &'static str,
+    pub entry_point: &'static str,
+    // Compute buffers and textures by their registered name. Every binding the shader can write has
+    // to be listed as an output.
+    pub inputs: Vec<&'static str>,
+    pub outputs: Vec<&'static str>,
+}
+
+impl ComputePass {
+    // Whether this pass has to wait for `earlier`, because one of them writes what the other uses
+    fn depends_on(&self, earlier: &ComputePass) -> bool {
+        let shares =
+            |names: &[&str], others: &[&str]| names.iter().any(|name| others.contains(name));
+        shares(&earlier.outputs, &self.inputs)
+            || shares(&earlier.outputs, &self.outputs)
+            || shares(&earlier.inputs, &self.outputs)
+    }
+}
+
+// Passes in the order they were added, kept in both worlds
+#[derive(Resource, Default)]
+pub struct ComputePasses(pub Vec<ComputePass>);
+
+// Passes that are skipped until they are removed again. Only a pass that writes its outputs in
+// place, or whose outputs are also written by an earlier pass, can be turned off without leaving
+// stale data for the passes after it.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct DisabledComputePasses(pub HashSet<&'static str>);
+
+impl DisabledComputePasses {
+    pub fn toggle(&mut self, name: &'static str) {
+        if !self.0.remove(name) {
+            self.0.insert(name);
+        }
+    }
+}
+
+// Shader defs every compute pass is compiled with, kept in sync with the compile-time options of
+// `RenderSettings`. Changing them switches the passes to pipelines compiled with the new defs.
+#[derive(Resource, Clone, Default, PartialEq, ExtractResource)]
+pub struct ComputeShaderDefs(pub Vec<ShaderDefVal>);
+
+// Adds the node of `pass` after every earlier pass it depends on, passes added later never run
+// first so the graph can not form a cycle
+pub(crate) fn add_compute_pass(app: &mut App, pass: ComputePass) {
+    app.world
+        .get_resource_or_insert_with(ComputePasses::default)
+        .0
+        .push(pass.clone());
+
+    let render_app = app.sub_app_mut(RenderApp);
+    let mut passes = render_app
+        .world
+        .get_resource_or_insert_with(ComputePasses::default);
+    if passes.0.iter().any(|added| added.name == pass.name) {
+        panic!("compute pass {} was added twice", pass.name);
+    }
+    let dependencies: Vec<&'static str> = passes
+        .0
+        .iter()
+        .filter(|earlier| pass.depends_on(earlier))
+        .map(|earlier| earlier.name)
+        .collect();
+    passes.0.push(pass.clone());
+
+    let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+    render_graph.add_node(pass.name, ComputePassNode { name: pass.name });
+    for dependency in dependencies {
+        render_graph.add_node_edge(dependency, pass.name);
+    }
+    render_graph.add_node_edge(pass.name, CAMERA_DRIVER);
+}
+
+pub struct ComputePassPipeline {
+    pub name: &'static str,
+    // Shared by every variant, shader defs can not change the bindings of a shader
+    pub bindings: Vec<ShaderBinding>,
+    pub bind_group_layout: BindGroupLayout,
+    // The variant for `shader_defs`, or the previous one if those were rejected
+    pub pipeline: CachedComputePipelineId,
+    pub workgroup_size: UVec2,
+    pub shader_defs: Vec<ShaderDefVal>,
+    // Every variant queued so far, switching back to one does not compile it again
+    variants: HashMap<Vec<ShaderDefVal>, (CachedComputePipelineId, UVec2)>,
+    // Rebuilt in `prepare_bind_group` whenever a buffer or texture is replaced
+    pub bind_group: Option<BindGroup>,
+}
+
+#[derive(Resource)]
+pub struct ComputePassPipelines {
+    pub pipelines: Vec<ComputePassPipeline>,
+    // Passes whose shader could not be loaded or reflected, they are set up again when their
+    // shader is reloaded
+    pub failed: Vec<FailedComputePass>,
+}
+
+pub struct FailedComputePass {
+    pub name: &'static str,
+    pub error: String,
+    // Keeps the shader loaded, no pipeline holds on to it
+    shader: Handle<Shader>,
+}
+
+impl FailedComputePass {
+    fn new(asset_server: &AssetServer, pass: &ComputePass, error: String) -> Self {
+        FailedComputePass {
+            name: pass.name,
+            error,
+            shader: asset_server.load(pass.shader),
+        }
+    }
+}
+
+impl ComputePassPipelines {
+    // Stops running `pass` until its shader is reloaded, `error` is reported like a shader that
+    // failed to reflect
+    pub fn fail(&mut self, asset_server: &AssetServer, pass: &ComputePass, error: String) {
+        self.pipelines.retain(|pipeline| pipeline.name != pass.name);
+        self.failed
+            .push(FailedComputePass::new(asset_server, pass, error));
+    }
+
+    // Passes only run together, while one of them is compiling or failed to the texture on screen
+    // keeps the last frame they all rendered
+    fn ready(&self, pipeline_cache: &PipelineCache) -> bool {
+        self.failed.is_empty()
+            && self.pipelines.iter().all(|pass| {
+                pass.bind_group.is_some()
+                    && matches!(
+                        pipeline_state(pipeline_cache, pass.pipeline),
+                        Some(CachedPipelineState::Ok(_))
+                    )
+            })
+    }
+}
+
+// `PipelineCache::get_compute_pipeline_state` panics for pipelines that were queued but not yet
+// processed, which is the case during the first extract
+fn pipeline_state(
+    pipeline_cache: &PipelineCache,
+    id: CachedComputePipelineId,
+) -> Option<&CachedPipelineState> {
+    pipeline_cache
+        .pipelines()
+        .nth(id.id())
+        .map(|pipeline| &pipeline.state)
+}
+
+// Whether the compute passes rendered the last frame, copied to the main world on every extract
+#[derive(Resource, Default)]
+pub struct ComputePassStatus {
+    pub ready: bool,
+}
+
+// Reports the passes whose shader failed to compile to the log and the overlay, once each time the
+// set of failing passes changes. The pipeline cache recompiles them when their shader is reloaded.
+pub fn report_compute_pass_status(
+    mut main_world: ResMut<MainWorld>,
+    pipelines: Option<Res<ComputePassPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    asset_server: Res<AssetServer>,
+    passes: Res<ComputePasses>,
+    mut reported: Local<(Vec<&'static str>, Vec<String>)>,
+) {
+    let Some(pipelines) = pipelines else {
+        return;
+    };
+    main_world.resource_mut::<ComputePassStatus>().ready = pipelines.ready(&pipeline_cache);
+
+    let failed: Vec<&'static str> = pipelines
+        .pipelines
+        .iter()
+        .filter(|pass| {
+            matches!(
+                pipeline_state(&pipeline_cache, pass.pipeline),
+                Some(CachedPipelineState::Err(_))
+            )
+        })
+        .map(|pass| pass.name)
+        .collect();
+    let setup_errors: Vec<String> = pipelines
+        .failed
+        .iter()
+        .map(|failed| failed.error.clone())
+        .collect();
+    if reported.0 == failed && reported.1 == setup_errors {
+        return;
+    }
+
+    let mut errors = setup_errors.clone();
+    for pipeline in pipelines
+        .pipelines
+        .iter()
+        .filter(|pass| failed.contains(&pass.name))
+    {
+        let Some(pass) = passes.0.iter().find(|pass| pass.name == pipeline.name) else {
+            continue;
+        };
+        // The pipeline cache only keeps the error without the source it points into
+        let error = shader_error_message(&asset_server, pass.shader, &pipeline.shader_defs)
+            .unwrap_or_else(
+                || match pipeline_state(&pipeline_cache, pipeline.pipeline) {
+                    Some(CachedPipelineState::Err(error)) => format!("{}: {error}", pass.shader),
+                    _ => format!("{}: failed to compile", pass.shader),
+                },
+            );
+        // Passes sharing a shader fail with the same error
+        if !errors.contains(&error) {
+            errors.push(error);
+        }
+    }
+
+    let mut overlay = main_world.resource_mut::<ErrorOverlay>();
+    if errors.is_empty() {
+        println!("compute shaders compiled");
+        overlay.clear(SHADER_ERROR_SOURCE);
+    } else {
+        for error in errors.iter() {
+            println!("{error}");
+        }
+        overlay.set(SHADER_ERROR_SOURCE, errors.join("\n"));
+    }
+    *reported = (failed, setup_errors);
+}
+
+// Sets up the passes that failed to load or reflect their shader again once it is reloaded
+pub fn retry_failed_compute_passes(
+    world: &mut World,
+    mut ev_shader: Local<ManualEventReader<AssetEvent<Shader>>>,
+) {
+    let reloaded: Vec<Handle<Shader>> = {
+        let main_world = world.resource::<MainWorld>();
+        let Some(events) = main_world.get_resource::<Events<AssetEvent<Shader>>>() else {
+            return;
+        };
+        ev_shader
+            .iter(events)
+            .filter_map(|event| match event {
+                AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                    Some(handle.clone_weak())
+                }
+                AssetEvent::Removed { .. } => None,
+            })
+            .collect()
+    };
+    if reloaded.is_empty() {
+        return;
+    }
+
+    let shader_defs = world.resource::<ComputeShaderDefs>().0.clone();
+    world.resource_scope(|world, mut pipelines: Mut<ComputePassPipelines>| {
+        let passes = world.resource::<ComputePasses>();
+        let mut still_failed = Vec::new();
+        for failed in std::mem::take(&mut pipelines.failed) {
+            let pass = passes.0.iter().find(|pass| pass.name == failed.name);
+            let Some(pass) = pass.filter(|_| reloaded.contains(&failed.shader)) else {
+                still_failed.push(failed);
+                continue;
+            };
+            match ComputePassPipeline::new(world, pass, &shader_defs) {
+                Ok(pipeline) => pipelines.pipelines.push(pipeline),
+                Err(error) => still_failed.push(FailedComputePass::new(
+                    world.resource::<AssetServer>(),
+                    pass,
+                    error,
+                )),
+            }
+        }
+        pipelines.failed = still_failed;
+    });
+}
+
+impl FromWorld for ComputePassPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let passes = world
+            .get_resource::<ComputePasses>()
+            .map(|passes| passes.0.clone())
+            .unwrap_or_default();
+        let shader_defs = world
+            .get_resource::<ComputeShaderDefs>()
+            .map(|shader_defs| shader_defs.0.clone())
+            .unwrap_or_default();
+        let mut pipelines = ComputePassPipelines {
+            pipelines: Vec::new(),
+            failed: Vec::new(),
+        };
+        for pass in passes.iter() {
+            match ComputePassPipeline::new(world, pass, &shader_defs) {
+                Ok(pipeline) => pipelines.pipelines.push(pipeline),
+                Err(error) => pipelines.failed.push(FailedComputePass::new(
+                    world.resource::<AssetServer>(),
+                    pass,
+                    error,
+                )),
+            }
+        }
+        pipelines
+    }
+}
+
+// Switches every pass to its variant for the current `ComputeShaderDefs`, queuing the ones that
+// were not compiled before. Until they are the passes do not run and the last frame stays on
+// screen, like while a reloaded shader compiles.
+pub fn specialize_compute_passes(world: &mut World) {
+    let shader_defs = world.resource::<ComputeShaderDefs>().0.clone();
+    world.resource_scope(|world, mut pipelines: Mut<ComputePassPipelines>| {
+        let passes = world.resource::<ComputePasses>();
+        for pipeline in pipelines
+            .pipelines
+            .iter_mut()
+            .filter(|pipeline| pipeline.shader_defs != shader_defs)
+        {
+            let Some(pass) = passes.0.iter().find(|pass| pass.name == pipeline.name) else {
+                continue;
+            };
+            if let Err(error) = pipeline.specialize(world, pass, &shader_defs) {
+                println!("{error}");
+            }
+        }
+    });
+}
+
+impl ComputePassPipeline {
+    fn new(
+        world: &World,
+        pass: &ComputePass,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<Self, String> {
+        let asset_server = world.resource::<AssetServer>();
+        let compute_buffers = world.resource::<ComputeBuffers>();
+        let compute_textures = world.resource::<ComputeTextures>();
+        let (module, bindings) = reflect_bind_group_layout(
+            asset_server,
+            pass.shader,
+            shader_defs,
+            compute_buffers,
+            compute_textures,
+        )?;
+        let workgroup_size = workgroup_size(&module, pass)?;
+
+        let errors = pass_binding_errors(pass, compute_buffers, compute_textures, &bindings);
+        if !errors.is_empty() {
+            return Err(format!("{}:\n{}", pass.shader, errors.join("\n")));
+        }
+
+        let entries: Vec<BindGroupLayoutEntry> =
+            bindings.iter().map(|binding| binding.entry).collect();
+        let bind_group_layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some(pass.name),
+                    entries: &entries,
+                });
+        let pipeline = queue_pipeline(world, pass, &bind_group_layout, shader_defs);
+
+        Ok(ComputePassPipeline {
+            name: pass.name,
+            bindings,
+            bind_group_layout,
+            pipeline,
+            workgroup_size,
+            shader_defs: shader_defs.to_vec(),
+            variants: HashMap::from([(shader_defs.to_vec(), (pipeline, workgroup_size))]),
+            bind_group: None,
+        })
+    }
+
+    // Uses the variant compiled with `shader_defs` from now on. Defs under which the shader
+    // declares other bindings are rejected once and the current variant is kept.
+    fn specialize(
+        &mut self,
+        world: &World,
+        pass: &ComputePass,
+        shader_defs: &[ShaderDefVal],
+    ) -> Result<(), String> {
+        self.shader_defs = shader_defs.to_vec();
+        if let Some(&(pipeline, workgroup_size)) = self.variants.get(shader_defs) {
+            self.pipeline = pipeline;
+            self.workgroup_size = workgroup_size;
+            return Ok(());
+        }
+
+        let workgroup_size =
+            match load_shader_module(world.resource::<AssetServer>(), pass.shader, shader_defs) {
+                // Queued anyway so the pipeline cache reports the error, as for a reloaded shader
+                Err(_) => self.workgroup_size,
+                Ok(module) => {
+                    let bindings = shader_bindings(&module)
+                        .map_err(|error| format!("{}: {error}", pass.shader))?;
+                    if bindings != self.bindings {
+                        return Err(format!(
+                            "{}: the shader defs {shader_defs:?} change its bindings, keeping the \
+                             pipeline compiled without them",
+                            pass.shader
+                        ));
+                    }
+                    workgroup_size(&module, pass)?
+                }
+            };
+
+        let pipeline = queue_pipeline(world, pass, &self.bind_group_layout, shader_defs);
+        self.variants
+            .insert(shader_defs.to_vec(), (pipeline, workgroup_size));
+        self.pipeline = pipeline;
+        self.workgroup_size = workgroup_size;
+        Ok(())
+    }
+}
+
+fn workgroup_size(module: &naga::Module, pass: &ComputePass) -> Result<UVec2, String> {
+    let entry_point = module
+        .entry_points
+        .iter()
+        .find(|entry_point| {
+            entry_point.name == pass.entry_point && entry_point.stage == naga::ShaderStage::Compute
+        })
+        .ok_or_else(|| {
+            format!(
+                "{}: there is no compute entry point {}",
+                pass.shader, pass.entry_point
+            )
+        })?;
+    let [x, y, _] = entry_point.workgroup_size;
+    Ok(UVec2::new(x, y))
+}
+
+fn queue_pipeline(
+    world: &World,
+    pass: &ComputePass,
+    bind_group_layout: &BindGroupLayout,
+    shader_defs: &[ShaderDefVal],
+) -> CachedComputePipelineId {
+    world
+        .resource::<PipelineCache>()
+        .queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some(Cow::from(pass.name)),
+            layout: vec![bind_group_layout.clone()],
+            push_constant_ranges: Vec::new(),
+            shader: world.resource::<AssetServer>().load(pass.shader),
+            shader_defs: shader_defs.to_vec(),
+            entry_point: Cow::from(pass.entry_point),
+        })
+}
+
+// Inputs and outputs the shader does not bind, and bindings it writes without declaring them
+fn pass_binding_errors(
+    pass: &ComputePass,
+    compute_buffers: &ComputeBuffers,
+    compute_textures: &ComputeTextures,
+    bindings: &[ShaderBinding],
+) -> Vec<String> {
+    let names = compute_buffers
+        .0
+        .iter()
+        .map(|buffer| buffer.name)
+        .chain(compute_textures.0.iter().map(|texture| texture.name))
+        .collect::<Vec<_>>();
+    let is_writable = |entry: &BindGroupLayoutEntry| match entry.ty {
+        BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            ..
+        } => !read_only,
+        BindingType::StorageTexture { access, .. } => access != StorageTextureAccess::ReadOnly,
+        _ => false,
+    };
+
+    let mut errors = Vec::new();
+    let declared = pass.inputs.iter().map(|name| (name, false));
+    for (name, written) in declared.chain(pass.outputs.iter().map(|name| (name, true))) {
+        if !names.contains(name) {
+            errors.push(format!(
+                "pass {} uses {name}, which is neither a compute buffer nor a compute texture",
+                pass.name
+            ));
+            continue;
+        }
+        match bindings.iter().find(|binding| binding.name == *name) {
+            None => errors.push(format!(
+                "pass {} uses {name}, but the shader does not declare it",
+                pass.name
+            )),
+            Some(binding) if written && !is_writable(&binding.entry) => errors.push(format!(
+                "pass {} writes {name}, but binding {} is declared read only",
+                pass.name, binding.entry.binding
+            )),
+            Some(_) => {}
+        }
+    }
+
+    for binding in bindings
+        .iter()
+        .filter(|binding| is_writable(&binding.entry))
+    {
+        if !pass.outputs.contains(&binding.name.as_str()) {
+            errors.push(format!(
+                "pass {} can write binding {} ({}) but does not list it as an output",
+                pass.name, binding.entry.binding, binding.name
+            ));
+        }
+    }
+    errors
+}
+
+struct ComputePassNode {
+    name: &'static str,
+}
+
+impl render_graph::Node for ComputePassNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        if world
+            .get_resource::<DisabledComputePasses>()
+            .is_some_and(|disabled| disabled.0.contains(self.name))
+        {
+            return Ok(());
+        }
+        let pipelines = world.resource::<ComputePassPipelines>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        // Nothing to do until every shader compiled and the buffers were uploaded
+        if !pipelines.ready(pipeline_cache) {
+            return Ok(());
+        }
+        let Some(pass) = pipelines
+            .pipelines
+            .iter()
+            .find(|pass| pass.name == self.name)
+        else {
+            return Ok(());
+        };
+        let (Some(pipeline), Some(bind_group)) = (
+            pipeline_cache.get_compute_pipeline(pass.pipeline),
+            &pass.bind_group,
+        ) else {
+            return Ok(());
+        };
+
+        let mut compute_pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some(self.name),
+                });
+        compute_pass.set_bind_group(0, bind_group, &[]);
+        compute_pass.set_pipeline(pipeline);
+
+        let resolution = world.resource::<WindowSize>().0.as_uvec2();
+        // Round up so sizes that are not a multiple of the workgroup still cover the edge pixels,
+        // the shaders skip invocations outside the image
+        let workgroups = (resolution + pass.workgroup_size - 1) / pass.workgroup_size;
+        compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+
+        Ok(())
+    }
+}
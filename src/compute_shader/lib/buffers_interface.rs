@@ -1,5 +1,16 @@
+use std::{marker::PhantomData, ops::Range};
+
 use super::buffers_setup::{setup, ComputeRenderStartPlugin};
-use bevy::prelude::*;
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::{encase::internal::WriteInto, BufferUsages, ShaderType, TextureFormat},
+        RenderApp,
+    },
+};
+
+use crate::compute_shader::compute_pass::{add_compute_pass, ComputePass};
 
 pub struct ComputeBuffersPlugin;
 
@@ -12,12 +23,168 @@ impl Plugin for ComputeBuffersPlugin {
 
 #[derive(Clone)]
 pub struct ComputeBuffer {
-    pub binding: u32,
+    // The variable the shaders declare it as, also names it in the inputs and outputs of a
+    // `ComputePass`
+    pub name: &'static str,
+    // STORAGE or UNIFORM, whichever the shaders declare the binding as
+    pub usage: BufferUsages,
     pub bytes: Vec<u8>,
+    // Bytes changed by `set_value_at`, cleared once they were passed on to the render world and
+    // then once more when they were uploaded to the GPU
+    pub dirty: Option<Range<u64>>,
+    pub layout: BufferLayout,
+}
+
+// Size and alignment of the Rust type a buffer is serialized from, compared against the shader
+// at startup. Runtime sized arrays report a single element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferLayout {
+    pub type_name: &'static str,
+    pub size: u64,
+    pub alignment: u64,
+}
+
+// A compute buffer by name together with the type it holds, values are written with the WGSL
+// layout of `T` (see `ShaderType`) so vec3 members get padded like the shader expects
+pub struct TypedComputeBuffer<T> {
+    pub name: &'static str,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedComputeBuffer<T> {
+    pub const fn new(name: &'static str) -> Self {
+        TypedComputeBuffer {
+            name,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for TypedComputeBuffer<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
 }
 
+impl<T> Copy for TypedComputeBuffer<T> {}
+
 #[derive(Resource, Clone)]
 pub struct ComputeBuffers(pub Vec<ComputeBuffer>);
 
-#[derive(Resource)]
-pub struct ShaderPath(pub &'static str);
+// A texture the compute passes share, allocated at the window size and recreated whenever the
+// window is resized, so its contents only last until then
+#[derive(Clone)]
+pub struct ComputeTexture {
+    // The variable the shaders declare it as, also names it in the inputs and outputs of a
+    // `ComputePass`
+    pub name: &'static str,
+    pub format: TextureFormat,
+    // Default until the textures are first allocated at startup
+    pub image: Handle<Image>,
+}
+
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct ComputeTextures(pub Vec<ComputeTexture>);
+
+impl ComputeTextures {
+    pub fn image(&self, name: &str) -> Option<&Handle<Image>> {
+        self.0
+            .iter()
+            .find(|texture| texture.name == name)
+            .map(|texture| &texture.image)
+    }
+}
+
+// Lets other crates extend the raytracer without touching its internals. Buffers can be registered
+// at any point while the app is built, passes are added after `RaytracerPlugins`.
+pub trait ComputeAppExt {
+    // Binds a buffer holding a `T` to the variable `name` of every compute shader that declares it,
+    // at whichever binding the shader gives it. It starts out as `T::default()`, set it through
+    // `ComputeBuffers::set_value_at` with a `TypedComputeBuffer<T>` of the same name.
+    fn register_compute_buffer<T>(&mut self, name: &'static str, usage: BufferUsages) -> &mut Self
+    where
+        T: ShaderType + WriteInto + Default;
+
+    // Binds a texture of `format` to the variable `name` of every compute shader that declares it,
+    // see `ComputeTexture`
+    fn register_compute_texture(&mut self, name: &'static str, format: TextureFormat) -> &mut Self;
+
+    // Runs another compute shader entry point every frame, see `ComputePass`
+    fn add_compute_pass(&mut self, pass: ComputePass) -> &mut Self;
+}
+
+impl ComputeAppExt for App {
+    fn register_compute_buffer<T>(&mut self, name: &'static str, usage: BufferUsages) -> &mut Self
+    where
+        T: ShaderType + WriteInto + Default,
+    {
+        let buffer = ComputeBuffer::new(TypedComputeBuffer::<T>::new(name), usage, T::default());
+        if let Ok(render_app) = self.get_sub_app_mut(RenderApp) {
+            register_buffer(&mut render_app.world, buffer.clone());
+        }
+        register_buffer(&mut self.world, buffer);
+        self
+    }
+
+    fn register_compute_texture(&mut self, name: &'static str, format: TextureFormat) -> &mut Self {
+        let texture = ComputeTexture {
+            name,
+            format,
+            image: Handle::default(),
+        };
+        if let Ok(render_app) = self.get_sub_app_mut(RenderApp) {
+            register_texture(&mut render_app.world, texture.clone());
+        }
+        register_texture(&mut self.world, texture);
+        self
+    }
+
+    fn add_compute_pass(&mut self, pass: ComputePass) -> &mut Self {
+        add_compute_pass(self, pass);
+        self
+    }
+}
+
+fn register_buffer(world: &mut World, buffer: ComputeBuffer) {
+    if world
+        .get_resource::<ComputeTextures>()
+        .is_some_and(|textures| textures.0.iter().any(|texture| texture.name == buffer.name))
+    {
+        panic!(
+            "compute buffer {} can not be registered, a texture has the same name",
+            buffer.name
+        );
+    }
+
+    let mut buffers = world.get_resource_or_insert_with(|| ComputeBuffers::new(Vec::new()));
+    if buffers
+        .0
+        .iter()
+        .any(|existing| existing.name == buffer.name)
+    {
+        panic!("compute buffer {} was registered twice", buffer.name);
+    }
+    buffers.0.push(buffer);
+}
+
+fn register_texture(world: &mut World, texture: ComputeTexture) {
+    if world
+        .get_resource::<ComputeBuffers>()
+        .is_some_and(|buffers| buffers.0.iter().any(|buffer| buffer.name == texture.name))
+    {
+        panic!(
+            "compute texture {} can not be registered, a compute buffer has the same name",
+            texture.name
+        );
+    }
+
+    let mut textures = world.get_resource_or_insert_with(ComputeTextures::default);
+    if textures
+        .0
+        .iter()
+        .any(|existing| existing.name == texture.name)
+    {
+        panic!("compute texture {} was registered twice", texture.name);
+    }
+    textures.0.push(texture);
+}
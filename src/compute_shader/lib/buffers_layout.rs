@@ -0,0 +1,467 @@
+use std::{collections::HashMap, num::NonZeroU64, path::Path};
+
+use bevy::{prelude::*, render::render_resource::*};
+use naga::{
+    proc::Layouter,
+    valid::{Capabilities, ValidationFlags, Validator},
+    AddressSpace, ImageClass, ImageDimension, Module, ScalarKind, StorageAccess, StorageFormat,
+    TypeInner,
+};
+use naga_oil::compose::{preprocess::Preprocessor, ShaderDefValue};
+
+use crate::{
+    compute_shader::compute_pass::{ComputePasses, ComputeShaderDefs},
+    ErrorOverlay,
+};
+
+use super::buffers_interface::*;
+
+pub const LAYOUT_ERROR_SOURCE: &str = "buffer layout";
+
+// A variable of bind group 0, the compute buffer or texture of the same name is bound to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShaderBinding {
+    pub name: String,
+    pub entry: BindGroupLayoutEntry,
+}
+
+// Compares every compute buffer and texture against the variable each compute pass shader declares
+// at its binding whenever one of them is (re)loaded or the shader defs change. A mismatch would
+// otherwise only show up as garbage on screen.
+#[allow(clippy::too_many_arguments)]
+pub fn check_buffer_layouts(
+    mut ev_shader: EventReader<AssetEvent<Shader>>,
+    passes: Option<Res<ComputePasses>>,
+    asset_server: Res<AssetServer>,
+    shaders: Res<Assets<Shader>>,
+    shader_defs: Res<ComputeShaderDefs>,
+    compute_buffers: Res<ComputeBuffers>,
+    compute_textures: Res<ComputeTextures>,
+    mut overlay: ResMut<ErrorOverlay>,
+) {
+    let Some(passes) = passes else {
+        return;
+    };
+    let mut paths: Vec<&'static str> = passes.0.iter().map(|pass| pass.shader).collect();
+    paths.sort_unstable();
+    paths.dedup();
+
+    let handles: Vec<Handle<Shader>> = paths
+        .iter()
+        .map(|path| asset_server.get_handle(*path))
+        .collect();
+    let loaded = ev_shader.iter().any(|event| match event {
+        AssetEvent::Created { handle: changed } | AssetEvent::Modified { handle: changed } => {
+            handles.contains(changed)
+        }
+        AssetEvent::Removed { .. } => false,
+    });
+    if !loaded && !shader_defs.is_changed() {
+        return;
+    }
+
+    let mut errors = Vec::new();
+    for (path, handle) in paths.iter().zip(handles.iter()) {
+        let Some(shader) = shaders.get(handle) else {
+            continue;
+        };
+        // A shader that does not parse is reported by the pipeline cache instead
+        let Ok(module) = parse_shader(shader.source.as_str(), path, &shader_defs.0) else {
+            continue;
+        };
+        let mut shader_errors = buffer_layout_errors(&module, &compute_buffers.0);
+        if let Ok(bindings) = shader_bindings(&module) {
+            shader_errors.extend(texture_binding_errors(&bindings, &compute_textures));
+        }
+        errors.extend(
+            shader_errors
+                .into_iter()
+                .map(|error| format!("{path}: {error}")),
+        );
+    }
+
+    for error in errors.iter() {
+        println!("{error}");
+    }
+    if errors.is_empty() {
+        overlay.clear(LAYOUT_ERROR_SOURCE);
+    } else {
+        overlay.set(LAYOUT_ERROR_SOURCE, errors.join("\n"));
+    }
+}
+
+// One message per buffer the shader declares under a name no compute buffer was registered with,
+// or whose Rust layout or usage differs from the shader's. Runtime sized arrays are compared by
+// their element.
+pub fn buffer_layout_errors(module: &Module, buffers: &[ComputeBuffer]) -> Vec<String> {
+    let mut layouter = Layouter::default();
+    if let Err(error) = layouter.update(&module.types, &module.constants) {
+        return vec![format!("could not lay out the shader types: {error}")];
+    }
+
+    let mut errors = Vec::new();
+    for variable in module.global_variables.iter().map(|(_, variable)| variable) {
+        let Some(binding) = &variable.binding else {
+            continue;
+        };
+        if binding.group != 0
+            || !matches!(
+                variable.space,
+                AddressSpace::Storage { .. } | AddressSpace::Uniform
+            )
+        {
+            continue;
+        }
+
+        let name = variable.name.as_deref().unwrap_or("?");
+        let Some(buffer) = buffers.iter().find(|buffer| buffer.name == name) else {
+            errors.push(format!(
+                "binding {} ({name}) has no compute buffer",
+                binding.binding
+            ));
+            continue;
+        };
+
+        let (usage, kind) = match variable.space {
+            AddressSpace::Uniform => (BufferUsages::UNIFORM, "uniform"),
+            _ => (BufferUsages::STORAGE, "storage"),
+        };
+        if !buffer.usage.contains(usage) {
+            errors.push(format!(
+                "binding {} ({name}) is a {kind} buffer, but {} was not registered as one",
+                binding.binding, buffer.name
+            ));
+        }
+
+        let layout = &layouter[variable.ty];
+        // The smallest multiple of an alignment is the alignment itself
+        let (size, alignment) = (layout.size as u64, layout.alignment.round_up(1) as u64);
+        if (size, alignment) != (buffer.layout.size, buffer.layout.alignment) {
+            errors.push(format!(
+                "binding {} ({name}) is {} with size {size} and alignment {alignment}, \
+                 but {} holds {} with size {} and alignment {}",
+                binding.binding,
+                wgsl_type_name(module, variable.ty),
+                buffer.name,
+                short_type_name(buffer.layout.type_name),
+                buffer.layout.size,
+                buffer.layout.alignment,
+            ));
+        }
+    }
+
+    errors
+}
+
+// Reads a compute shader straight from the asset folder, the pipeline needs its bindings before
+// the asset server has loaded it
+fn load_shader_source(asset_server: &AssetServer, path: &str) -> Result<String, String> {
+    let bytes = futures_lite::future::block_on(asset_server.asset_io().load_path(Path::new(path)))
+        .map_err(|error| format!("{path}: {error}"))?;
+    String::from_utf8(bytes).map_err(|error| format!("{path}: {error}"))
+}
+
+// Resolves the `#ifdef` and `#if` directives like the pipeline cache does before compiling. The
+// lines left out are blanked, so naga's errors still point into the original source.
+fn parse_shader(source: &str, path: &str, shader_defs: &[ShaderDefVal]) -> Result<Module, String> {
+    let shader_defs: HashMap<String, ShaderDefValue> = shader_defs
+        .iter()
+        .map(|def| match def.clone() {
+            ShaderDefVal::Bool(name, value) => (name, ShaderDefValue::Bool(value)),
+            ShaderDefVal::Int(name, value) => (name, ShaderDefValue::Int(value)),
+            ShaderDefVal::UInt(name, value) => (name, ShaderDefValue::UInt(value)),
+        })
+        .collect();
+    let preprocessed = Preprocessor::default()
+        .preprocess(source, &shader_defs, false)
+        .map_err(|error| format!("{path}: {error}"))?;
+    naga::front::wgsl::parse_str(&preprocessed.preprocessed_source)
+        .map_err(|error| error.emit_to_string_with_path(source, path))
+}
+
+pub fn load_shader_module(
+    asset_server: &AssetServer,
+    path: &str,
+    shader_defs: &[ShaderDefVal],
+) -> Result<Module, String> {
+    let source = load_shader_source(asset_server, path)?;
+    parse_shader(&source, path, shader_defs)
+}
+
+// The parse or validation error of a compute shader compiled with `shader_defs` as naga reports
+// it, pointing into the source
+pub fn shader_error_message(
+    asset_server: &AssetServer,
+    path: &str,
+    shader_defs: &[ShaderDefVal],
+) -> Option<String> {
+    let source = match load_shader_source(asset_server, path) {
+        Ok(source) => source,
+        Err(error) => return Some(error),
+    };
+    let module = match parse_shader(&source, path, shader_defs) {
+        Ok(module) => module,
+        Err(error) => return Some(error),
+    };
+    Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .err()
+        .map(|error| error.emit_to_string_with_path(&source, path))
+}
+
+// Loads a compute shader and the bindings of its bind group 0, or every way in which the shader
+// disagrees with the registered buffers and textures at once
+pub fn reflect_bind_group_layout(
+    asset_server: &AssetServer,
+    path: &str,
+    shader_defs: &[ShaderDefVal],
+    compute_buffers: &ComputeBuffers,
+    compute_textures: &ComputeTextures,
+) -> Result<(Module, Vec<ShaderBinding>), String> {
+    let module = load_shader_module(asset_server, path, shader_defs)?;
+    let bindings = shader_bindings(&module).map_err(|error| format!("{path}: {error}"))?;
+
+    let mut errors = buffer_layout_errors(&module, &compute_buffers.0);
+    errors.extend(texture_binding_errors(&bindings, compute_textures));
+    if !errors.is_empty() {
+        return Err(format!("{path}:\n{}", errors.join("\n")));
+    }
+    Ok((module, bindings))
+}
+
+// Bind group 0 as the shader declares it, in declaration order
+pub fn shader_bindings(module: &Module) -> Result<Vec<ShaderBinding>, String> {
+    let mut layouter = Layouter::default();
+    layouter
+        .update(&module.types, &module.constants)
+        .map_err(|error| format!("could not lay out the shader types: {error}"))?;
+
+    let mut bindings = Vec::new();
+    for (_, variable) in module.global_variables.iter() {
+        let Some(binding) = &variable.binding else {
+            continue;
+        };
+        if binding.group != 0 {
+            continue;
+        }
+
+        let name = variable.name.as_deref().unwrap_or("?");
+        let unsupported = || {
+            format!(
+                "binding {} ({name}) has a type the raytracer can not bind",
+                binding.binding
+            )
+        };
+        let min_binding_size = NonZeroU64::new(layouter[variable.ty].size as u64);
+        let ty = match (variable.space, &module.types[variable.ty].inner) {
+            (AddressSpace::Storage { access }, _) => BindingType::Buffer {
+                ty: BufferBindingType::Storage {
+                    read_only: !access.contains(StorageAccess::STORE),
+                },
+                has_dynamic_offset: false,
+                min_binding_size,
+            },
+            (AddressSpace::Uniform, _) => BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size,
+            },
+            (
+                AddressSpace::Handle,
+                &TypeInner::Image {
+                    dim,
+                    arrayed,
+                    class,
+                },
+            ) => {
+                let view_dimension = match (dim, arrayed) {
+                    (ImageDimension::D1, false) => TextureViewDimension::D1,
+                    (ImageDimension::D2, false) => TextureViewDimension::D2,
+                    (ImageDimension::D2, true) => TextureViewDimension::D2Array,
+                    (ImageDimension::D3, false) => TextureViewDimension::D3,
+                    (ImageDimension::Cube, false) => TextureViewDimension::Cube,
+                    (ImageDimension::Cube, true) => TextureViewDimension::CubeArray,
+                    _ => return Err(unsupported()),
+                };
+                match class {
+                    ImageClass::Storage { format, access } => BindingType::StorageTexture {
+                        access: match (
+                            access.contains(StorageAccess::LOAD),
+                            access.contains(StorageAccess::STORE),
+                        ) {
+                            (true, true) => StorageTextureAccess::ReadWrite,
+                            (true, false) => StorageTextureAccess::ReadOnly,
+                            _ => StorageTextureAccess::WriteOnly,
+                        },
+                        format: texture_format(format),
+                        view_dimension,
+                    },
+                    // Compute passes read their textures with textureLoad, which unlike filtering
+                    // works for every float format including rgba32float
+                    ImageClass::Sampled { kind, multi } => BindingType::Texture {
+                        sample_type: match kind {
+                            ScalarKind::Float => TextureSampleType::Float { filterable: false },
+                            ScalarKind::Sint => TextureSampleType::Sint,
+                            ScalarKind::Uint => TextureSampleType::Uint,
+                            ScalarKind::Bool => return Err(unsupported()),
+                        },
+                        view_dimension,
+                        multisampled: multi,
+                    },
+                    ImageClass::Depth { multi } => BindingType::Texture {
+                        sample_type: TextureSampleType::Depth,
+                        view_dimension,
+                        multisampled: multi,
+                    },
+                }
+            }
+            (AddressSpace::Handle, &TypeInner::Sampler { comparison }) => {
+                BindingType::Sampler(if comparison {
+                    SamplerBindingType::Comparison
+                } else {
+                    SamplerBindingType::Filtering
+                })
+            }
+            _ => return Err(unsupported()),
+        };
+
+        bindings.push(ShaderBinding {
+            name: name.to_string(),
+            entry: BindGroupLayoutEntry {
+                binding: binding.binding,
+                visibility: ShaderStages::COMPUTE,
+                ty,
+                count: None,
+            },
+        });
+    }
+    Ok(bindings)
+}
+
+// Textures and samplers the shader declares under names no compute texture is registered with,
+// and storage textures whose format differs from the registered one
+pub fn texture_binding_errors(
+    bindings: &[ShaderBinding],
+    compute_textures: &ComputeTextures,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for ShaderBinding { name, entry } in bindings
+        .iter()
+        .filter(|binding| !matches!(binding.entry.ty, BindingType::Buffer { .. }))
+    {
+        let Some(texture) = compute_textures
+            .0
+            .iter()
+            .find(|texture| texture.name == name)
+        else {
+            errors.push(format!(
+                "binding {} ({name}) is a texture or sampler the raytracer does not bind",
+                entry.binding
+            ));
+            continue;
+        };
+        if let BindingType::StorageTexture { format, .. } = entry.ty {
+            if format != texture.format {
+                errors.push(format!(
+                    "binding {} ({name}) is a {format:?} storage texture, but the {name} texture \
+                     is {:?}",
+                    entry.binding, texture.format
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn texture_format(format: StorageFormat) -> TextureFormat {
+    match format {
+        StorageFormat::R8Unorm => TextureFormat::R8Unorm,
+        StorageFormat::R8Snorm => TextureFormat::R8Snorm,
+        StorageFormat::R8Uint => TextureFormat::R8Uint,
+        StorageFormat::R8Sint => TextureFormat::R8Sint,
+        StorageFormat::R16Uint => TextureFormat::R16Uint,
+        StorageFormat::R16Sint => TextureFormat::R16Sint,
+        StorageFormat::R16Float => TextureFormat::R16Float,
+        StorageFormat::Rg8Unorm => TextureFormat::Rg8Unorm,
+        StorageFormat::Rg8Snorm => TextureFormat::Rg8Snorm,
+        StorageFormat::Rg8Uint => TextureFormat::Rg8Uint,
+        StorageFormat::Rg8Sint => TextureFormat::Rg8Sint,
+        StorageFormat::R32Uint => TextureFormat::R32Uint,
+        StorageFormat::R32Sint => TextureFormat::R32Sint,
+        StorageFormat::R32Float => TextureFormat::R32Float,
+        StorageFormat::Rg16Uint => TextureFormat::Rg16Uint,
+        StorageFormat::Rg16Sint => TextureFormat::Rg16Sint,
+        StorageFormat::Rg16Float => TextureFormat::Rg16Float,
+        StorageFormat::Rgba8Unorm => TextureFormat::Rgba8Unorm,
+        StorageFormat::Rgba8Snorm => TextureFormat::Rgba8Snorm,
+        StorageFormat::Rgba8Uint => TextureFormat::Rgba8Uint,
+        StorageFormat::Rgba8Sint => TextureFormat::Rgba8Sint,
+        StorageFormat::Rgb10a2Unorm => TextureFormat::Rgb10a2Unorm,
+        StorageFormat::Rg11b10Float => TextureFormat::Rg11b10Float,
+        StorageFormat::Rg32Uint => TextureFormat::Rg32Uint,
+        StorageFormat::Rg32Sint => TextureFormat::Rg32Sint,
+        StorageFormat::Rg32Float => TextureFormat::Rg32Float,
+        StorageFormat::Rgba16Uint => TextureFormat::Rgba16Uint,
+        StorageFormat::Rgba16Sint => TextureFormat::Rgba16Sint,
+        StorageFormat::Rgba16Float => TextureFormat::Rgba16Float,
+        StorageFormat::Rgba32Uint => TextureFormat::Rgba32Uint,
+        StorageFormat::Rgba32Sint => TextureFormat::Rgba32Sint,
+        StorageFormat::Rgba32Float => TextureFormat::Rgba32Float,
+        StorageFormat::R16Unorm => TextureFormat::R16Unorm,
+        StorageFormat::R16Snorm => TextureFormat::R16Snorm,
+        StorageFormat::Rg16Unorm => TextureFormat::Rg16Unorm,
+        StorageFormat::Rg16Snorm => TextureFormat::Rg16Snorm,
+        StorageFormat::Rgba16Unorm => TextureFormat::Rgba16Unorm,
+        StorageFormat::Rgba16Snorm => TextureFormat::Rgba16Snorm,
+    }
+}
+
+fn wgsl_type_name(module: &Module, ty: naga::Handle<naga::Type>) -> String {
+    let ty = &module.types[ty];
+    if let Some(name) = &ty.name {
+        return name.clone();
+    }
+
+    let scalar = |kind: ScalarKind, width: u8| match kind {
+        ScalarKind::Sint => format!("i{}", width * 8),
+        ScalarKind::Uint => format!("u{}", width * 8),
+        ScalarKind::Float => format!("f{}", width * 8),
+        ScalarKind::Bool => "bool".to_string(),
+    };
+    match ty.inner {
+        TypeInner::Scalar { kind, width } => scalar(kind, width),
+        TypeInner::Vector { size, kind, width } => {
+            format!("vec{}<{}>", size as u8, scalar(kind, width))
+        }
+        TypeInner::Matrix {
+            columns,
+            rows,
+            width,
+        } => format!(
+            "mat{}x{}<{}>",
+            columns as u8,
+            rows as u8,
+            scalar(ScalarKind::Float, width)
+        ),
+        TypeInner::Array { base, .. } => format!("array<{}>", wgsl_type_name(module, base)),
+        _ => "an unsupported type".to_string(),
+    }
+}
+
+// `alloc::vec::Vec<candela::scene::spheres::sphere::Sphere>` becomes `Vec<Sphere>`
+fn short_type_name(type_name: &str) -> String {
+    let mut short = String::new();
+    let mut segment = String::new();
+    for character in type_name.chars() {
+        if character.is_alphanumeric() || character == '_' {
+            segment.push(character);
+        } else if character == ':' {
+            segment.clear();
+        } else {
+            short.push_str(&segment);
+            short.push(character);
+            segment.clear();
+        }
+    }
+    short + &segment
+}
@@ -1,238 +1,115 @@
 use bevy::{
     prelude::*,
     render::{
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
-        render_graph::{self, RenderGraph},
-        render_resource::*,
-        renderer::{RenderContext, RenderDevice},
-        Render, RenderApp, RenderSet,
+        extract_resource::ExtractResourcePlugin, render_resource::*, Render, RenderApp, RenderSet,
     },
 };
-use std::borrow::Cow;
 
-use crate::WindowSize;
+use crate::compute_shader::compute_pass::{
+    report_compute_pass_status, retry_failed_compute_passes, specialize_compute_passes,
+    ComputePassPipelines, ComputePassStatus, ComputeShaderDefs, DisabledComputePasses,
+};
 
 use super::buffers_interface::*;
 use super::buffers_update::*;
 
 const SIZE: (u32, u32) = (1280, 720);
-const WORKGROUP_SIZE: u32 = 8;
+
+// The compute texture the sprite shows, the present pass writes it
+pub const DISPLAY_TEXTURE: &str = "texture";
 
 pub struct ComputeRenderStartPlugin;
 
-pub fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
-    let mut image_texture = Image::new_fill(
-        Extent3d {
-            width: SIZE.0,
-            height: SIZE.1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        &[0, 0, 0, 255],
-        TextureFormat::Rgba8Unorm,
+pub fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut compute_textures: ResMut<ComputeTextures>,
+) {
+    allocate_compute_textures(
+        &mut compute_textures,
+        &mut images,
+        UVec2::new(SIZE.0, SIZE.1),
     );
-    image_texture.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
-    let image_handle = images.add(image_texture);
 
     commands.spawn(SpriteBundle {
         sprite: Sprite {
             custom_size: Some(Vec2::new(SIZE.0 as f32, SIZE.1 as f32)),
             ..default()
         },
-        texture: image_handle.clone(),
+        texture: compute_textures
+            .image(DISPLAY_TEXTURE)
+            .cloned()
+            .unwrap_or_default(),
         ..default()
     });
     commands.spawn(Camera2dBundle::default());
-    commands.insert_resource(ComputeImage(image_handle));
 }
 
-impl Plugin for ComputeRenderStartPlugin {
-    fn build(&self, app: &mut App) {
-        // Extract the raytracer image resource from the main world into the render world
-        // for operation on by the compute shader and display on the sprite.
-        app.add_plugins(ExtractResourcePlugin::<ComputeImage>::default());
-        let render_app = app.sub_app_mut(RenderApp);
-        render_app.add_systems(Render, prepare_bind_group.in_set(RenderSet::Prepare));
-
-        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
-        render_graph.add_node("raytracer", ComputeNode::default());
-        render_graph.add_node_edge("raytracer", bevy::render::main_graph::node::CAMERA_DRIVER);
-    }
-
-    fn finish(&self, app: &mut App) {
-        let render_app = app.sub_app_mut(RenderApp);
-        render_app.init_resource::<ComputePipeline>();
+// Replaces every compute texture with a cleared one of `size`
+pub fn allocate_compute_textures(
+    compute_textures: &mut ComputeTextures,
+    images: &mut Assets<Image>,
+    size: UVec2,
+) {
+    for texture in compute_textures.0.iter_mut() {
+        texture.image = images.add(compute_texture_image(size, texture.format));
     }
 }
 
-#[derive(Resource, Clone, Deref, ExtractResource)]
-pub struct ComputeImage(pub Handle<Image>);
-
-#[derive(Resource)]
-pub struct ComputePipeline {
-    pub texture_bind_group_layout: BindGroupLayout,
-    pub init_pipeline: CachedComputePipelineId,
-    pub update_pipeline: CachedComputePipelineId,
-}
-
-impl FromWorld for ComputePipeline {
-    fn from_world(world: &mut World) -> Self {
-        let compute_buffers = world.resource_mut::<ComputeBuffers>().clone().0;
-
-        let texture_bind_group_layout_entry = BindGroupLayoutEntry {
-            binding: 0,
-            visibility: ShaderStages::COMPUTE,
-            ty: BindingType::StorageTexture {
-                access: StorageTextureAccess::ReadWrite,
-                format: TextureFormat::Rgba8Unorm,
-                view_dimension: TextureViewDimension::D2,
-            },
-            count: None,
-        };
-
-        let mut bind_group_layout_entries = vec![texture_bind_group_layout_entry];
-        for buffer in compute_buffers.clone() {
-            let bind_group_layout_entry = BindGroupLayoutEntry {
-                binding: buffer.binding,
-                visibility: ShaderStages::COMPUTE,
-                ty: BindingType::Buffer {
-                    ty: BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            };
-            bind_group_layout_entries.push(bind_group_layout_entry);
-        }
-
-        //create the layout that will be used for bind group later with the texture and buffer bindings
-        let texture_bind_group_layout =
-            world
-                .resource::<RenderDevice>()
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: bind_group_layout_entries.as_slice(),
-                });
-
-        let shader_path = world.resource::<ShaderPath>().0;
-
-        let shader = world.resource::<AssetServer>().load(shader_path);
-        let pipeline_cache = world.resource::<PipelineCache>();
-        //this is to call the init function in shader
-        let init_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![texture_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("init"),
-        });
-        //this is to call update in shader
-        let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: None,
-            layout: vec![texture_bind_group_layout.clone()],
-            push_constant_ranges: Vec::new(),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-        });
-
-        ComputePipeline {
-            texture_bind_group_layout,
-            init_pipeline,
-            update_pipeline,
-        }
-    }
-}
-
-pub enum ComputeState {
-    Loading,
-    Init,
-    Update,
-}
-
-pub struct ComputeNode {
-    pub state: ComputeState,
-}
-
-impl Default for ComputeNode {
-    fn default() -> Self {
-        Self {
-            state: ComputeState::Loading,
-        }
-    }
+fn compute_texture_image(size: UVec2, format: TextureFormat) -> Image {
+    let pixel_size = format.block_size(None).unwrap_or(4) as usize;
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &vec![0; pixel_size],
+        format,
+    );
+    // COPY_SRC so the offline renderer can read the result back
+    image.texture_descriptor.usage = TextureUsages::COPY_DST
+        | TextureUsages::COPY_SRC
+        | TextureUsages::STORAGE_BINDING
+        | TextureUsages::TEXTURE_BINDING;
+    image
 }
 
-impl render_graph::Node for ComputeNode {
-    fn update(&mut self, world: &mut World) {
-        let pipeline = world.resource::<ComputePipeline>();
-        let pipeline_cache = world.resource::<PipelineCache>();
-
-        // if the corresponding pipeline has loaded, transition to the next stage
-        match self.state {
-            //pipeline is being created, can't call the shader yet
-            ComputeState::Loading => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.init_pipeline)
-                {
-                    //init pipeline is ready, call init next update
-                    self.state = ComputeState::Init;
-                }
-            }
-            ComputeState::Init => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.update_pipeline)
-                {
-                    //update pipeline is ready, call update next update
-                    self.state = ComputeState::Update;
-                }
-            }
-            ComputeState::Update => {}
-        }
+impl Plugin for ComputeRenderStartPlugin {
+    fn build(&self, app: &mut App) {
+        // Extract the compute textures from the main world into the render world for operation on
+        // by the compute passes and display on the sprite.
+        app.init_resource::<ComputeTextures>()
+            .init_resource::<DisabledComputePasses>()
+            .init_resource::<ComputePassStatus>()
+            .init_resource::<ComputeShaderDefs>()
+            .add_plugins((
+                ExtractResourcePlugin::<ComputeTextures>::default(),
+                ExtractResourcePlugin::<DisabledComputePasses>::default(),
+                ExtractResourcePlugin::<ComputeShaderDefs>::default(),
+            ));
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<ComputeTextures>()
+            .init_resource::<GpuComputeBuffers>();
+        render_app
+            .add_systems(
+                ExtractSchedule,
+                (retry_failed_compute_passes, report_compute_pass_status).chain(),
+            )
+            .add_systems(
+                Render,
+                (specialize_compute_passes, prepare_bind_group).in_set(RenderSet::Prepare),
+            );
     }
 
-    fn run(
-        &self,
-        _graph: &mut render_graph::RenderGraphContext,
-        render_context: &mut RenderContext,
-        world: &World,
-    ) -> Result<(), render_graph::NodeRunError> {
-        //get our saved resource with pipeline and bind group
-        let texture_bind_group = &world.resource::<ComputeBindGroup>().0;
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<ComputePipeline>();
-
-        let mut pass = render_context
-            .command_encoder()
-            .begin_compute_pass(&ComputePassDescriptor::default());
-
-        //set bind group created earlier
-        pass.set_bind_group(0, texture_bind_group, &[]);
-
-        let resolution = world.resource::<WindowSize>();
-
-        // select the pipeline based on the current state
-        match self.state {
-            ComputeState::Loading => {}
-            ComputeState::Init => {
-                //call init until update is ready
-                let init_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.init_pipeline)
-                    .unwrap();
-                pass.set_pipeline(init_pipeline);
-                pass.dispatch_workgroups(resolution.0.x as u32 / WORKGROUP_SIZE, resolution.0.y as u32 / WORKGROUP_SIZE, 1);
-            }
-            ComputeState::Update => {
-                //run update fn
-                let update_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.update_pipeline)
-                    .unwrap();
-                pass.set_pipeline(update_pipeline);
-                pass.dispatch_workgroups(resolution.0.x as u32 / WORKGROUP_SIZE, resolution.0.y as u32 / WORKGROUP_SIZE, 1);
-            }
-        }
-
-        Ok(())
+    fn finish(&self, app: &mut App) {
+        // The first pipelines are compiled with the defs of the settings the app starts with
+        let shader_defs = app.world.resource::<ComputeShaderDefs>().clone();
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(shader_defs)
+            .init_resource::<ComputePassPipelines>();
     }
 }
@@ -1,88 +1,366 @@
+use std::{collections::HashMap, ops::Range};
+
 use bevy::{
     prelude::*,
-    render::{render_asset::RenderAssets, render_resource::*, renderer::RenderDevice},
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{encase::internal::WriteInto, *},
+        renderer::{RenderDevice, RenderQueue},
+    },
 };
-use bytemuck::Pod;
 
-use super::buffers_interface::*;
-use super::buffers_setup::*;
+use crate::compute_shader::compute_pass::{ComputePassPipelines, ComputePasses};
+
+use super::{buffers_interface::*, buffers_layout::ShaderBinding};
+
+// Smallest allocation, keeps tiny buffers from being reallocated on every growth
+const MIN_BUFFER_CAPACITY: u64 = 256;
+
+// GPU side of one `ComputeBuffer`, kept alive across frames and only reallocated when it grows
+struct GpuComputeBuffer {
+    buffer: Buffer,
+    capacity: u64,
+    // Bytes written by the last upload, the rest of the buffer is zeroed
+    len: u64,
+}
+
+#[derive(Resource, Default)]
+pub struct GpuComputeBuffers {
+    buffers: HashMap<&'static str, GpuComputeBuffer>,
+    // Views the current bind groups were built with, the images are recreated on resize
+    texture_views: Vec<TextureViewId>,
+}
 
-// Run when the buffers get updated
+// Uploads the dirty buffers and rebuilds the bind groups only when a buffer or texture changed
+#[allow(clippy::too_many_arguments)]
 pub fn prepare_bind_group(
-    mut commands: Commands,
-    pipeline: Res<super::buffers_setup::ComputePipeline>,
     gpu_images: Res<RenderAssets<Image>>,
-    raytracer_image: Res<ComputeImage>,
+    compute_textures: Res<ComputeTextures>,
     render_device: Res<RenderDevice>,
-    compute_buffers: Res<ComputeBuffers>,
+    render_queue: Res<RenderQueue>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+    mut gpu_buffers: ResMut<GpuComputeBuffers>,
+    mut pass_pipelines: ResMut<ComputePassPipelines>,
+    passes: Res<ComputePasses>,
+    asset_server: Res<AssetServer>,
 ) {
-    let view = gpu_images.get(&raytracer_image.0).unwrap();
-    let texture_bind_group_entry = BindGroupEntry {
-        binding: 0,
-        resource: BindingResource::TextureView(&view.texture_view),
+    // Not until every texture was uploaded
+    let Some(textures) = compute_textures
+        .0
+        .iter()
+        .map(|texture| {
+            let gpu_image = gpu_images.get(&texture.image)?;
+            Some((texture.name, &gpu_image.texture_view))
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
     };
-    let mut bind_group_entries = vec![texture_bind_group_entry];
-
-    // Compute buffers setup
-    // -------------------
-    let mut data_buffers = vec![];
-    for buffer in compute_buffers.0.iter().cloned() {
-        let buffer_data = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: None,
-            contents: &buffer.bytes,
-            usage: BufferUsages::STORAGE,
-        });
-        data_buffers.push(buffer_data);
+
+    // Compute buffers upload
+    // ----------------------
+    let mut reallocated = false;
+    for buffer in compute_buffers.0.iter_mut() {
+        if let Some(dirty) = buffer.dirty.take() {
+            reallocated |= gpu_buffers.upload(buffer, dirty, &render_device, &render_queue);
+        }
     }
 
-    for i in 0..data_buffers.len() {
-        let bind_group_entry = BindGroupEntry {
-            binding: compute_buffers.0[i].binding,
-            resource: data_buffers[i].as_entire_binding(),
-        };
-        bind_group_entries.push(bind_group_entry);
+    let texture_views: Vec<TextureViewId> = textures.iter().map(|(_, view)| view.id()).collect();
+    // Passes set up again after a shader reload have no bind group yet
+    let missing = pass_pipelines
+        .pipelines
+        .iter()
+        .any(|pass| pass.bind_group.is_none());
+    if !reallocated && !missing && gpu_buffers.texture_views == texture_views {
+        return;
     }
+    gpu_buffers.texture_views = texture_views;
 
     // Final bind group setup
     // ----------------------
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: None,
-        layout: &pipeline.texture_bind_group_layout,
-        entries: bind_group_entries.as_slice(),
-    });
+    let mut unbound = Vec::new();
+    for pass in pass_pipelines.pipelines.iter_mut() {
+        match gpu_buffers.bind_group_entries(&pass.bindings, &textures) {
+            Ok(entries) => {
+                pass.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some(pass.name),
+                    layout: &pass.bind_group_layout,
+                    entries: &entries,
+                }));
+            }
+            Err(error) => unbound.push((pass.name, error)),
+        }
+    }
+    // Reported like a shader that failed to reflect, until the shader is reloaded
+    for (name, error) in unbound {
+        if let Some(pass) = passes.0.iter().find(|pass| pass.name == name) {
+            let error = format!("{}: pass {name} {error}", pass.shader);
+            pass_pipelines.fail(&asset_server, pass, error);
+        }
+    }
+}
 
-    commands.insert_resource(ComputeBindGroup(bind_group));
+impl GpuComputeBuffers {
+    // Binds whatever `bindings` declares by name, the compute textures and otherwise the uploaded
+    // buffers. Fails for a binding that has neither.
+    fn bind_group_entries<'a>(
+        &'a self,
+        bindings: &[ShaderBinding],
+        textures: &[(&'static str, &'a TextureView)],
+    ) -> Result<Vec<BindGroupEntry<'a>>, String> {
+        bindings
+            .iter()
+            .map(|binding| {
+                let texture = textures.iter().find(|(name, _)| *name == binding.name);
+                let resource = match texture {
+                    Some((_, view)) => BindingResource::TextureView(view),
+                    None => self
+                        .buffers
+                        .get(binding.name.as_str())
+                        .ok_or_else(|| {
+                            format!(
+                                "binds {} at {}, which has no compute buffer",
+                                binding.name, binding.entry.binding
+                            )
+                        })?
+                        .buffer
+                        .as_entire_binding(),
+                };
+                Ok(BindGroupEntry {
+                    binding: binding.entry.binding,
+                    resource,
+                })
+            })
+            .collect()
+    }
+
+    // Writes the `dirty` bytes of `buffer` to its GPU buffer, all of them when a new one has to be
+    // allocated, which is when it returns true
+    fn upload(
+        &mut self,
+        buffer: &ComputeBuffer,
+        dirty: Range<u64>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> bool {
+        let len = buffer.bytes.len() as u64;
+
+        if let Some(gpu_buffer) = self.buffers.get_mut(buffer.name) {
+            if len <= gpu_buffer.capacity {
+                let bytes = &buffer.bytes[dirty.start as usize..dirty.end as usize];
+                render_queue.write_buffer(&gpu_buffer.buffer, dirty.start, bytes);
+                // Runtime sized arrays see the whole buffer, zero what the last upload left
+                // behind so stale elements read as empty (lights with zero intensity)
+                if len < gpu_buffer.len {
+                    let stale = vec![0; (gpu_buffer.len - len) as usize];
+                    render_queue.write_buffer(&gpu_buffer.buffer, len, &stale);
+                }
+                gpu_buffer.len = len;
+                return false;
+            }
+        }
+
+        // Grow geometrically so arrays that keep growing are not reallocated every frame
+        let previous_capacity = self
+            .buffers
+            .get(buffer.name)
+            .map_or(0, |gpu_buffer| gpu_buffer.capacity);
+        let capacity = len
+            .max(previous_capacity * 2)
+            .max(MIN_BUFFER_CAPACITY)
+            .next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT);
+        let gpu_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some(buffer.name),
+            size: capacity,
+            usage: buffer.usage | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        render_queue.write_buffer(&gpu_buffer, 0, &buffer.bytes);
+
+        self.buffers.insert(
+            buffer.name,
+            GpuComputeBuffer {
+                buffer: gpu_buffer,
+                capacity,
+                len,
+            },
+        );
+        true
+    }
+}
+
+impl<T> TypedComputeBuffer<T>
+where
+    T: ShaderType + WriteInto,
+{
+    pub fn layout(&self) -> BufferLayout {
+        BufferLayout {
+            type_name: std::any::type_name::<T>(),
+            size: T::min_size().get(),
+            alignment: T::METADATA.alignment().get(),
+        }
+    }
+
+    // Serializes `value` with its WGSL storage layout, padding included
+    pub fn to_bytes(&self, value: &T) -> Vec<u8> {
+        let mut buffer = encase::StorageBuffer::new(Vec::new());
+        buffer
+            .write(value)
+            .expect("writing into a growable buffer can not fail");
+        let mut bytes = buffer.into_inner();
+        // Zero sized bindings are invalid, an empty array uploads a single zeroed element instead
+        bytes.resize(bytes.len().max(T::min_size().get() as usize), 0);
+        bytes
+    }
 }
 
 impl ComputeBuffer {
-    pub fn new<T>(binding: u32, vector: Vec<T>) -> Self
+    pub fn new<T>(buffer: TypedComputeBuffer<T>, usage: BufferUsages, value: T) -> Self
     where
-        T: Pod,
+        T: ShaderType + WriteInto,
     {
+        let bytes = buffer.to_bytes(&value);
         ComputeBuffer {
-            binding,
-            bytes: bytemuck::cast_slice(&vector).to_vec(),
+            name: buffer.name,
+            usage,
+            dirty: Some(0..bytes.len() as u64),
+            bytes,
+            layout: buffer.layout(),
         }
     }
 }
 
 impl ComputeBuffers {
-    pub fn new(value: Vec<ComputeBuffer>, world: &mut World) -> Self {
+    pub fn new(value: Vec<ComputeBuffer>) -> Self {
         ComputeBuffers(value)
     }
 
-    pub fn set_value_at<T>(&mut self, binding: u32, new_value: Vec<T>, commands: &mut Commands)
+    // Panics when `buffer` was registered with another type, the shader would read the bytes with
+    // the wrong layout
+    pub fn set_value_at<T>(&mut self, buffer: TypedComputeBuffer<T>, new_value: T)
     where
-        T: Pod,
+        T: ShaderType + WriteInto,
     {
+        let registered = self
+            .0
+            .iter()
+            .find(|registered| registered.name == buffer.name);
+        if let Some(registered) = registered {
+            assert!(
+                registered.layout == buffer.layout(),
+                "compute buffer {} holds {}, can not set it to {}",
+                registered.name,
+                registered.layout.type_name,
+                std::any::type_name::<T>()
+            );
+        }
+        self.set_bytes_at(buffer.name, buffer.to_bytes(&new_value));
+    }
+
+    // Only the bytes that differ are marked dirty, together with what is still waiting to be
+    // uploaded. Many systems set their buffers every frame and large ones like the BVH often
+    // change in a few places only.
+    pub fn set_bytes_at(&mut self, name: &str, bytes: Vec<u8>) {
         for buffer in self.0.iter_mut() {
-            if buffer.binding == binding {
-                *buffer = ComputeBuffer::new(binding, new_value);
+            if buffer.name == name {
+                if let Some(changed) = changed_range(&buffer.bytes, &bytes) {
+                    // What is still pending may reach past the end of a buffer that shrunk since,
+                    // its stale tail is zeroed on upload instead
+                    let len = bytes.len() as u64;
+                    buffer.dirty = Some(match buffer.dirty.take() {
+                        Some(dirty) => {
+                            dirty.start.min(changed.start)..dirty.end.max(changed.end).min(len)
+                        }
+                        None => changed,
+                    });
+                    buffer.bytes = bytes;
+                }
                 break;
             }
         }
     }
 }
 
-#[derive(Resource)]
-pub struct ComputeBindGroup(pub BindGroup);
+// From the first to past the last byte of `new` that differs from `old`, widened to what
+// `write_buffer` can copy. None when nothing changed.
+fn changed_range(old: &[u8], new: &[u8]) -> Option<Range<u64>> {
+    let first = old.iter().zip(new).position(|(old, new)| old != new);
+    let first = match first {
+        Some(first) => first,
+        None if old.len() == new.len() => return None,
+        None => old.len().min(new.len()),
+    };
+    let last = if old.len() == new.len() {
+        old.iter().zip(new).rposition(|(old, new)| old != new)? + 1
+    } else {
+        new.len()
+    };
+    let alignment = wgpu::COPY_BUFFER_ALIGNMENT;
+    let start = first as u64 / alignment * alignment;
+    let end = (last as u64)
+        .next_multiple_of(alignment)
+        .min(new.len() as u64);
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: TypedComputeBuffer<Vec<u32>> = TypedComputeBuffer::new("values");
+
+    fn values(dirty: impl Fn(&mut ComputeBuffers)) -> Option<Range<u64>> {
+        let mut buffers = ComputeBuffers::new(vec![ComputeBuffer::new(
+            VALUES,
+            BufferUsages::STORAGE,
+            vec![0; 8],
+        )]);
+        buffers.0[0].dirty = None;
+        dirty(&mut buffers);
+        buffers.0[0].dirty.clone()
+    }
+
+    #[test]
+    fn unchanged_bytes_are_not_dirty() {
+        assert_eq!(
+            values(|buffers| buffers.set_value_at(VALUES, vec![0; 8])),
+            None
+        );
+    }
+
+    #[test]
+    fn only_changed_words_are_dirty() {
+        let mut changed = vec![0; 8];
+        changed[2] = 0x100;
+        changed[5] = 1;
+        // Element 2 starts at byte 8, its second byte is the one that changed
+        assert_eq!(
+            values(|buffers| buffers.set_value_at(VALUES, changed.clone())),
+            Some(8..24)
+        );
+    }
+
+    #[test]
+    fn pending_ranges_are_merged() {
+        let dirty = values(|buffers| {
+            let mut changed = vec![0; 8];
+            changed[1] = 1;
+            buffers.set_value_at(VALUES, changed.clone());
+            changed[6] = 1;
+            buffers.set_value_at(VALUES, changed);
+        });
+        assert_eq!(dirty, Some(4..28));
+    }
+
+    #[test]
+    fn grown_and_shrunk_buffers_stay_in_bounds() {
+        let grown = values(|buffers| buffers.set_value_at(VALUES, vec![0; 10]));
+        assert_eq!(grown, Some(32..40));
+        let shrunk = values(|buffers| {
+            buffers.set_value_at(VALUES, vec![1; 8]);
+            buffers.set_value_at(VALUES, vec![0; 2]);
+        });
+        assert_eq!(shrunk, Some(0..8));
+    }
+}
@@ -0,0 +1,82 @@
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+
+use crate::{
+    compute_shader::compute_pass::{ComputePass, DisabledComputePasses},
+    ComputeAppExt,
+};
+
+pub const TRACE_PASS: &str = "trace";
+pub const ACCUMULATE_PASS: &str = "accumulate";
+pub const DENOISE_PASS: &str = "denoise";
+pub const TONEMAP_PASS: &str = "tonemap";
+pub const PRESENT_PASS: &str = "present";
+
+// The frame as a chain of compute passes: trace one sample into `radiance`, average it into
+// `accumulation` and copy that to `hdr`, optionally denoise `accumulation` into `hdr`, tone map
+// `hdr` in place and copy it into the texture on screen. Denoise and tone map can be
+// turned off through `DisabledComputePasses`, the passes after them then see the plain average.
+pub struct RenderPassesPlugin;
+impl Plugin for RenderPassesPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_compute_texture("texture", TextureFormat::Rgba8Unorm)
+            .register_compute_texture("accumulation", TextureFormat::Rgba32Float)
+            .register_compute_texture("radiance", TextureFormat::Rgba32Float)
+            .register_compute_texture("hdr", TextureFormat::Rgba32Float);
+
+        app.add_compute_pass(ComputePass {
+            name: TRACE_PASS,
+            shader: "shaders/raytracer.wgsl",
+            entry_point: "trace_pixel",
+            inputs: vec!["accumulation"],
+            outputs: vec!["radiance"],
+        })
+        .add_compute_pass(ComputePass {
+            name: ACCUMULATE_PASS,
+            shader: "shaders/accumulate.wgsl",
+            entry_point: "accumulate",
+            inputs: vec!["radiance"],
+            outputs: vec!["accumulation", "hdr"],
+        })
+        .add_compute_pass(ComputePass {
+            name: DENOISE_PASS,
+            shader: "shaders/denoise.wgsl",
+            entry_point: "denoise",
+            inputs: vec!["accumulation"],
+            outputs: vec!["hdr"],
+        })
+        .add_compute_pass(ComputePass {
+            name: TONEMAP_PASS,
+            shader: "shaders/tonemap.wgsl",
+            entry_point: "tonemap",
+            inputs: vec![],
+            outputs: vec!["hdr"],
+        })
+        .add_compute_pass(ComputePass {
+            name: PRESENT_PASS,
+            shader: "shaders/present.wgsl",
+            entry_point: "present",
+            inputs: vec!["hdr"],
+            outputs: vec!["texture"],
+        });
+
+        // The denoiser blurs fine detail, it is opt in
+        app.world
+            .get_resource_or_insert_with(DisabledComputePasses::default)
+            .0
+            .insert(DENOISE_PASS);
+        app.add_systems(Update, toggle_post_processing);
+    }
+}
+
+// N toggles the denoiser and T the tone mapping
+fn toggle_post_processing(
+    input_keyboard: Res<Input<KeyCode>>,
+    mut disabled: ResMut<DisabledComputePasses>,
+) {
+    if input_keyboard.just_pressed(KeyCode::N) {
+        disabled.toggle(DENOISE_PASS);
+    }
+    if input_keyboard.just_pressed(KeyCode::T) {
+        disabled.toggle(TONEMAP_PASS);
+    }
+}
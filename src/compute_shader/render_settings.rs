@@ -0,0 +1,139 @@
+use bevy::{
+    prelude::*,
+    render::render_resource::{ShaderDefVal, ShaderType},
+};
+
+use crate::{
+    compute_shader::compute_pass::ComputeShaderDefs, ComputeBuffers, RENDER_SETTINGS_BUFFER,
+};
+
+pub struct RenderSettingsPlugin;
+impl Plugin for RenderSettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderSettings>();
+        // Before the pipelines are created, so they are not compiled twice at startup
+        let shader_defs = app.world.resource::<RenderSettings>().shader_defs();
+        app.insert_resource(ComputeShaderDefs(shader_defs));
+        app.add_systems(
+            Update,
+            (toggle_shader_options, update_render_settings_buffers).chain(),
+        );
+    }
+}
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    pub max_bounces: u32,
+    // Accumulation stops once every pixel has this many samples, zero keeps refining forever
+    pub samples_per_pixel: u32,
+    // Paths traced per pixel every frame, more converge faster at a lower frame rate
+    pub samples_per_frame: u32,
+
+    // The options below are compiled into the shaders, changing one recompiles the passes
+    pub debug_view: DebugView,
+    // Samples the punctual lights at every bounce. Paths can never hit them, so without it they
+    // light nothing.
+    pub next_event_estimation: bool,
+    // Samples the camera's thin lens, without it every camera is a pinhole
+    pub depth_of_field: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            max_bounces: 8,
+            samples_per_pixel: 0,
+            samples_per_frame: 1,
+            debug_view: DebugView::Off,
+            next_event_estimation: true,
+            depth_of_field: true,
+        }
+    }
+}
+
+// Shows a property of the first hit instead of the traced path, the value of `DEBUG_VIEW` in the
+// shaders
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    Off = 0,
+    Normals = 1,
+    Albedo = 2,
+}
+
+impl DebugView {
+    fn next(self) -> Self {
+        match self {
+            DebugView::Off => DebugView::Normals,
+            DebugView::Normals => DebugView::Albedo,
+            DebugView::Albedo => DebugView::Off,
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        // Always defined, the shaders compare it with `#if`
+        let mut shader_defs = vec![ShaderDefVal::UInt(
+            "DEBUG_VIEW".to_string(),
+            self.debug_view as u32,
+        )];
+        if self.next_event_estimation {
+            shader_defs.push("NEXT_EVENT_ESTIMATION".into());
+        }
+        if self.depth_of_field {
+            shader_defs.push("DEPTH_OF_FIELD".into());
+        }
+        shader_defs
+    }
+}
+
+shader_types! {
+    // Mirrors `struct RenderSettings` in raytracer.wgsl and accumulate.wgsl, only the options
+    // that are not compiled into the shaders
+    #[derive(Debug, Clone, Copy, Default, ShaderType)]
+    pub struct GpuRenderSettings {
+        pub max_bounces: u32,
+        pub samples_per_pixel: u32,
+        pub samples_per_frame: u32,
+    }
+}
+
+impl From<RenderSettings> for GpuRenderSettings {
+    fn from(settings: RenderSettings) -> Self {
+        GpuRenderSettings {
+            max_bounces: settings.max_bounces,
+            samples_per_pixel: settings.samples_per_pixel,
+            samples_per_frame: settings.samples_per_frame.max(1),
+        }
+    }
+}
+
+// V cycles through the debug views, L toggles the light sampling and F the depth of field
+fn toggle_shader_options(
+    input_keyboard: Res<Input<KeyCode>>,
+    mut settings: ResMut<RenderSettings>,
+) {
+    if input_keyboard.just_pressed(KeyCode::V) {
+        settings.debug_view = settings.debug_view.next();
+    }
+    if input_keyboard.just_pressed(KeyCode::L) {
+        settings.next_event_estimation = !settings.next_event_estimation;
+    }
+    if input_keyboard.just_pressed(KeyCode::F) {
+        settings.depth_of_field = !settings.depth_of_field;
+    }
+}
+
+fn update_render_settings_buffers(
+    settings: Res<RenderSettings>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+    mut shader_defs: ResMut<ComputeShaderDefs>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    compute_buffers.set_value_at(RENDER_SETTINGS_BUFFER, GpuRenderSettings::from(*settings));
+    shader_defs.set_if_neq(ComputeShaderDefs(settings.shader_defs()));
+}
@@ -0,0 +1,130 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use bevy::{asset::FileAssetIo, prelude::*};
+
+use crate::{
+    compute_shader::{
+        compute_pass::{ComputePasses, ComputeShaderDefs},
+        lib::buffers_layout::{load_shader_module, shader_bindings, ShaderBinding},
+    },
+    ErrorOverlay,
+};
+
+// How often the modification times of the compute shaders are checked
+const POLL_INTERVAL_SECONDS: f32 = 0.5;
+
+pub const SHADER_BINDINGS_ERROR_SOURCE: &str = "shader bindings";
+
+pub struct ShaderWatcherPlugin;
+impl Plugin for ShaderWatcherPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ShaderWatcher {
+            shaders: HashMap::new(),
+            timer: Timer::from_seconds(POLL_INTERVAL_SECONDS, TimerMode::Repeating),
+        });
+        app.add_systems(Update, reload_changed_shaders);
+    }
+}
+
+struct WatchedShader {
+    // Modification time of the file when it was last looked at
+    modified: Option<SystemTime>,
+    // Bind group the pass pipelines were created with at startup, None if the shader did not parse
+    // then and its passes wait for a version that does
+    layout: Option<Vec<ShaderBinding>>,
+    // The file declares other bindings than `layout`, so it was not reloaded
+    rejected: bool,
+}
+
+#[derive(Resource)]
+pub struct ShaderWatcher {
+    shaders: HashMap<&'static str, WatchedShader>,
+    timer: Timer,
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(FileAssetIo::get_base_path().join("assets").join(path))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Polls the shaders of the compute passes and reloads the changed ones through the asset server,
+// the pipeline cache then recompiles the pipelines that use them. Their bind group layouts can not
+// change while the app runs, a shader that declares other bindings is left at its last version.
+fn reload_changed_shaders(
+    time: Res<Time>,
+    passes: Option<Res<ComputePasses>>,
+    asset_server: Res<AssetServer>,
+    shader_defs: Res<ComputeShaderDefs>,
+    mut watcher: ResMut<ShaderWatcher>,
+    mut overlay: ResMut<ErrorOverlay>,
+) {
+    let Some(passes) = passes else {
+        return;
+    };
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let mut changed = false;
+    for pass in passes.0.iter() {
+        let modified = modified_time(pass.shader);
+        let Some(watched) = watcher.shaders.get_mut(pass.shader) else {
+            // The pipelines were created from this version
+            let layout = load_shader_module(&asset_server, pass.shader, &shader_defs.0)
+                .and_then(|module| shader_bindings(&module))
+                .ok();
+            watcher.shaders.insert(
+                pass.shader,
+                WatchedShader {
+                    modified,
+                    layout,
+                    rejected: false,
+                },
+            );
+            continue;
+        };
+
+        // Editors that save by replacing the file can leave it missing for a moment
+        let Some(modified) = modified else {
+            continue;
+        };
+        if watched.modified.replace(modified) == Some(modified) {
+            continue;
+        }
+        changed = true;
+
+        // A shader that does not parse is reloaded anyway, the pipeline cache reports the error
+        let layout = load_shader_module(&asset_server, pass.shader, &shader_defs.0)
+            .and_then(|module| shader_bindings(&module));
+        watched.rejected = match (layout, &watched.layout) {
+            (Ok(layout), Some(startup_layout)) => layout != *startup_layout,
+            (Ok(layout), None) => {
+                watched.layout = Some(layout);
+                false
+            }
+            (Err(_), _) => false,
+        };
+        if watched.rejected {
+            println!("{}: the bindings changed, not reloading it", pass.shader);
+        } else {
+            println!("reloading {}", pass.shader);
+            asset_server.reload_asset(pass.shader);
+        }
+    }
+    if !changed {
+        return;
+    }
+
+    let rejected: Vec<String> = watcher
+        .shaders
+        .iter()
+        .filter(|(_, watched)| watched.rejected)
+        .map(|(path, _)| format!("{path}: the bindings changed, restart to apply the shader"))
+        .collect();
+    if rejected.is_empty() {
+        overlay.clear(SHADER_BINDINGS_ERROR_SOURCE);
+    } else {
+        overlay.set(SHADER_BINDINGS_ERROR_SOURCE, rejected.join("\n"));
+    }
+}
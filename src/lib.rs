@@ -0,0 +1,115 @@
+#![allow(clippy::module_inception)]
+
+// Declares structs deriving `ShaderType`. The derive of bevy 0.11 emits layout assertions that are
+// never called, which newer compilers report as dead code, and an allow on the struct does not
+// reach them. The structs go into a private module that allows it and are re-exported, so the rest
+// of the crate is still checked.
+macro_rules! shader_types {
+    ($($(#[$attribute:meta])* pub struct $name:ident $fields:tt)*) => {
+        #[allow(dead_code)]
+        mod shader_types {
+            use super::*;
+
+            $($(#[$attribute])* pub struct $name $fields)*
+        }
+        pub use shader_types::{$($name),*};
+    };
+}
+
+pub mod compute_shader {
+    pub mod lib {
+        pub mod buffers_interface;
+        pub mod buffers_layout;
+        pub mod buffers_setup;
+        mod buffers_update;
+    }
+    pub mod accumulation;
+    pub mod compute_buffers;
+    pub mod compute_pass;
+    pub mod render_passes;
+    pub mod render_settings;
+    pub mod shader_watcher;
+}
+pub mod camera {
+    pub mod camera_path;
+    pub mod camera_update;
+}
+pub mod window {
+    pub mod overlay;
+    pub mod window;
+    pub mod window_shader;
+}
+pub mod scene {
+    pub mod animation;
+    pub mod motion;
+    pub mod scene;
+    pub mod scene_file;
+    pub mod scene_watcher;
+    pub mod bvh {
+        pub mod bvh;
+    }
+    pub mod environment {
+        pub mod environment;
+        pub mod sky;
+    }
+    pub mod lights {
+        pub mod light;
+    }
+    pub mod loaders {
+        pub mod gltf_loader;
+        pub mod load_error;
+        pub mod obj_loader;
+    }
+    pub mod materials {
+        pub mod material;
+    }
+    pub mod meshes {
+        pub mod mesh;
+    }
+    pub mod spheres {
+        pub mod sphere;
+    }
+}
+pub mod offline {
+    pub mod cpu_renderer;
+    pub mod headless;
+    pub mod image_output;
+    pub mod readback;
+    pub mod sequence;
+}
+
+use bevy::{app::PluginGroupBuilder, prelude::*};
+
+use camera::{camera_path::CameraPathPlugin, camera_update::*};
+use compute_shader::{
+    accumulation::*, compute_buffers::*, lib::buffers_interface::*, render_passes::*,
+    render_settings::*, shader_watcher::ShaderWatcherPlugin,
+};
+use scene::{
+    animation::AnimationPlugin, environment::environment::EnvironmentPlugin, scene::ScenePlugin,
+    scene_watcher::SceneWatcherPlugin,
+};
+use window::{overlay::*, window::*, window_shader::*};
+
+// Everything the raytracer adds on top of bevy's `DefaultPlugins`, shared by the interactive
+// viewer and the offline `candela-render` binary
+pub struct RaytracerPlugins;
+impl PluginGroup for RaytracerPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        PluginGroupBuilder::start::<Self>()
+            .add(ComputeBuffersPlugin)
+            .add(ComputeBuffersUpdatePlugin)
+            .add(RenderPassesPlugin)
+            .add(window::window::WindowPlugin)
+            .add(CameraPlugin)
+            .add(CameraPathPlugin)
+            .add(ScenePlugin)
+            .add(AnimationPlugin)
+            .add(EnvironmentPlugin)
+            .add(AccumulationPlugin)
+            .add(RenderSettingsPlugin)
+            .add(OverlayPlugin)
+            .add(SceneWatcherPlugin)
+            .add(ShaderWatcherPlugin)
+    }
+}
@@ -0,0 +1,1283 @@
+use std::{borrow::Cow, f32::consts::PI, sync::Arc, thread};
+
+use bevy::prelude::*;
+
+use crate::{
+    camera::camera_update::{CameraProjection, SceneCamera},
+    compute_shader::render_settings::{DebugView, RenderSettings},
+    scene::{
+        bvh::bvh::{Bvh, BvhNode, PrimitiveRef, PrimitiveType, MAX_DEPTH},
+        environment::environment::{Environment, EnvironmentMap},
+        lights::light::{Light, LightType},
+        materials::material::Material,
+        meshes::mesh::{MeshBuffers, Triangle},
+        motion::Motion,
+        scene::Scene,
+        spheres::sphere::Sphere,
+    },
+};
+
+// CPU port of raytracer.wgsl for machines without a GPU and as a reference for the shader.
+// Every function below mirrors the shader function of the same name and has to be kept in sync
+// with it, including the random number sequence, so both produce the same samples.
+
+const EPSILON: f32 = 0.0001;
+const FAR_AWAY: f32 = 1e30;
+const BVH_STACK_SIZE: usize = MAX_DEPTH;
+
+const PRIMITIVE_NONE: u32 = 0;
+const PRIMITIVE_SPHERE: u32 = PrimitiveType::Sphere as u32;
+const PRIMITIVE_TRIANGLE: u32 = PrimitiveType::Triangle as u32;
+
+const LIGHT_DIRECTIONAL: u32 = LightType::Directional as u32;
+const LIGHT_SPOT: u32 = LightType::Spot as u32;
+
+#[derive(Debug, Clone, Copy)]
+struct Ray {
+    origin: Vec3,
+    direction: Vec3,
+    time: f32,
+}
+
+#[derive(Clone, Copy)]
+struct HitRecord {
+    t: f32,
+    barycentric: Vec2,
+    position: Vec3,
+    normal: Vec3,
+    front_face: bool,
+    primitive_type: u32,
+    primitive_id: u32,
+    material_id: u32,
+}
+
+// The `rng_state` private variable of the shader
+struct Rng {
+    state: u32,
+}
+
+fn pcg_hash(input: u32) -> u32 {
+    let state = input.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+impl Rng {
+    fn random_float(&mut self) -> f32 {
+        self.state = pcg_hash(self.state);
+        self.state as f32 / 4294967295.0
+    }
+
+    fn random_unit_vector(&mut self) -> Vec3 {
+        let z = self.random_float() * 2.0 - 1.0;
+        let angle = self.random_float() * 2.0 * PI;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Vec3::new(r * angle.cos(), r * angle.sin(), z)
+    }
+
+    fn cosine_weighted_direction(&mut self, normal: Vec3) -> Vec3 {
+        let direction = normal + self.random_unit_vector();
+        if direction.dot(direction) < EPSILON {
+            return normal;
+        }
+        direction.normalize()
+    }
+}
+
+// WGSL builtins that glam does not provide with the same semantics
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Rounds towards negative infinity first, `f32::fract` truncates
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn reflect(incident: Vec3, normal: Vec3) -> Vec3 {
+    incident - 2.0 * normal.dot(incident) * normal
+}
+
+fn refract(incident: Vec3, normal: Vec3, eta: f32) -> Vec3 {
+    let cos_incident = normal.dot(incident);
+    let k = 1.0 - eta * eta * (1.0 - cos_incident * cos_incident);
+    if k < 0.0 {
+        return Vec3::ZERO;
+    }
+    eta * incident - (eta * cos_incident + k.sqrt()) * normal
+}
+
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+fn schlick_fresnel(cos_theta: f32, f0: f32) -> f32 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powf(5.0)
+}
+
+fn no_hit() -> HitRecord {
+    HitRecord {
+        t: FAR_AWAY,
+        barycentric: Vec2::ZERO,
+        position: Vec3::ZERO,
+        normal: Vec3::ZERO,
+        front_face: false,
+        primitive_type: PRIMITIVE_NONE,
+        primitive_id: 0,
+        material_id: 0,
+    }
+}
+
+// The shader's `rotate` and `slerp` are glam's, `motion_at` is `Motion::transform_at`
+fn ray_to_rest(ray: Ray, motion: &Motion) -> Ray {
+    let transform = motion.transform_at(ray.time);
+    let inverse_rotation = transform.quat().conjugate();
+    Ray {
+        origin: motion.pivot
+            + inverse_rotation * (ray.origin - motion.pivot - transform.translation)
+                / transform.scale,
+        direction: inverse_rotation * ray.direction / transform.scale,
+        time: ray.time,
+    }
+}
+
+fn normal_from_rest(normal: Vec3, motion: &Motion, time: f32) -> Vec3 {
+    let transform = motion.transform_at(time);
+    (transform.quat() * (normal / transform.scale)).normalize()
+}
+
+fn sphere_intersection(world_ray: Ray, sphere: &Sphere, t_min: f32, t_max: f32) -> f32 {
+    let ray = ray_to_rest(world_ray, &sphere.motion);
+    let oc = ray.origin - sphere.position;
+    let a = ray.direction.dot(ray.direction);
+    let b = 2.0 * oc.dot(ray.direction);
+    let c = oc.dot(oc) - sphere.radius * sphere.radius;
+
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return -1.0;
+    }
+
+    let disc = disc.sqrt();
+    let sol1 = (-b - disc) / (2.0 * a);
+    let sol2 = (-b + disc) / (2.0 * a);
+
+    if sol1 > t_min && sol1 < t_max {
+        return sol1;
+    }
+    if sol2 > t_min && sol2 < t_max {
+        return sol2;
+    }
+    -1.0
+}
+
+fn aabb_intersection(
+    ray: Ray,
+    inverse_direction: Vec3,
+    aabb_min: Vec3,
+    aabb_max: Vec3,
+    t_max: f32,
+) -> f32 {
+    // An inverted box, the root of an empty scene, would span all of space in the slab test
+    if aabb_min.cmpgt(aabb_max).any() {
+        return FAR_AWAY;
+    }
+    let t0 = (aabb_min - ray.origin) * inverse_direction;
+    let t1 = (aabb_max - ray.origin) * inverse_direction;
+    let t_near = t0.min(t1).max_element();
+    let t_far = t0.max(t1).min_element();
+
+    if t_near > t_far || t_far < 0.0 || t_near > t_max {
+        return FAR_AWAY;
+    }
+    t_near
+}
+
+// `search_marginal_cdf` and `search_conditional_cdf`
+fn search_cdf(cdf: &[f32], value: f32) -> u32 {
+    let mut low = 0;
+    let mut high = cdf.len() - 1;
+    while low < high {
+        let middle = (low + high) / 2;
+        if cdf[middle] <= value {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low as u32
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let squared = pdf * pdf;
+    let total = squared + other_pdf * other_pdf;
+    if total > 0.0 {
+        squared / total
+    } else {
+        0.0
+    }
+}
+
+// Everything the shader reads from its storage buffers, owned by the renderer or borrowed from the
+// app's scene for a single ray
+struct SceneData<'a> {
+    spheres: Cow<'a, [Sphere]>,
+    materials: Cow<'a, [Material]>,
+    lights: Cow<'a, [Light]>,
+    environment: Environment,
+    environment_map: Option<Arc<EnvironmentMap>>,
+    bvh_nodes: Cow<'a, [BvhNode]>,
+    bvh_primitives: Cow<'a, [PrimitiveRef]>,
+    vertices: Cow<'a, [Vec4]>,
+    normals: Cow<'a, [Vec4]>,
+    triangles: Cow<'a, [Triangle]>,
+    mesh_motions: Cow<'a, [Motion]>,
+    max_bounces: u32,
+    // The `NEXT_EVENT_ESTIMATION` shader def
+    next_event_estimation: bool,
+}
+
+impl SceneData<'static> {
+    fn new(scene: &Scene, settings: &RenderSettings) -> Self {
+        let bvh = Bvh::build(&scene.primitive_bounds());
+        let mesh_buffers = MeshBuffers::new(&scene.meshes);
+
+        SceneData {
+            spheres: Cow::Owned(scene.spheres.clone()),
+            materials: Cow::Owned(scene.materials.clone()),
+            lights: Cow::Owned(scene.lights.clone()),
+            environment: scene.environment,
+            environment_map: scene.environment_map.clone(),
+            bvh_nodes: Cow::Owned(bvh.nodes),
+            bvh_primitives: Cow::Owned(bvh.primitives),
+            vertices: Cow::Owned(mesh_buffers.vertices),
+            normals: Cow::Owned(mesh_buffers.normals),
+            triangles: Cow::Owned(mesh_buffers.triangles),
+            mesh_motions: Cow::Owned(mesh_buffers.motions),
+            max_bounces: settings.max_bounces,
+            next_event_estimation: settings.next_event_estimation,
+        }
+    }
+}
+
+impl<'a> SceneData<'a> {
+    // The buffers `update_scene_buffers` uploaded last, which `bvh` and `mesh_buffers` are kept for
+    fn borrowed(scene: &'a Scene, bvh: &'a Bvh, mesh_buffers: &'a MeshBuffers) -> Self {
+        let settings = RenderSettings::default();
+        SceneData {
+            spheres: Cow::Borrowed(&scene.spheres),
+            materials: Cow::Borrowed(&scene.materials),
+            lights: Cow::Borrowed(&scene.lights),
+            environment: scene.environment,
+            environment_map: scene.environment_map.clone(),
+            bvh_nodes: Cow::Borrowed(&bvh.nodes),
+            bvh_primitives: Cow::Borrowed(&bvh.primitives),
+            vertices: Cow::Borrowed(&mesh_buffers.vertices),
+            normals: Cow::Borrowed(&mesh_buffers.normals),
+            triangles: Cow::Borrowed(&mesh_buffers.triangles),
+            mesh_motions: Cow::Borrowed(&mesh_buffers.motions),
+            max_bounces: settings.max_bounces,
+            next_event_estimation: settings.next_event_estimation,
+        }
+    }
+
+    fn get_environment_light(&self, ray: Ray) -> Vec3 {
+        let environment = &self.environment;
+        if let Some(map) = &self.environment_map {
+            return self.environment_map_radiance(map, ray.direction) * environment.intensity;
+        }
+        if environment.sun_cos_radius > 0.0 {
+            let sky_t = smoothstep(-0.001, 0.0, ray.direction.y);
+            let sky = self
+                .physical_sky_ground()
+                .lerp(self.physical_sky_radiance(ray.direction), sky_t);
+            return sky * environment.intensity;
+        }
+
+        let sky_gradient_t = smoothstep(0.0, 1.0, ray.direction.y);
+        let sky_gradient = environment
+            .sky_horizon
+            .lerp(environment.sky_zenith, sky_gradient_t);
+
+        let ground_to_sky_t = smoothstep(-0.001, 0.0, -ray.direction.y);
+        environment.ground.lerp(sky_gradient, ground_to_sky_t) * environment.intensity
+    }
+
+    fn perez(&self, cos_theta: f32, gamma: f32, cos_gamma: f32) -> Vec3 {
+        let environment = &self.environment;
+        (Vec3::ONE + environment.perez_a * (environment.perez_b / cos_theta.max(0.001)).exp())
+            * (Vec3::ONE
+                + environment.perez_c * (environment.perez_d * gamma).exp()
+                + environment.perez_e * cos_gamma * cos_gamma)
+    }
+
+    fn physical_sky_radiance(&self, direction: Vec3) -> Vec3 {
+        let cos_gamma = direction
+            .dot(self.environment.sun_direction)
+            .clamp(-1.0, 1.0);
+        let yxy =
+            self.environment.sky_zenith_yxy * self.perez(direction.y, cos_gamma.acos(), cos_gamma);
+        let xyz = Vec3::new(
+            yxy.y / yxy.z * yxy.x,
+            yxy.x,
+            (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x,
+        );
+        let rgb = Vec3::new(
+            Vec3::new(3.2406, -1.5372, -0.4986).dot(xyz),
+            Vec3::new(-0.9689, 1.8758, 0.0415).dot(xyz),
+            Vec3::new(0.0557, -0.2040, 1.0570).dot(xyz),
+        );
+        rgb.max(Vec3::ZERO)
+    }
+
+    fn physical_sky_ground(&self) -> Vec3 {
+        let environment = &self.environment;
+        let sun_irradiance =
+            environment.sun_radiance / self.sun_pdf() * environment.sun_direction.y.max(0.0);
+        environment.ground * (sun_irradiance / PI + self.physical_sky_radiance(Vec3::Y))
+    }
+
+    fn sun_light(&self, direction: Vec3) -> Vec3 {
+        let environment = &self.environment;
+        if environment.sun_cos_radius <= 0.0
+            || direction.dot(environment.sun_direction) < environment.sun_cos_radius
+        {
+            return Vec3::ZERO;
+        }
+        environment.sun_radiance * environment.intensity
+    }
+
+    fn sun_pdf(&self) -> f32 {
+        1.0 / (2.0 * PI * (1.0 - self.environment.sun_cos_radius))
+    }
+
+    fn sample_sun(&self, rng: &mut Rng) -> Vec3 {
+        let cos_theta = 1.0 - rng.random_float() * (1.0 - self.environment.sun_cos_radius);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = rng.random_float() * 2.0 * PI;
+
+        let w = self.environment.sun_direction;
+        let helper = if w.x.abs() > 0.9 { Vec3::Z } else { Vec3::X };
+        let u = helper.cross(w).normalize();
+        let v = w.cross(u);
+        ((u * phi.cos() + v * phi.sin()) * sin_theta + w * cos_theta).normalize()
+    }
+
+    fn environment_texel(&self, map: &EnvironmentMap, direction: Vec3) -> UVec2 {
+        let longitude = direction.x.atan2(direction.z) - self.environment.rotation;
+        let latitude = direction.y.clamp(-1.0, 1.0).asin();
+        let uv = Vec2::new(fract(longitude / (2.0 * PI) + 0.5), 0.5 - latitude / PI);
+        let size = UVec2::new(map.width, map.height);
+        (uv * size.as_vec2()).as_uvec2().min(size - 1)
+    }
+
+    fn environment_map_radiance(&self, map: &EnvironmentMap, direction: Vec3) -> Vec3 {
+        let texel = self.environment_texel(map, direction);
+        map.pixels[(texel.y * map.width + texel.x) as usize].truncate()
+    }
+
+    fn environment_texel_pdf(&self, map: &EnvironmentMap, texel: UVec2, cos_latitude: f32) -> f32 {
+        if cos_latitude <= 0.0 {
+            return 0.0;
+        }
+        let row = (texel.y * map.width) as usize;
+        let (x, y) = (texel.x as usize, texel.y as usize);
+        let marginal = map.marginal_cdf[y] - if y > 0 { map.marginal_cdf[y - 1] } else { 0.0 };
+        let conditional = map.conditional_cdf[row + x]
+            - if x > 0 {
+                map.conditional_cdf[row + x - 1]
+            } else {
+                0.0
+            };
+        let texels = map.width as f32 * map.height as f32;
+        marginal * conditional * texels / (2.0 * PI * PI * cos_latitude)
+    }
+
+    fn environment_pdf(&self, map: &EnvironmentMap, direction: Vec3) -> f32 {
+        let cos_latitude = (1.0 - direction.y * direction.y).max(0.0).sqrt();
+        let texel = self.environment_texel(map, direction);
+        self.environment_texel_pdf(map, texel, cos_latitude)
+    }
+
+    // Returns the direction and its density
+    fn sample_environment(&self, rng: &mut Rng, map: &EnvironmentMap) -> (Vec3, f32) {
+        let y = search_cdf(&map.marginal_cdf, rng.random_float());
+        let row = (y * map.width) as usize;
+        let x = search_cdf(
+            &map.conditional_cdf[row..row + map.width as usize],
+            rng.random_float(),
+        );
+        let jitter = Vec2::new(rng.random_float(), rng.random_float());
+        let uv =
+            (UVec2::new(x, y).as_vec2() + jitter) / UVec2::new(map.width, map.height).as_vec2();
+
+        let longitude = (uv.x - 0.5) * 2.0 * PI + self.environment.rotation;
+        let latitude = (0.5 - uv.y) * PI;
+        let direction = Vec3::new(
+            longitude.sin() * latitude.cos(),
+            latitude.sin(),
+            longitude.cos() * latitude.cos(),
+        );
+        let pdf = self.environment_texel_pdf(map, UVec2::new(x, y), latitude.cos());
+        (direction, pdf)
+    }
+
+    fn triangle_intersection(
+        &self,
+        world_ray: Ray,
+        triangle: &Triangle,
+        t_min: f32,
+        t_max: f32,
+    ) -> Vec3 {
+        let ray = ray_to_rest(world_ray, &self.mesh_motions[triangle.mesh_index as usize]);
+        let [i0, i1, i2] = triangle.indices.to_array().map(|index| index as usize);
+        let v0 = self.vertices[i0].truncate();
+        let edge1 = self.vertices[i1].truncate() - v0;
+        let edge2 = self.vertices[i2].truncate() - v0;
+
+        let p = ray.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < 1e-8 {
+            return Vec3::splat(-1.0);
+        }
+
+        let inverse_determinant = 1.0 / determinant;
+        let s = ray.origin - v0;
+        let u = s.dot(p) * inverse_determinant;
+        if !(0.0..=1.0).contains(&u) {
+            return Vec3::splat(-1.0);
+        }
+
+        let q = s.cross(edge1);
+        let v = ray.direction.dot(q) * inverse_determinant;
+        if v < 0.0 || u + v > 1.0 {
+            return Vec3::splat(-1.0);
+        }
+
+        let t = edge2.dot(q) * inverse_determinant;
+        if t <= t_min || t >= t_max {
+            return Vec3::splat(-1.0);
+        }
+        Vec3::new(t, u, v)
+    }
+
+    fn intersect_primitive(&self, ray: Ray, primitive: PrimitiveRef, record: &mut HitRecord) {
+        let mut t = -1.0;
+        let mut barycentric = Vec2::ZERO;
+        let id = primitive.primitive_id as usize;
+        if primitive.primitive_type == PRIMITIVE_SPHERE {
+            t = sphere_intersection(ray, &self.spheres[id], EPSILON, record.t);
+        } else if primitive.primitive_type == PRIMITIVE_TRIANGLE {
+            let hit = self.triangle_intersection(ray, &self.triangles[id], EPSILON, record.t);
+            t = hit.x;
+            barycentric = Vec2::new(hit.y, hit.z);
+        }
+
+        if t > 0.0 {
+            record.t = t;
+            record.barycentric = barycentric;
+            record.primitive_type = primitive.primitive_type;
+            record.primitive_id = primitive.primitive_id;
+        }
+    }
+
+    fn trace(&self, ray: Ray) -> HitRecord {
+        self.trace_range(ray, FAR_AWAY)
+    }
+
+    fn trace_range(&self, ray: Ray, t_max: f32) -> HitRecord {
+        let mut record = no_hit();
+        record.t = t_max;
+        let inverse_direction = 1.0 / ray.direction;
+
+        let mut stack = [0_u32; BVH_STACK_SIZE];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+
+        let root = &self.bvh_nodes[0];
+        if aabb_intersection(
+            ray,
+            inverse_direction,
+            root.aabb_min,
+            root.aabb_max,
+            record.t,
+        ) == FAR_AWAY
+        {
+            return record;
+        }
+
+        loop {
+            let node = &self.bvh_nodes[node_index as usize];
+
+            if node.primitive_count > 0 {
+                for i in 0..node.primitive_count {
+                    let primitive = self.bvh_primitives[(node.left_first + i) as usize];
+                    self.intersect_primitive(ray, primitive, &mut record);
+                }
+
+                if stack_size == 0 {
+                    break;
+                }
+                stack_size -= 1;
+                node_index = stack[stack_size];
+                continue;
+            }
+
+            let mut near_index = node.left_first;
+            let mut far_index = node.left_first + 1;
+            let near_node = &self.bvh_nodes[near_index as usize];
+            let far_node = &self.bvh_nodes[far_index as usize];
+            let mut near_t = aabb_intersection(
+                ray,
+                inverse_direction,
+                near_node.aabb_min,
+                near_node.aabb_max,
+                record.t,
+            );
+            let mut far_t = aabb_intersection(
+                ray,
+                inverse_direction,
+                far_node.aabb_min,
+                far_node.aabb_max,
+                record.t,
+            );
+            if far_t < near_t {
+                std::mem::swap(&mut near_index, &mut far_index);
+                std::mem::swap(&mut near_t, &mut far_t);
+            }
+
+            if near_t == FAR_AWAY {
+                if stack_size == 0 {
+                    break;
+                }
+                stack_size -= 1;
+                node_index = stack[stack_size];
+                continue;
+            }
+
+            node_index = near_index;
+            // `Bvh::build` limits the depth of the tree so this never overflows
+            if far_t != FAR_AWAY {
+                stack[stack_size] = far_index;
+                stack_size += 1;
+            }
+        }
+
+        if record.primitive_type == PRIMITIVE_NONE {
+            return record;
+        }
+
+        record.position = ray.origin + ray.direction * record.t;
+        let mut outward_normal = Vec3::ZERO;
+        let mut shading_normal = Vec3::ZERO;
+        if record.primitive_type == PRIMITIVE_SPHERE {
+            let sphere = &self.spheres[record.primitive_id as usize];
+            let rest_ray = ray_to_rest(ray, &sphere.motion);
+            let rest_normal =
+                (rest_ray.origin + rest_ray.direction * record.t - sphere.position) / sphere.radius;
+            outward_normal = normal_from_rest(rest_normal, &sphere.motion, ray.time);
+            shading_normal = outward_normal;
+            record.material_id = sphere.material_index;
+        } else if record.primitive_type == PRIMITIVE_TRIANGLE {
+            let triangle = &self.triangles[record.primitive_id as usize];
+            let motion = &self.mesh_motions[triangle.mesh_index as usize];
+            let [i0, i1, i2] = triangle.indices.to_array().map(|index| index as usize);
+            let v0 = self.vertices[i0].truncate();
+            let rest_normal =
+                (self.vertices[i1].truncate() - v0).cross(self.vertices[i2].truncate() - v0);
+            outward_normal = normal_from_rest(rest_normal, motion, ray.time);
+
+            let w = Vec3::new(
+                1.0 - record.barycentric.x - record.barycentric.y,
+                record.barycentric.x,
+                record.barycentric.y,
+            );
+            let mut interpolated =
+                (self.normals[i0] * w.x + self.normals[i1] * w.y + self.normals[i2] * w.z)
+                    .truncate();
+            if interpolated.dot(interpolated) > EPSILON {
+                interpolated = normal_from_rest(interpolated, motion, ray.time);
+            }
+            shading_normal = outward_normal;
+            if interpolated.dot(interpolated) > EPSILON {
+                shading_normal =
+                    interpolated.normalize() * sign(interpolated.dot(outward_normal) + EPSILON);
+            }
+            record.material_id = triangle.material_index;
+        }
+
+        record.front_face = ray.direction.dot(outward_normal) < 0.0;
+        record.normal = if record.front_face {
+            shading_normal
+        } else {
+            -shading_normal
+        };
+        record
+    }
+
+    fn scatter(
+        &self,
+        rng: &mut Rng,
+        ray: &mut Ray,
+        throughput: &mut Vec3,
+        hit: &HitRecord,
+        material: &Material,
+    ) -> f32 {
+        let direction = ray.direction;
+        let front_face = hit.front_face;
+        let facing_normal = hit.normal;
+        let diffuse_direction = rng.cosine_weighted_direction(facing_normal);
+        let roughness = material.roughness * material.roughness;
+
+        let next_direction;
+        let mut diffuse_pdf = 0.0;
+        if rng.random_float() < material.transmission {
+            let eta = if front_face {
+                1.0 / material.ior
+            } else {
+                material.ior
+            };
+            let cos_theta = (-direction).dot(facing_normal).min(1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let f0 = ((1.0 - material.ior) / (1.0 + material.ior)).powf(2.0);
+
+            let ideal =
+                if eta * sin_theta > 1.0 || rng.random_float() < schlick_fresnel(cos_theta, f0) {
+                    reflect(direction, facing_normal)
+                } else {
+                    refract(direction, facing_normal, eta)
+                };
+            let rough = (ideal + rng.random_unit_vector() * roughness).normalize();
+            next_direction = if roughness > 0.0 { rough } else { ideal };
+            *throughput *= material.albedo;
+        } else {
+            let cos_theta = (-direction).dot(facing_normal);
+            let fresnel = schlick_fresnel(cos_theta, 0.04);
+            let specular_chance = fresnel + (1.0 - fresnel) * material.metallic;
+
+            if rng.random_float() < specular_chance {
+                let specular_direction = reflect(direction, facing_normal);
+                next_direction = specular_direction
+                    .lerp(diffuse_direction, roughness)
+                    .normalize();
+                *throughput *= Vec3::ONE.lerp(material.albedo, material.metallic);
+            } else {
+                next_direction = diffuse_direction;
+                *throughput *= material.albedo;
+                diffuse_pdf = (1.0 - material.transmission)
+                    * (1.0 - specular_chance)
+                    * next_direction.dot(facing_normal).max(0.0)
+                    / PI;
+            }
+        }
+
+        let offset = if next_direction.dot(facing_normal) > 0.0 {
+            facing_normal
+        } else {
+            -facing_normal
+        };
+        *ray = Ray {
+            origin: hit.position + offset * EPSILON * 10.0,
+            direction: next_direction,
+            time: ray.time,
+        };
+        diffuse_pdf
+    }
+
+    fn direct_light(&self, rng: &mut Rng, ray: Ray, hit: &HitRecord, material: &Material) -> Vec3 {
+        let cos_view = (-ray.direction).dot(hit.normal);
+        let fresnel = schlick_fresnel(cos_view, 0.04);
+        let specular_chance = fresnel + (1.0 - fresnel) * material.metallic;
+        let diffuse_weight = (1.0 - material.transmission) * (1.0 - specular_chance);
+        if diffuse_weight <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let mut irradiance = Vec3::ZERO;
+        for light in self.lights.iter() {
+            if light.intensity <= 0.0 {
+                continue;
+            }
+
+            let mut to_light = -light.direction;
+            let mut distance = FAR_AWAY;
+            let mut falloff = 1.0;
+            if light.light_type != LIGHT_DIRECTIONAL {
+                let offset = light.position - hit.position;
+                distance = offset.length();
+                to_light = offset / distance;
+                falloff = 1.0 / (distance * distance).max(EPSILON);
+
+                if light.range > 0.0 {
+                    let ratio = distance / light.range;
+                    falloff *= (1.0 - ratio.powf(4.0)).clamp(0.0, 1.0).powf(2.0);
+                }
+                if light.light_type == LIGHT_SPOT {
+                    falloff *= smoothstep(
+                        light.outer_cone_cos,
+                        light.inner_cone_cos,
+                        light.direction.dot(-to_light),
+                    );
+                }
+            }
+
+            let cos_theta = hit.normal.dot(to_light);
+            if cos_theta <= 0.0 || falloff <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray {
+                origin: hit.position + hit.normal * EPSILON * 10.0,
+                direction: to_light,
+                time: ray.time,
+            };
+            if self
+                .trace_range(shadow_ray, distance - EPSILON * 20.0)
+                .primitive_type
+                != PRIMITIVE_NONE
+            {
+                continue;
+            }
+
+            irradiance += light.color * light.intensity * falloff * cos_theta;
+        }
+
+        if let Some(map) = &self.environment_map {
+            let (to_light, light_pdf) = self.sample_environment(rng, map);
+            let cos_theta = hit.normal.dot(to_light);
+            if cos_theta > 0.0 && light_pdf > 0.0 {
+                let shadow_ray = Ray {
+                    origin: hit.position + hit.normal * EPSILON * 10.0,
+                    direction: to_light,
+                    time: ray.time,
+                };
+                if self.trace(shadow_ray).primitive_type == PRIMITIVE_NONE {
+                    let weight = power_heuristic(light_pdf, diffuse_weight * cos_theta / PI);
+                    irradiance +=
+                        self.get_environment_light(shadow_ray) * cos_theta * weight / light_pdf;
+                }
+            }
+        }
+
+        if self.environment.sun_cos_radius > 0.0 {
+            let to_light = self.sample_sun(rng);
+            let cos_theta = hit.normal.dot(to_light);
+            if cos_theta > 0.0 {
+                let shadow_ray = Ray {
+                    origin: hit.position + hit.normal * EPSILON * 10.0,
+                    direction: to_light,
+                    time: ray.time,
+                };
+                if self.trace(shadow_ray).primitive_type == PRIMITIVE_NONE {
+                    let light_pdf = self.sun_pdf();
+                    let weight = power_heuristic(light_pdf, diffuse_weight * cos_theta / PI);
+                    irradiance += self.environment.sun_radiance
+                        * self.environment.intensity
+                        * cos_theta
+                        * weight
+                        / light_pdf;
+                }
+            }
+        }
+
+        irradiance * material.albedo / PI * diffuse_weight
+    }
+
+    fn trace_path(&self, rng: &mut Rng, primary_ray: Ray) -> Vec3 {
+        let mut ray = primary_ray;
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut diffuse_pdf = 0.0;
+
+        for bounce in 0..=self.max_bounces {
+            let hit = self.trace(ray);
+            if hit.primitive_type == PRIMITIVE_NONE {
+                let mut weight = 1.0;
+                let mut sun_weight = 1.0;
+                if self.next_event_estimation && diffuse_pdf > 0.0 {
+                    if let Some(map) = &self.environment_map {
+                        weight =
+                            power_heuristic(diffuse_pdf, self.environment_pdf(map, ray.direction));
+                    }
+                    sun_weight = power_heuristic(diffuse_pdf, self.sun_pdf());
+                }
+                radiance += throughput
+                    * (self.get_environment_light(ray) * weight
+                        + self.sun_light(ray.direction) * sun_weight);
+                break;
+            }
+
+            let material = &self.materials[hit.material_id as usize];
+            radiance += throughput * material.emission;
+            if self.next_event_estimation {
+                radiance += throughput * self.direct_light(rng, ray, &hit, material);
+            }
+            diffuse_pdf = self.scatter(rng, &mut ray, &mut throughput, &hit, material);
+
+            if bounce > 2 {
+                let survival = throughput.max_element().clamp(0.05, 1.0);
+                if rng.random_float() > survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+
+        radiance
+    }
+
+    fn debug_view(&self, ray: Ray, view: DebugView) -> Vec3 {
+        let hit = self.trace(ray);
+        if hit.primitive_type == PRIMITIVE_NONE {
+            return Vec3::ZERO;
+        }
+        match view {
+            DebugView::Normals => hit.normal * 0.5 + 0.5,
+            _ => self.materials[hit.material_id as usize].albedo,
+        }
+    }
+}
+
+pub struct CpuRenderer {
+    scene: SceneData<'static>,
+    // With `inverse_view_matrix` up to date
+    camera: SceneCamera,
+    resolution: UVec2,
+    samples_per_pixel: u32,
+    samples_per_frame: u32,
+    debug_view: DebugView,
+    depth_of_field: bool,
+    // Seconds on the animation timeline, only seeds the random numbers. The scene and camera are
+    // expected to be animated to it already.
+    render_time: f32,
+    frame_count: u32,
+    // Same layout as the accumulation texture: linear rgb and the sample count in alpha
+    accumulation: Vec<Vec4>,
+}
+
+impl CpuRenderer {
+    pub fn new(
+        scene: &Scene,
+        camera: &SceneCamera,
+        settings: &RenderSettings,
+        resolution: UVec2,
+    ) -> Self {
+        CpuRenderer {
+            scene: SceneData::new(scene, settings),
+            camera: SceneCamera {
+                inverse_view_matrix: camera.compute_inverse_view_matrix(),
+                ..*camera
+            },
+            resolution,
+            samples_per_pixel: settings.samples_per_pixel,
+            samples_per_frame: settings.samples_per_frame.max(1),
+            debug_view: settings.debug_view,
+            depth_of_field: settings.depth_of_field,
+            render_time: 0.0,
+            frame_count: 0,
+            accumulation: vec![Vec4::ZERO; (resolution.x * resolution.y) as usize],
+        }
+    }
+
+    pub fn with_render_time(mut self, render_time: f32) -> Self {
+        self.render_time = render_time;
+        self
+    }
+
+    pub fn accumulation(&self) -> &[Vec4] {
+        &self.accumulation
+    }
+
+    pub fn resolution(&self) -> UVec2 {
+        self.resolution
+    }
+
+    // One invocation of the `trace_pixel` and `accumulate` entry points for every pixel, rows are
+    // split across threads
+    pub fn render_frame(&mut self) {
+        let width = self.resolution.x as usize;
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        let rows_per_thread = (self.resolution.y as usize).div_ceil(threads).max(1);
+
+        let mut accumulation = std::mem::take(&mut self.accumulation);
+        let renderer = &*self;
+        thread::scope(|scope| {
+            for (chunk, pixels) in accumulation.chunks_mut(rows_per_thread * width).enumerate() {
+                scope.spawn(move || {
+                    for (i, pixel) in pixels.iter_mut().enumerate() {
+                        let index = chunk * rows_per_thread * width + i;
+                        let location = UVec2::new((index % width) as u32, (index / width) as u32);
+                        renderer.update(location, pixel);
+                    }
+                });
+            }
+        });
+        self.accumulation = accumulation;
+        self.frame_count = self.frame_count.saturating_add(1);
+    }
+
+    fn update(&self, location: UVec2, accumulated: &mut Vec4) {
+        let previous = *accumulated;
+        let sample_count = if self.frame_count == 0 {
+            0.0
+        } else {
+            previous.w
+        };
+        if self.samples_per_pixel > 0 && sample_count >= self.samples_per_pixel as f32 {
+            return;
+        }
+
+        let frame_seed = self.frame_count ^ self.render_time.to_bits();
+        let mut rng = Rng {
+            state: pcg_hash(
+                location
+                    .x
+                    .wrapping_add(location.y.wrapping_mul(self.resolution.x))
+                    .wrapping_add(pcg_hash(frame_seed)),
+            ),
+        };
+
+        let mut samples = self.samples_per_frame;
+        if self.samples_per_pixel > 0 {
+            samples = samples.min(self.samples_per_pixel - sample_count as u32);
+        }
+        let color = (0..samples)
+            .map(|_| self.sample_pixel(&mut rng, location))
+            .sum::<Vec3>()
+            / samples as f32;
+
+        let total = sample_count + samples as f32;
+        let average = (previous.truncate() * sample_count + color * samples as f32) / total;
+        *accumulated = average.extend(total);
+    }
+
+    fn sample_pixel(&self, rng: &mut Rng, location: UVec2) -> Vec3 {
+        let frag_coord = location.as_vec2() + Vec2::new(rng.random_float(), rng.random_float());
+        let mut ray = primary_ray(&self.camera, self.resolution.as_vec2(), frag_coord);
+        let camera = &self.camera;
+        ray.time = 0.0;
+        if camera.shutter_close > camera.shutter_open {
+            ray.time = rng.random_float();
+        }
+        if self.depth_of_field && has_thin_lens(&self.camera) {
+            ray = thin_lens_ray(rng, &self.camera, ray);
+        }
+
+        let color = match self.debug_view {
+            DebugView::Off => self.scene.trace_path(rng, ray),
+            view => self.scene.debug_view(ray, view),
+        };
+        if color.is_nan() {
+            Vec3::ZERO
+        } else {
+            color
+        }
+    }
+}
+
+fn primary_ray(camera: &SceneCamera, resolution: Vec2, frag_coord: Vec2) -> Ray {
+    let (origin, direction) = camera.primary_ray(resolution, frag_coord);
+    Ray {
+        origin,
+        direction,
+        time: 0.0,
+    }
+}
+
+// The panoramic projections have no focus plane, their rays leave the camera in every direction
+fn has_thin_lens(camera: &SceneCamera) -> bool {
+    camera.aperture_radius > 0.0
+        && matches!(
+            camera.projection,
+            CameraProjection::Perspective | CameraProjection::Orthographic
+        )
+}
+
+fn sample_aperture(rng: &mut Rng, blade_count: u32) -> Vec2 {
+    if blade_count < 3 {
+        let angle = 2.0 * PI * rng.random_float();
+        let radius = rng.random_float().sqrt();
+        return Vec2::new(angle.cos(), angle.sin()) * radius;
+    }
+
+    let blades = blade_count as f32;
+    let blade = (rng.random_float() * blades).floor();
+    let first = 2.0 * PI * blade / blades;
+    let second = 2.0 * PI * (blade + 1.0) / blades;
+    let mut u = rng.random_float();
+    let mut v = rng.random_float();
+    if u + v > 1.0 {
+        u = 1.0 - u;
+        v = 1.0 - v;
+    }
+    Vec2::new(first.cos(), first.sin()) * u + Vec2::new(second.cos(), second.sin()) * v
+}
+
+fn thin_lens_ray(rng: &mut Rng, camera: &SceneCamera, ray: Ray) -> Ray {
+    let forward = camera.front.normalize();
+    let focus_point =
+        ray.origin + ray.direction * (camera.focal_distance / ray.direction.dot(forward));
+    let aperture = sample_aperture(rng, camera.blade_count) * camera.aperture_radius;
+    let origin = ray.origin
+        + camera.inverse_view_matrix.x_axis.truncate().normalize() * aperture.x
+        + camera.inverse_view_matrix.y_axis.truncate().normalize() * aperture.y;
+    Ray {
+        origin,
+        direction: (focus_point - origin).normalize(),
+        time: ray.time,
+    }
+}
+
+// Distance along the camera's view direction to the surface seen through `frag_coord`, the focal
+// distance that brings it into focus. Casts the same pinhole ray the renderers start from against
+// the BVH and mesh buffers last built for `scene`.
+pub fn focal_distance_at(
+    scene: &Scene,
+    bvh: &Bvh,
+    mesh_buffers: &MeshBuffers,
+    camera: &SceneCamera,
+    resolution: UVec2,
+    frag_coord: Vec2,
+) -> Option<f32> {
+    // Nothing was built yet
+    if bvh.nodes.is_empty() {
+        return None;
+    }
+    let ray = primary_ray(camera, resolution.as_vec2(), frag_coord);
+    let hit = SceneData::borrowed(scene, bvh, mesh_buffers).trace(ray);
+    if hit.primitive_type == PRIMITIVE_NONE {
+        return None;
+    }
+    // Fisheye rays can leave sideways or backwards, those surfaces can not be focused on
+    let distance = hit.t * ray.direction.dot(camera.front.normalize());
+    (distance > 0.0).then_some(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{meshes::mesh::TriangleMesh, motion::MotionTransform};
+
+    const RESOLUTION: UVec2 = UVec2::new(16, 16);
+
+    // One sample per pixel through the default camera, at the origin looking down +Z
+    fn render(scene: &Scene, debug_view: DebugView) -> CpuRenderer {
+        let settings = RenderSettings {
+            debug_view,
+            ..default()
+        };
+        let mut renderer = CpuRenderer::new(scene, &SceneCamera::default(), &settings, RESOLUTION);
+        renderer.render_frame();
+        renderer
+    }
+
+    fn pixel(renderer: &CpuRenderer, x: u32, y: u32) -> Vec3 {
+        renderer.accumulation()[(y * RESOLUTION.x + x) as usize].truncate()
+    }
+
+    fn sphere_scene() -> Scene {
+        let mut scene = Scene::empty();
+        let material = scene.add_material(Material::new(Vec3::new(0.8, 0.2, 0.2), 1.0, 0.0));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, material));
+        scene.add_light(Light::point(Vec3::new(2.0, 3.0, 0.0), Vec3::ONE, 20.0));
+        scene
+    }
+
+    #[test]
+    fn empty_scene_shows_the_environment() {
+        let mut scene = Scene::empty();
+        let color = Vec3::new(0.2, 0.4, 0.6);
+        scene.environment = Environment::gradient(color, color, color);
+
+        let renderer = render(&scene, DebugView::Off);
+        for pixel in renderer.accumulation() {
+            assert!(pixel.truncate().abs_diff_eq(color, 1e-5), "{pixel}");
+            assert_eq!(pixel.w, 1.0);
+        }
+    }
+
+    #[test]
+    fn sphere_faces_the_camera() {
+        let renderer = render(&sphere_scene(), DebugView::Normals);
+        // The normal at the centre points back at the camera, -Z shown as (0.5, 0.5, 0)
+        let centre = pixel(&renderer, RESOLUTION.x / 2, RESOLUTION.y / 2);
+        assert!(
+            centre.abs_diff_eq(Vec3::new(0.5, 0.5, 0.0), 0.1),
+            "{centre}"
+        );
+        assert_eq!(pixel(&renderer, 0, 0), Vec3::ZERO);
+    }
+
+    #[test]
+    fn triangle_shows_its_albedo() {
+        let mut scene = Scene::empty();
+        let albedo = Vec3::new(0.1, 0.6, 0.3);
+        let material = scene.add_material(Material::new(albedo, 1.0, 0.0));
+        let corners = vec![
+            Vec3::new(-1.0, -1.0, 3.0),
+            Vec3::new(0.0, 1.0, 3.0),
+            Vec3::new(1.0, -1.0, 3.0),
+        ];
+        scene.add_mesh(TriangleMesh::new(
+            corners,
+            Vec::new(),
+            vec![[0, 1, 2]],
+            material,
+        ));
+
+        let renderer = render(&scene, DebugView::Albedo);
+        assert_eq!(pixel(&renderer, RESOLUTION.x / 2, RESOLUTION.y / 2), albedo);
+        assert_eq!(pixel(&renderer, 0, 0), Vec3::ZERO);
+        assert_eq!(pixel(&renderer, RESOLUTION.x - 1, 0), Vec3::ZERO);
+    }
+
+    // Traces a fan of rays from the origin at `time` through both scenes, which have to be hit
+    // in the same places
+    fn assert_same_hits(first: &Scene, second: &Scene, time: f32) {
+        let settings = RenderSettings::default();
+        let (first, second) = (
+            SceneData::new(first, &settings),
+            SceneData::new(second, &settings),
+        );
+        let mut hits = 0;
+        for y in -8..=8 {
+            for x in -8..=8 {
+                let ray = Ray {
+                    origin: Vec3::ZERO,
+                    direction: Vec3::new(x as f32 * 0.05, y as f32 * 0.05, 1.0),
+                    time,
+                };
+                let (a, b) = (first.trace(ray), second.trace(ray));
+                assert_eq!(a.primitive_type, b.primitive_type, "{ray:?}");
+                if a.primitive_type == PRIMITIVE_NONE {
+                    continue;
+                }
+                hits += 1;
+                assert!((a.t - b.t).abs() < 1e-4, "{} {}", a.t, b.t);
+                assert!(a.barycentric.abs_diff_eq(b.barycentric, 1e-4));
+                assert!(
+                    a.normal.abs_diff_eq(b.normal, 1e-4),
+                    "{} {}",
+                    a.normal,
+                    b.normal
+                );
+            }
+        }
+        // Rays on both sides of the edges
+        assert!(hits > 0 && hits < 17 * 17, "{hits}");
+    }
+
+    fn test_motion(pivot: Vec3) -> Motion {
+        Motion::new(
+            pivot,
+            MotionTransform::new(Vec3::new(-0.2, 0.0, 0.5), Quat::IDENTITY, Vec3::ONE),
+            MotionTransform::new(
+                Vec3::new(0.5, 0.25, 1.0),
+                Quat::from_rotation_z(0.8) * Quat::from_rotation_y(0.3),
+                Vec3::new(1.5, 0.75, 1.0),
+            ),
+        )
+    }
+
+    #[test]
+    fn moving_triangle_is_hit_where_it_has_moved_to() {
+        let corners = vec![
+            Vec3::new(-1.0, -1.0, 3.0),
+            Vec3::new(0.0, 1.0, 3.0),
+            Vec3::new(1.0, -1.0, 3.0),
+        ];
+        let motion = test_motion(Vec3::new(0.0, 0.0, 3.0));
+        let time = 0.6;
+        let triangle_scene = |corners: Vec<Vec3>, motion| {
+            let mut scene = Scene::empty();
+            let material = scene.add_material(Material::new(Vec3::ONE, 1.0, 0.0));
+            scene.add_mesh(
+                TriangleMesh::new(corners, Vec::new(), vec![[0, 1, 2]], material)
+                    .with_motion(motion),
+            );
+            scene
+        };
+
+        let transform = motion.transform_at(time);
+        let moved = corners
+            .iter()
+            .map(|corner| motion.transform_point(&transform, *corner))
+            .collect();
+        assert_same_hits(
+            &triangle_scene(corners, motion),
+            &triangle_scene(moved, Motion::STATIC),
+            time,
+        );
+    }
+
+    #[test]
+    fn moving_sphere_is_hit_where_it_has_moved_to() {
+        let centre = Vec3::new(0.0, 0.0, 5.0);
+        let motion = test_motion(centre + Vec3::X);
+        let time = 0.3;
+        let sphere_scene = |centre, radius, motion| {
+            let mut scene = Scene::empty();
+            let material = scene.add_material(Material::new(Vec3::ONE, 1.0, 0.0));
+            scene.add_sphere(Sphere::new(centre, radius, material).with_motion(motion));
+            scene
+        };
+
+        // A uniform scale keeps it a sphere that can be placed without motion
+        let motion = Motion {
+            end: MotionTransform {
+                scale: Vec3::splat(1.25),
+                ..motion.end
+            },
+            ..motion
+        };
+        let transform = motion.transform_at(time);
+        assert_same_hits(
+            &sphere_scene(centre, 1.0, motion),
+            &sphere_scene(
+                motion.transform_point(&transform, centre),
+                transform.scale.x,
+                Motion::STATIC,
+            ),
+            time,
+        );
+    }
+
+    #[test]
+    fn focuses_on_the_surface_under_the_cursor() {
+        let scene = sphere_scene();
+        let camera = SceneCamera {
+            inverse_view_matrix: SceneCamera::default().compute_inverse_view_matrix(),
+            ..default()
+        };
+        let bvh = Bvh::build(&scene.primitive_bounds());
+        let mesh_buffers = MeshBuffers::new(&scene.meshes);
+        let centre = RESOLUTION.as_vec2() * 0.5;
+        let focus = |frag_coord| {
+            focal_distance_at(&scene, &bvh, &mesh_buffers, &camera, RESOLUTION, frag_coord)
+        };
+
+        let distance = focus(centre).unwrap();
+        assert!((distance - 4.0).abs() < 1e-3, "{distance}");
+        assert_eq!(focus(Vec2::ZERO), None);
+        // Before the first BVH was built
+        let empty = Bvh::default();
+        let unbuilt = focal_distance_at(&scene, &empty, &mesh_buffers, &camera, RESOLUTION, centre);
+        assert_eq!(unbuilt, None);
+    }
+
+    #[test]
+    fn renders_are_deterministic() {
+        let scene = sphere_scene();
+        let first = render(&scene, DebugView::Off);
+        let second = render(&scene, DebugView::Off);
+        assert_eq!(first.accumulation(), second.accumulation());
+        assert!(first.accumulation().iter().all(|pixel| pixel.is_finite()));
+    }
+}
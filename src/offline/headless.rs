@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::{
+    camera::{camera_path::ActiveCameraPath, camera_update::SceneCamera},
+    compute_shader::{
+        accumulation::AccumulatedFrames,
+        render_settings::{DebugView, RenderSettings},
+    },
+    offline::{
+        image_output::write_image,
+        readback::{ReadbackPlugin, ReadbackReceiver, ReadbackRequest},
+        sequence::{frame_path, FrameSequence, SequenceManifest},
+    },
+    scene::{
+        animation::{animation_end_time, seek, RenderTime},
+        scene::{Scene, ScenePath},
+    },
+    PreviousWindowSize, ResizedWindowEvent, WindowSize,
+};
+
+// Used when neither the command line nor the scene file limit the sample count
+pub const DEFAULT_SAMPLES_PER_PIXEL: u32 = 128;
+
+// Renders a single frame at a fixed resolution without a window and writes it to `output` once
+// every pixel has accumulated the requested number of samples. Add after `RaytracerPlugins`.
+pub struct HeadlessRenderPlugin {
+    pub resolution: UVec2,
+    // None uses the scene's render settings
+    pub samples_per_pixel: Option<u32>,
+    pub output: PathBuf,
+    // Steps through the animation instead, converging every frame and writing it to a numbered
+    // image next to `output`
+    pub frames: Option<FrameSequence>,
+}
+
+impl Plugin for HeadlessRenderPlugin {
+    fn build(&self, app: &mut App) {
+        let resolution = self.resolution.as_vec2();
+        app.insert_resource(WindowSize(resolution));
+        app.insert_resource(PreviousWindowSize(resolution));
+        app.insert_resource(HeadlessRender {
+            samples_per_pixel: self.samples_per_pixel,
+            output: self.output.clone(),
+            reported_samples: 0,
+            frames: self.frames,
+            frame: 0,
+            manifest: None,
+        });
+
+        app.add_plugins(ReadbackPlugin);
+        app.add_systems(Startup, resize_render_target);
+        app.add_systems(
+            PostStartup,
+            (apply_sample_count, start_frame_sequence).chain(),
+        );
+        // The readback is requested for the frame the image was written for moves on to
+        app.add_systems(Update, (write_finished_image, request_readback).chain());
+    }
+}
+
+#[derive(Resource)]
+struct HeadlessRender {
+    samples_per_pixel: Option<u32>,
+    output: PathBuf,
+    // Lowest per pixel sample count printed so far
+    reported_samples: u32,
+    frames: Option<FrameSequence>,
+    // Frame of `frames` being rendered, the tag of the readbacks it is written from
+    frame: u32,
+    manifest: Option<SequenceManifest>,
+}
+
+// Recreates the render textures at `WindowSize`, as a window resize would
+fn resize_render_target(mut ev_window_resized: EventWriter<ResizedWindowEvent>) {
+    ev_window_resized.send(ResizedWindowEvent());
+}
+
+// The command line wins over the scene's render settings, an unlimited count gets a default
+pub fn resolve_samples_per_pixel(requested: Option<u32>, scene_setting: u32) -> u32 {
+    match requested.unwrap_or(scene_setting) {
+        0 => DEFAULT_SAMPLES_PER_PIXEL,
+        samples_per_pixel => samples_per_pixel,
+    }
+}
+
+// Runs after the scene file was loaded, so the command line overrides its render settings
+fn apply_sample_count(headless: Res<HeadlessRender>, mut settings: ResMut<RenderSettings>) {
+    settings.samples_per_pixel =
+        resolve_samples_per_pixel(headless.samples_per_pixel, settings.samples_per_pixel);
+}
+
+// Jumps to the first frame of the sequence, one of a scene without animation would all be the same
+#[allow(clippy::too_many_arguments)]
+fn start_frame_sequence(
+    mut headless: ResMut<HeadlessRender>,
+    settings: Res<RenderSettings>,
+    scene_path: Res<ScenePath>,
+    window_size: Res<WindowSize>,
+    camera_path: Res<ActiveCameraPath>,
+    mut render_time: ResMut<RenderTime>,
+    mut scene: ResMut<Scene>,
+    mut camera: ResMut<SceneCamera>,
+) {
+    let Some(frames) = headless.frames else {
+        return;
+    };
+    if animation_end_time(&scene, camera_path.0.as_ref()).is_none() {
+        println!("nothing in the scene is animated, there are no frames to render");
+        std::process::exit(1);
+    }
+
+    let manifest = SequenceManifest::create(
+        &headless.output,
+        scene_path.0.as_deref().unwrap_or(Path::new("")),
+        window_size.0.as_uvec2(),
+        settings.samples_per_pixel,
+        &frames,
+    );
+    match manifest {
+        Ok(manifest) => headless.manifest = Some(manifest),
+        Err(error) => {
+            println!("{}: {error}", headless.output.display());
+            std::process::exit(1);
+        }
+    }
+    render_time.0 = frames.time(0);
+    seek(
+        render_time.0,
+        &mut scene,
+        &mut camera,
+        camera_path.0.as_ref(),
+    );
+}
+
+// The GPU lags behind the frame counter, so readbacks only start once it could be done
+fn request_readback(
+    frames: Res<AccumulatedFrames>,
+    settings: Res<RenderSettings>,
+    headless: Res<HeadlessRender>,
+    mut request: ResMut<ReadbackRequest>,
+) {
+    let samples = frames.0.saturating_mul(settings.samples_per_frame.max(1));
+    request.set_if_neq(ReadbackRequest {
+        enabled: samples >= settings.samples_per_pixel,
+        tag: headless.frame,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_finished_image(
+    mut headless: ResMut<HeadlessRender>,
+    receiver: Res<ReadbackReceiver>,
+    settings: Res<RenderSettings>,
+    window_size: Res<WindowSize>,
+    camera_path: Res<ActiveCameraPath>,
+    mut render_time: ResMut<RenderTime>,
+    mut scene: ResMut<Scene>,
+    mut camera: ResMut<SceneCamera>,
+    mut ev_app_exit: EventWriter<AppExit>,
+) {
+    for image in receiver.0.try_iter() {
+        // Copies still in flight from an earlier frame of the sequence
+        if image.tag != headless.frame
+            || UVec2::new(image.width, image.height) != window_size.0.as_uvec2()
+        {
+            continue;
+        }
+
+        // The alpha channel of the accumulation texture holds each pixel's sample count
+        let samples = image
+            .pixels
+            .iter()
+            .map(|pixel| pixel.w as u32)
+            .min()
+            .unwrap_or(0);
+        if samples > headless.reported_samples {
+            headless.reported_samples = samples;
+            println!("{samples}/{} samples", settings.samples_per_pixel);
+        }
+        if samples < settings.samples_per_pixel {
+            continue;
+        }
+
+        // The readback holds the plain average, tone map it like the tonemap pass would
+        let tonemap = settings.debug_view == DebugView::Off;
+
+        let Some(frames) = headless.frames else {
+            match write_image(
+                &headless.output,
+                image.width,
+                image.height,
+                &image.pixels,
+                tonemap,
+            ) {
+                Ok(()) => println!("wrote {}", headless.output.display()),
+                Err(error) => {
+                    println!("{error}");
+                    std::process::exit(1);
+                }
+            }
+            ev_app_exit.send(AppExit);
+            return;
+        };
+
+        let output = frame_path(&headless.output, headless.frame);
+        let written = write_image(&output, image.width, image.height, &image.pixels, tonemap)
+            .map_err(|error| error.to_string())
+            .and_then(|()| {
+                let manifest = headless.manifest.as_mut().expect("created at startup");
+                manifest
+                    .add_frame(&output, render_time.0)
+                    .map_err(|error| format!("{}: {error}", manifest.path().display()))
+            });
+        if let Err(error) = written {
+            println!("{error}");
+            std::process::exit(1);
+        }
+        println!(
+            "wrote {}, frame {}/{}",
+            output.display(),
+            headless.frame + 1,
+            frames.count
+        );
+
+        if headless.frame + 1 == frames.count {
+            if let Some(manifest) = &headless.manifest {
+                println!("wrote {}", manifest.path().display());
+            }
+            ev_app_exit.send(AppExit);
+            return;
+        }
+        headless.frame += 1;
+        headless.reported_samples = 0;
+        render_time.0 = frames.time(headless.frame);
+        seek(
+            render_time.0,
+            &mut scene,
+            &mut camera,
+            camera_path.0.as_ref(),
+        );
+        return;
+    }
+}
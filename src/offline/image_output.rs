@@ -0,0 +1,106 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // 8 bit sRGB, tone mapped like the preview
+    Png,
+    // 32 bit float linear radiance
+    Exr,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "exr" => Some(OutputFormat::Exr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageOutputError {
+    UnsupportedFormat(PathBuf),
+    Image {
+        path: PathBuf,
+        error: image::ImageError,
+    },
+}
+
+impl fmt::Display for ImageOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageOutputError::UnsupportedFormat(path) => {
+                write!(f, "{}: output must be a .png or .exr file", path.display())
+            }
+            ImageOutputError::Image { path, error } => {
+                write!(f, "{}: failed to write image: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageOutputError {}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve, the same as `aces` in tonemap.wgsl
+fn aces(color: Vec3) -> Vec3 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    ((color * (a * color + b)) / (color * (c * color + d) + e)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// `pixels` holds linear rgb radiance row by row from the top, alpha is ignored. Png output goes
+// through the ACES curve first when `tonemap` is set, exr always keeps the radiance as it is.
+pub fn write_image(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[Vec4],
+    tonemap: bool,
+) -> Result<(), ImageOutputError> {
+    let format = OutputFormat::from_path(path)
+        .ok_or_else(|| ImageOutputError::UnsupportedFormat(path.to_path_buf()))?;
+    let image_error = |error| ImageOutputError::Image {
+        path: path.to_path_buf(),
+        error,
+    };
+
+    match format {
+        OutputFormat::Png => {
+            let bytes = pixels
+                .iter()
+                .map(|pixel| pixel.truncate())
+                .flat_map(|color| if tonemap { aces(color) } else { color }.to_array())
+                .map(|channel| (linear_to_srgb(channel) * 255.0).round() as u8)
+                .collect();
+            image::RgbImage::from_raw(width, height, bytes)
+                .expect("pixel count matches the image size")
+                .save_with_format(path, image::ImageFormat::Png)
+                .map_err(image_error)
+        }
+        OutputFormat::Exr => {
+            let floats = pixels
+                .iter()
+                .flat_map(|pixel| pixel.truncate().to_array())
+                .collect();
+            image::Rgb32FImage::from_raw(width, height, floats)
+                .expect("pixel count matches the image size")
+                .save_with_format(path, image::ImageFormat::OpenExr)
+                .map_err(image_error)
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::RenderAssets,
+        render_graph::{self, RenderGraph},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        Render, RenderApp, RenderSet,
+    },
+};
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::{compute_shader::compute_pass::ComputePasses, ComputeTextures};
+
+// Copies the accumulation texture back to the CPU while `ReadbackRequest` is set. Every copy
+// arrives as a `ReadbackImage` on `ReadbackReceiver`, one frame or more after it was requested.
+pub struct ReadbackPlugin;
+impl Plugin for ReadbackPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        app.init_resource::<ReadbackRequest>();
+        app.insert_resource(ReadbackReceiver(receiver));
+        app.add_plugins(ExtractResourcePlugin::<ReadbackRequest>::default());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<ReadbackRequest>();
+        render_app.insert_resource(ReadbackSender(sender));
+        render_app.add_systems(
+            Render,
+            (
+                prepare_readback_buffer.in_set(RenderSet::Prepare),
+                // Cleanup runs after the frame's commands were submitted
+                map_readback_buffer.in_set(RenderSet::Cleanup),
+            ),
+        );
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node("readback", ReadbackNode);
+        render_graph.add_node_edge("readback", bevy::render::main_graph::node::CAMERA_DRIVER);
+    }
+
+    // Passes are only all known once every plugin was built
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        let passes: Vec<&'static str> = render_app
+            .world
+            .get_resource::<ComputePasses>()
+            .map(|passes| {
+                passes
+                    .0
+                    .iter()
+                    .filter(|pass| pass.outputs.contains(&"accumulation"))
+                    .map(|pass| pass.name)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        for pass in passes {
+            render_graph.add_node_edge(pass, "readback");
+        }
+    }
+}
+
+#[derive(Resource, Clone, Copy, Default, PartialEq, ExtractResource)]
+pub struct ReadbackRequest {
+    pub enabled: bool,
+    // Copied into every `ReadbackImage`, it is extracted together with the camera and scene so
+    // copies of a frame rendered before they changed can be told apart
+    pub tag: u32,
+}
+
+#[derive(Resource)]
+pub struct ReadbackReceiver(pub Receiver<ReadbackImage>);
+
+#[derive(Resource)]
+struct ReadbackSender(Sender<ReadbackImage>);
+
+// Linear radiance in rgb, the number of accumulated samples in alpha, rows from the top
+pub struct ReadbackImage {
+    pub tag: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec4>,
+}
+
+#[derive(Resource)]
+struct ReadbackBuffer {
+    buffer: Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+}
+
+const BYTES_PER_PIXEL: u32 = 16;
+
+// The accumulation texture and the buffer to copy it into, when a readback was requested and the
+// buffer has already been resized to the texture
+fn readback_source<'a>(
+    request: &ReadbackRequest,
+    gpu_images: &'a RenderAssets<Image>,
+    compute_textures: &ComputeTextures,
+    buffer: Option<&'a ReadbackBuffer>,
+) -> Option<(&'a Texture, &'a ReadbackBuffer)> {
+    if !request.enabled {
+        return None;
+    }
+    let gpu_image = gpu_images.get(compute_textures.image("accumulation")?)?;
+    let buffer = buffer?;
+    let size = gpu_image.size.as_uvec2();
+    (size == UVec2::new(buffer.width, buffer.height)).then_some((&gpu_image.texture, buffer))
+}
+
+fn prepare_readback_buffer(
+    mut commands: Commands,
+    request: Res<ReadbackRequest>,
+    gpu_images: Res<RenderAssets<Image>>,
+    compute_textures: Res<ComputeTextures>,
+    render_device: Res<RenderDevice>,
+    buffer: Option<Res<ReadbackBuffer>>,
+) {
+    if !request.enabled {
+        return;
+    }
+    let Some(gpu_image) = compute_textures
+        .image("accumulation")
+        .and_then(|image| gpu_images.get(image))
+    else {
+        return;
+    };
+
+    let size = gpu_image.size.as_uvec2();
+    if buffer.is_some_and(|buffer| UVec2::new(buffer.width, buffer.height) == size) {
+        return;
+    }
+
+    let padded_bytes_per_row =
+        RenderDevice::align_copy_bytes_per_row((size.x * BYTES_PER_PIXEL) as usize) as u32;
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("readback_buffer"),
+        size: (padded_bytes_per_row * size.y) as u64,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    commands.insert_resource(ReadbackBuffer {
+        buffer,
+        width: size.x,
+        height: size.y,
+        padded_bytes_per_row,
+    });
+}
+
+fn map_readback_buffer(
+    request: Res<ReadbackRequest>,
+    gpu_images: Res<RenderAssets<Image>>,
+    compute_textures: Res<ComputeTextures>,
+    render_device: Res<RenderDevice>,
+    buffer: Option<Res<ReadbackBuffer>>,
+    sender: Res<ReadbackSender>,
+) {
+    let Some((_, buffer)) =
+        readback_source(&request, &gpu_images, &compute_textures, buffer.as_deref())
+    else {
+        return;
+    };
+
+    let slice = buffer.buffer.slice(..);
+    let (mapped_sender, mapped_receiver) = crossbeam_channel::bounded(1);
+    render_device.map_buffer(&slice, MapMode::Read, move |result| {
+        let _ = mapped_sender.send(result);
+    });
+    render_device.poll(wgpu::Maintain::Wait);
+    match mapped_receiver.recv() {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            println!("failed to read back the accumulation texture: {error}");
+            return;
+        }
+        Err(_) => return,
+    }
+
+    let row_bytes = (buffer.width * BYTES_PER_PIXEL) as usize;
+    let pixels = {
+        let data = slice.get_mapped_range();
+        data.chunks_exact(buffer.padded_bytes_per_row as usize)
+            .flat_map(|row| bytemuck::pod_collect_to_vec::<u8, [f32; 4]>(&row[..row_bytes]))
+            .map(Vec4::from)
+            .collect()
+    };
+    buffer.buffer.unmap();
+
+    let _ = sender.0.send(ReadbackImage {
+        tag: request.tag,
+        width: buffer.width,
+        height: buffer.height,
+        pixels,
+    });
+}
+
+struct ReadbackNode;
+
+impl render_graph::Node for ReadbackNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let Some((texture, buffer)) = readback_source(
+            world.resource::<ReadbackRequest>(),
+            world.resource::<RenderAssets<Image>>(),
+            world.resource::<ComputeTextures>(),
+            world.get_resource::<ReadbackBuffer>(),
+        ) else {
+            return Ok(());
+        };
+
+        render_context.command_encoder().copy_texture_to_buffer(
+            texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(buffer.padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: buffer.width,
+                height: buffer.height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::Serialize;
+
+// Where the frames go when no output was given
+pub const DEFAULT_SEQUENCE_OUTPUT: &str = "frames/frame.png";
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// Evenly spaced frames of the animation, starting at time zero
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSequence {
+    pub count: u32,
+    pub frames_per_second: u32,
+}
+
+impl FrameSequence {
+    // Seconds on the timeline, frames are counted from zero
+    pub fn time(&self, frame: u32) -> f32 {
+        frame as f32 / self.frames_per_second as f32
+    }
+}
+
+// `frames/frame.png` becomes `frames/frame_0001.png` for the first frame, numbered from one
+pub fn frame_path(path: &Path, frame: u32) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let mut name = format!("{stem}_{:04}", frame + 1);
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+// Lists the frames of a sequence for whatever assembles them, written to manifest.json next to
+// them. It is rewritten after every frame, so an interrupted render lists the frames it finished.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequenceManifest {
+    pub scene: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub frames_per_second: u32,
+    pub frames: Vec<ManifestFrame>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestFrame {
+    // Relative to the manifest
+    pub file: PathBuf,
+    // Seconds
+    pub time: f32,
+}
+
+impl SequenceManifest {
+    // Creates the directory of `output`, the frames are written into it
+    pub fn create(
+        output: &Path,
+        scene: &Path,
+        resolution: UVec2,
+        samples_per_pixel: u32,
+        sequence: &FrameSequence,
+    ) -> io::Result<Self> {
+        let directory = output.parent().unwrap_or(Path::new(""));
+        if !directory.as_os_str().is_empty() {
+            fs::create_dir_all(directory)?;
+        }
+        Ok(SequenceManifest {
+            scene: scene.to_path_buf(),
+            width: resolution.x,
+            height: resolution.y,
+            samples_per_pixel,
+            frames_per_second: sequence.frames_per_second,
+            frames: Vec::new(),
+            path: output.with_file_name(MANIFEST_FILE_NAME),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn add_frame(&mut self, frame_path: &Path, time: f32) -> io::Result<()> {
+        self.frames.push(ManifestFrame {
+            file: frame_path
+                .file_name()
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+            time,
+        });
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+    }
+}
@@ -0,0 +1,331 @@
+use std::ops::{Add, Mul, Sub};
+
+use bevy::prelude::*;
+
+use crate::{
+    camera::{
+        camera_path::{ActiveCameraPath, CameraPath},
+        camera_update::SceneCamera,
+    },
+    scene::{
+        materials::material::Material,
+        motion::{Motion, MotionTransform},
+        scene::Scene,
+        spheres::sphere::Sphere,
+    },
+    ComputeBuffers, RENDER_TIME_BUFFER,
+};
+
+pub struct AnimationPlugin;
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderTime>();
+        app.init_resource::<Playback>();
+        app.add_systems(
+            Update,
+            (control_playback, advance_time, animate_scene).chain(),
+        );
+        // PostUpdate so every change made to the clock during Update is uploaded this frame
+        app.add_systems(PostUpdate, update_render_time_buffer);
+    }
+}
+
+// Seconds on the animation timeline the scene and camera are shown at, `render_time` in
+// raytracer.wgsl
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct RenderTime(pub f32);
+
+#[derive(Resource, Default)]
+pub struct Playback {
+    pub playing: bool,
+}
+
+// Values at increasing times, interpolated with a Catmull-Rom spline and held before the first
+// and after the last keyframe
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track<T> {
+    pub keyframes: Vec<(f32, T)>,
+}
+
+impl<T> Default for Track<T> {
+    fn default() -> Self {
+        Track {
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+impl<T> Track<T> {
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        self.keyframes.iter().map(|(time, _)| *time)
+    }
+}
+
+impl<T> Track<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let &(first_time, first) = self.keyframes.first()?;
+        if self.keyframes.len() == 1 {
+            return Some(first);
+        }
+        let times: Vec<f32> = self.times().collect();
+        let time = time.clamp(first_time, self.end_time());
+        let points = spline_segment(&times, time, false)
+            .map(|(index, time)| (self.keyframes[index].1, time));
+        Some(catmull_rom(points, time))
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |(time, _)| *time)
+    }
+
+    pub fn value_at_keyframe(&self, time: f32) -> Option<T> {
+        self.keyframes
+            .iter()
+            .find(|(keyframe_time, _)| *keyframe_time == time)
+            .map(|(_, value)| *value)
+    }
+}
+
+// Keyframes of one sphere, what has none stays as it is
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SphereAnimation {
+    pub sphere_index: u32,
+    pub position: Track<Vec3>,
+    pub radius: Track<f32>,
+}
+
+impl SphereAnimation {
+    // The keyframes also replace the sphere's motion, with where they move it to between the
+    // camera's shutter opening and closing, in seconds relative to `time`
+    pub fn apply(&self, sphere: &mut Sphere, time: f32, shutter_open: f32, shutter_close: f32) {
+        let at = |time: f32| {
+            let position = self.position.sample(time).unwrap_or(sphere.position);
+            let radius = self.radius.sample(time).map_or(sphere.radius, f32::abs);
+            (position, radius)
+        };
+        let (position, radius) = at(time);
+        let transform = |time: f32| {
+            let (moved, moved_radius) = at(time);
+            let scale = if radius > 0.0 {
+                moved_radius / radius
+            } else {
+                1.0
+            };
+            MotionTransform::new(moved - position, Quat::IDENTITY, Vec3::splat(scale))
+        };
+        let motion = Motion::new(
+            position,
+            transform(time + shutter_open),
+            transform(time + shutter_close),
+        );
+
+        sphere.position = position;
+        sphere.radius = radius;
+        sphere.motion = motion;
+    }
+
+    pub fn end_time(&self) -> f32 {
+        self.position.end_time().max(self.radius.end_time())
+    }
+}
+
+// Keyframes of one material's parameters, what has none stays as it is
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaterialAnimation {
+    pub material_index: u32,
+    pub albedo: Track<Vec3>,
+    pub roughness: Track<f32>,
+    pub metallic: Track<f32>,
+    pub emission: Track<Vec3>,
+    pub ior: Track<f32>,
+    pub transmission: Track<f32>,
+}
+
+impl MaterialAnimation {
+    // Keeps the parameters in the ranges the `Material` constructors allow, the spline can
+    // overshoot them between keyframes
+    pub fn apply(&self, material: &mut Material, time: f32) {
+        if let Some(albedo) = self.albedo.sample(time) {
+            material.albedo = albedo.max(Vec3::ZERO);
+        }
+        if let Some(roughness) = self.roughness.sample(time) {
+            material.roughness = roughness.clamp(0.0, 1.0);
+        }
+        if let Some(metallic) = self.metallic.sample(time) {
+            material.metallic = metallic.clamp(0.0, 1.0);
+        }
+        if let Some(emission) = self.emission.sample(time) {
+            material.emission = emission.max(Vec3::ZERO);
+        }
+        if let Some(ior) = self.ior.sample(time) {
+            material.ior = ior.max(1.0);
+        }
+        if let Some(transmission) = self.transmission.sample(time) {
+            material.transmission = transmission.clamp(0.0, 1.0);
+        }
+    }
+
+    pub fn end_time(&self) -> f32 {
+        [
+            self.albedo.end_time(),
+            self.roughness.end_time(),
+            self.metallic.end_time(),
+            self.emission.end_time(),
+            self.ior.end_time(),
+            self.transmission.end_time(),
+        ]
+        .into_iter()
+        .fold(0.0, f32::max)
+    }
+}
+
+// The keyframes on either side of the segment of sorted `times` that `time` falls into, as
+// indices and times for `catmull_rom`. Needs two times at least. Looping keyframes continue on the
+// other side of the last one, which repeats the first, a loop later or earlier. Open ones repeat
+// their end keyframes.
+pub fn spline_segment(times: &[f32], time: f32, looping: bool) -> [(usize, f32); 4] {
+    let last = times.len() - 1;
+    let duration = times[last] - times[0];
+    let neighbour = |index: isize| {
+        if looping && last > 1 {
+            if index < 0 {
+                return (last - 1, times[last - 1] - duration);
+            }
+            if index as usize > last {
+                return (1, times[1] + duration);
+            }
+        }
+        let index = index.clamp(0, last as isize) as usize;
+        (index, times[index])
+    };
+
+    let end = times
+        .iter()
+        .position(|&keyframe_time| keyframe_time > time)
+        .unwrap_or(last)
+        .clamp(1, last) as isize;
+    [
+        neighbour(end - 2),
+        neighbour(end - 1),
+        neighbour(end),
+        neighbour(end + 1),
+    ]
+}
+
+// Cubic Hermite curve from the second to the third of `points`, (value, time) pairs, with the
+// tangents of a Catmull-Rom spline. Dividing by the time between the neighbours keeps the speed
+// continuous through keyframes that are unevenly spaced.
+pub fn catmull_rom<T>(points: [(T, f32); 4], time: f32) -> T
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let [before, start, end, after] = points;
+    let segment = (end.1 - start.1).max(f32::EPSILON);
+    let tangent = |(from, from_time): (T, f32), (to, to_time): (T, f32)| {
+        (to - from) * (segment / (to_time - from_time).max(f32::EPSILON))
+    };
+    let start_tangent = tangent(before, end);
+    let end_tangent = tangent(start, after);
+
+    let t = ((time - start.1) / segment).clamp(0.0, 1.0);
+    let t2 = t * t;
+    let t3 = t2 * t;
+    start.0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + start_tangent * (t3 - 2.0 * t2 + t)
+        + end.0 * (3.0 * t2 - 2.0 * t3)
+        + end_tangent * (t3 - t2)
+}
+
+// Where the timeline ends, None when neither the scene nor the camera are animated
+pub fn animation_end_time(scene: &Scene, camera_path: Option<&CameraPath>) -> Option<f32> {
+    let camera_end_time = camera_path.map(CameraPath::end_time);
+    match (scene.animation_end_time(), camera_end_time) {
+        (Some(scene_end), Some(camera_end)) => Some(scene_end.max(camera_end)),
+        (scene_end, camera_end) => scene_end.or(camera_end),
+    }
+}
+
+// Moves the scene and `camera` to `time` at once. Renderers that write frames out use it, waiting
+// for the systems below would leave the first frame rendered after a jump at the old time.
+pub fn seek(
+    time: f32,
+    scene: &mut Scene,
+    camera: &mut SceneCamera,
+    camera_path: Option<&CameraPath>,
+) {
+    if let Some(moved) = camera_path.and_then(|path| path.camera_at(time, camera)) {
+        *camera = moved;
+    }
+    scene.animate(time, camera);
+}
+
+// Space plays and pauses the animation
+fn control_playback(
+    input_keyboard: Res<Input<KeyCode>>,
+    scene: Res<Scene>,
+    camera_path: Res<ActiveCameraPath>,
+    mut render_time: ResMut<RenderTime>,
+    mut playback: ResMut<Playback>,
+) {
+    if !input_keyboard.just_pressed(KeyCode::Space) {
+        return;
+    }
+    let Some(end_time) = animation_end_time(&scene, camera_path.0.as_ref()) else {
+        println!("nothing in the scene is animated");
+        return;
+    };
+
+    // Playing a finished animation starts it over
+    let looping = camera_path.0.as_ref().is_some_and(|path| path.looping);
+    if !playback.playing && !looping && render_time.0 >= end_time {
+        render_time.0 = 0.0;
+    }
+    playback.playing = !playback.playing;
+}
+
+pub fn advance_time(
+    time: Res<Time>,
+    scene: Res<Scene>,
+    camera_path: Res<ActiveCameraPath>,
+    mut render_time: ResMut<RenderTime>,
+    mut playback: ResMut<Playback>,
+) {
+    if !playback.playing {
+        return;
+    }
+
+    render_time.0 += time.delta_seconds();
+    // A looping camera path plays on forever, the scene holds its last keyframes meanwhile
+    let looping = camera_path.0.as_ref().is_some_and(|path| path.looping);
+    let end_time = animation_end_time(&scene, camera_path.0.as_ref()).unwrap_or(0.0);
+    if !looping && render_time.0 >= end_time {
+        render_time.0 = end_time;
+        playback.playing = false;
+    }
+}
+
+// Also runs when the scene was reloaded, the keyframes override the values the file was loaded with,
+// and when the camera changed, its shutter decides how far keyframed spheres move
+fn animate_scene(render_time: Res<RenderTime>, camera: Res<SceneCamera>, mut scene: ResMut<Scene>) {
+    if !render_time.is_changed() && !scene.is_changed() && !camera.is_changed() {
+        return;
+    }
+    // Only a moved value marks the scene changed, that rebuilds the BVH and restarts the
+    // accumulation
+    if scene
+        .bypass_change_detection()
+        .animate(render_time.0, &camera)
+    {
+        scene.set_changed();
+    }
+}
+
+fn update_render_time_buffer(
+    render_time: Res<RenderTime>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+) {
+    compute_buffers.set_value_at(RENDER_TIME_BUFFER, render_time.0);
+}
@@ -0,0 +1,509 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+// Matches BVH_STACK_SIZE in raytracer.wgsl. Traversal pushes at most one far child per level, so
+// leaves no deeper than this never overflow the stack.
+pub const MAX_DEPTH: usize = 32;
+// Refitting loosens the tree, past this growth of the root surface area a full rebuild is cheaper
+const REFIT_REBUILD_RATIO: f32 = 2.0;
+
+// Matches the PRIMITIVE_* constants in raytracer.wgsl, 0 is reserved for "no primitive"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimitiveType {
+    Sphere = 1,
+    Triangle = 2,
+}
+
+shader_types! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ShaderType)]
+    pub struct PrimitiveRef {
+        pub primitive_type: u32,
+        pub primitive_id: u32,
+    }
+
+    // Mirrors `struct BvhNode` in raytracer.wgsl. Interior nodes have `primitive_count == 0` and
+    // `left_first` pointing at the left child, the right child always follows it. Leaves store the
+    // first index into the primitive list instead.
+    #[derive(Debug, Clone, Copy, ShaderType)]
+    pub struct BvhNode {
+        pub aabb_min: Vec3,
+        pub left_first: u32,
+        pub aabb_max: Vec3,
+        pub primitive_count: u32,
+    }
+}
+
+impl PrimitiveRef {
+    pub fn new(primitive_type: PrimitiveType, primitive_id: u32) -> Self {
+        PrimitiveRef {
+            primitive_type: primitive_type as u32,
+            primitive_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    // Inverted box, growing it by anything yields that thing and rays never hit it
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::MAX),
+        max: Vec3::splat(f32::MIN),
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn grow(self, point: Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.min.cmpgt(self.max).any() {
+            return 0.0;
+        }
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+}
+
+impl BvhNode {
+    fn new(bounds: Aabb, left_first: u32, primitive_count: u32) -> Self {
+        BvhNode {
+            aabb_min: bounds.min,
+            left_first,
+            aabb_max: bounds.max,
+            primitive_count,
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb::new(self.aabb_min, self.aabb_max)
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.primitive_count > 0
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    // Primitives in leaf order, what the shader indexes with `left_first`
+    pub primitives: Vec<PrimitiveRef>,
+    // For each entry of `primitives`, its position in the list the tree was built from
+    source_indices: Vec<u32>,
+    built_surface_area: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    pub fn build(primitives: &[(PrimitiveRef, Aabb)]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(primitives.len().max(1) * 2),
+            primitives: Vec::new(),
+            source_indices: (0..primitives.len() as u32).collect(),
+            built_surface_area: 0.0,
+        };
+
+        let bounds = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |bounds, (_, aabb)| bounds.union(*aabb));
+        // With no primitives the root keeps the empty box, which rays miss before it is traversed
+        bvh.nodes.push(BvhNode::new(bounds, 0, 0));
+
+        if !primitives.is_empty() {
+            let centroids: Vec<Vec3> = primitives.iter().map(|(_, aabb)| aabb.centroid()).collect();
+            bvh.subdivide(0, 0, primitives.len(), 0, primitives, &centroids);
+        }
+
+        bvh.primitives = bvh
+            .source_indices
+            .iter()
+            .map(|&index| primitives[index as usize].0)
+            .collect();
+        bvh.built_surface_area = bounds.surface_area();
+        bvh
+    }
+
+    // Refits when the primitive list only moved, rebuilds when it was added to, removed from or
+    // reordered, or when refitting has degraded the tree too much.
+    pub fn update(&mut self, primitives: &[(PrimitiveRef, Aabb)]) {
+        // The default tree has no root to refit yet
+        let same_topology = !self.nodes.is_empty()
+            && self.source_indices.len() == primitives.len()
+            && self
+                .source_indices
+                .iter()
+                .zip(self.primitives.iter())
+                .all(|(&index, reference)| primitives[index as usize].0 == *reference);
+
+        if !same_topology {
+            *self = Bvh::build(primitives);
+            return;
+        }
+
+        self.refit(primitives);
+        let root_area = self.nodes[0].bounds().surface_area();
+        if root_area > self.built_surface_area * REFIT_REBUILD_RATIO {
+            *self = Bvh::build(primitives);
+        }
+    }
+
+    // Children are always stored after their parent, so a reverse sweep visits them first
+    fn refit(&mut self, primitives: &[(PrimitiveRef, Aabb)]) {
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            let bounds = if node.is_leaf() {
+                let first = node.left_first as usize;
+                self.source_indices[first..first + node.primitive_count as usize]
+                    .iter()
+                    .fold(Aabb::EMPTY, |bounds, &index| {
+                        bounds.union(primitives[index as usize].1)
+                    })
+            } else if self.source_indices.is_empty() {
+                Aabb::EMPTY
+            } else {
+                let left = self.nodes[node.left_first as usize].bounds();
+                let right = self.nodes[node.left_first as usize + 1].bounds();
+                left.union(right)
+            };
+            self.nodes[node_index].aabb_min = bounds.min;
+            self.nodes[node_index].aabb_max = bounds.max;
+        }
+    }
+
+    fn subdivide(
+        &mut self,
+        node_index: usize,
+        first: usize,
+        count: usize,
+        depth: usize,
+        primitives: &[(PrimitiveRef, Aabb)],
+        centroids: &[Vec3],
+    ) {
+        let leaf_cost = count as f32 * self.nodes[node_index].bounds().surface_area();
+        let split = self.find_best_split(first, count, primitives, centroids);
+
+        // At the depth limit the rest of the range goes into one leaf, however large
+        let split = split.filter(|(_, _, cost)| {
+            depth < MAX_DEPTH && (*cost < leaf_cost || count > MAX_LEAF_SIZE)
+        });
+        let Some((axis, position, _)) = split else {
+            let bounds = self.nodes[node_index].bounds();
+            self.nodes[node_index] = BvhNode::new(bounds, first as u32, count as u32);
+            return;
+        };
+
+        // Partition the primitive range in place around the split plane
+        let range = &mut self.source_indices[first..first + count];
+        let mut left_count = 0;
+        for i in 0..range.len() {
+            if centroids[range[i] as usize][axis] < position {
+                range.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+        // All centroids on one side, fall back to a median split so the recursion terminates
+        if left_count == 0 || left_count == count {
+            range.sort_unstable_by(|&a, &b| {
+                centroids[a as usize][axis].total_cmp(&centroids[b as usize][axis])
+            });
+            left_count = count / 2;
+        }
+
+        let range_bounds = |indices: &[u32]| {
+            indices.iter().fold(Aabb::EMPTY, |bounds, &index| {
+                bounds.union(primitives[index as usize].1)
+            })
+        };
+        let left_bounds = range_bounds(&self.source_indices[first..first + left_count]);
+        let right_bounds = range_bounds(&self.source_indices[first + left_count..first + count]);
+
+        let left_index = self.nodes.len();
+        self.nodes.push(BvhNode::new(left_bounds, 0, 0));
+        self.nodes.push(BvhNode::new(right_bounds, 0, 0));
+        self.nodes[node_index].left_first = left_index as u32;
+
+        self.subdivide(
+            left_index,
+            first,
+            left_count,
+            depth + 1,
+            primitives,
+            centroids,
+        );
+        self.subdivide(
+            left_index + 1,
+            first + left_count,
+            count - left_count,
+            depth + 1,
+            primitives,
+            centroids,
+        );
+    }
+
+    // Binned surface area heuristic, returns the axis, plane position and cost of the cheapest
+    // split
+    fn find_best_split(
+        &self,
+        first: usize,
+        count: usize,
+        primitives: &[(PrimitiveRef, Aabb)],
+        centroids: &[Vec3],
+    ) -> Option<(usize, f32, f32)> {
+        if count <= 1 {
+            return None;
+        }
+
+        let indices = &self.source_indices[first..first + count];
+        let centroid_bounds = indices.iter().fold(Aabb::EMPTY, |bounds, &index| {
+            bounds.grow(centroids[index as usize])
+        });
+
+        let mut best: Option<(usize, f32, f32)> = None;
+        let axis_ranges = centroid_bounds
+            .min
+            .to_array()
+            .into_iter()
+            .zip(centroid_bounds.max.to_array());
+        for (axis, (min, max)) in axis_ranges.enumerate() {
+            if max - min <= f32::EPSILON {
+                continue;
+            }
+
+            let scale = SAH_BINS as f32 / (max - min);
+            let mut bins = [Bin {
+                bounds: Aabb::EMPTY,
+                count: 0,
+            }; SAH_BINS];
+            for &index in indices {
+                let bin =
+                    (((centroids[index as usize][axis] - min) * scale) as usize).min(SAH_BINS - 1);
+                bins[bin].count += 1;
+                bins[bin].bounds = bins[bin].bounds.union(primitives[index as usize].1);
+            }
+
+            // Sweep from both sides to get the area and count left and right of every plane
+            let mut left_area = [0.0; SAH_BINS - 1];
+            let mut left_count = [0; SAH_BINS - 1];
+            let mut right_area = [0.0; SAH_BINS - 1];
+            let mut right_count = [0; SAH_BINS - 1];
+            let (mut left_bounds, mut right_bounds) = (Aabb::EMPTY, Aabb::EMPTY);
+            let (mut left_sum, mut right_sum) = (0, 0);
+            for i in 0..SAH_BINS - 1 {
+                left_sum += bins[i].count;
+                left_count[i] = left_sum;
+                left_bounds = left_bounds.union(bins[i].bounds);
+                left_area[i] = left_bounds.surface_area();
+
+                right_sum += bins[SAH_BINS - 1 - i].count;
+                right_count[SAH_BINS - 2 - i] = right_sum;
+                right_bounds = right_bounds.union(bins[SAH_BINS - 1 - i].bounds);
+                right_area[SAH_BINS - 2 - i] = right_bounds.surface_area();
+            }
+
+            for i in 0..SAH_BINS - 1 {
+                let cost =
+                    left_count[i] as f32 * left_area[i] + right_count[i] as f32 * right_area[i];
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, min + (i + 1) as f32 / scale, cost));
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere(id: u32, centre: Vec3, radius: f32) -> (PrimitiveRef, Aabb) {
+        let extent = Vec3::splat(radius);
+        (
+            PrimitiveRef::new(PrimitiveType::Sphere, id),
+            Aabb::new(centre - extent, centre + extent),
+        )
+    }
+
+    // Spheres of a few sizes scattered through a 20 unit cube, the same every run
+    fn scattered_spheres(count: u32) -> Vec<(PrimitiveRef, Aabb)> {
+        let mut state = 0x2545_f491_u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32
+        };
+        (0..count)
+            .map(|id| {
+                let centre = Vec3::new(random(), random(), random()) * 20.0 - 10.0;
+                sphere(id, centre, 0.1 + random())
+            })
+            .collect()
+    }
+
+    fn contains(outer: Aabb, inner: Aabb) -> bool {
+        outer.min.cmple(inner.min).all() && outer.max.cmpge(inner.max).all()
+    }
+
+    // Walks the tree from the root, checking that every node's bounds hold its children or
+    // primitives and that every primitive sits in exactly one leaf within the depth limit
+    fn assert_valid(bvh: &Bvh, primitives: &[(PrimitiveRef, Aabb)]) {
+        let mut seen = vec![0; primitives.len()];
+        let mut stack = vec![(0, 0)];
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = bvh.nodes[node_index];
+            assert!(
+                depth <= MAX_DEPTH,
+                "node {node_index} is {depth} levels deep"
+            );
+            if node.is_leaf() {
+                let first = node.left_first as usize;
+                for i in first..first + node.primitive_count as usize {
+                    let index = bvh.source_indices[i] as usize;
+                    assert_eq!(bvh.primitives[i], primitives[index].0);
+                    assert!(
+                        contains(node.bounds(), primitives[index].1),
+                        "leaf {node_index} does not contain primitive {index}"
+                    );
+                    seen[index] += 1;
+                }
+            } else if !primitives.is_empty() {
+                let left = node.left_first as usize;
+                for child in [left, left + 1] {
+                    assert!(
+                        child > node_index,
+                        "child {child} is stored before its parent"
+                    );
+                    assert!(
+                        contains(node.bounds(), bvh.nodes[child].bounds()),
+                        "node {node_index} does not contain its child {child}"
+                    );
+                    stack.push((child, depth + 1));
+                }
+            }
+        }
+        for (index, count) in seen.into_iter().enumerate() {
+            assert_eq!(count, 1, "primitive {index} is in {count} leaves");
+        }
+    }
+
+    // Ids of the primitives whose bounds hold `point`, found by descending the tree
+    fn query(bvh: &Bvh, primitives: &[(PrimitiveRef, Aabb)], point: Vec3) -> Vec<u32> {
+        let inside = |bounds: Aabb| bounds.min.cmple(point).all() && bounds.max.cmpge(point).all();
+        let mut found = Vec::new();
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = bvh.nodes[node_index];
+            if !inside(node.bounds()) {
+                continue;
+            }
+            if node.is_leaf() {
+                let first = node.left_first as usize;
+                for i in first..first + node.primitive_count as usize {
+                    let index = bvh.source_indices[i] as usize;
+                    if inside(primitives[index].1) {
+                        found.push(bvh.primitives[i].primitive_id);
+                    }
+                }
+            } else if !primitives.is_empty() {
+                stack.extend([node.left_first as usize, node.left_first as usize + 1]);
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+
+    #[test]
+    fn every_primitive_is_in_one_leaf() {
+        for count in [1, 2, 5, 64, 500] {
+            let primitives = scattered_spheres(count);
+            assert_valid(&Bvh::build(&primitives), &primitives);
+        }
+    }
+
+    #[test]
+    fn degenerate_input_stays_within_the_depth_limit() {
+        // Spheres quadrupling their distance mostly fall into the first bin, so every split peels
+        // off one or two of them. Without the limit that chain ends up deeper than the traversal
+        // stack.
+        let chain: Vec<_> = (0..63)
+            .map(|i| sphere(i, Vec3::X * 4f32.powi(i as i32), 0.5))
+            .collect();
+        assert_valid(&Bvh::build(&chain), &chain);
+
+        // Identical spheres can not be split by position at all
+        let stacked: Vec<_> = (0..20).map(|i| sphere(i, Vec3::ZERO, 1.0)).collect();
+        assert_valid(&Bvh::build(&stacked), &stacked);
+    }
+
+    #[test]
+    fn empty_tree_has_an_empty_root() {
+        let mut bvh = Bvh::build(&[]);
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].bounds(), Aabb::EMPTY);
+        assert!(bvh.primitives.is_empty());
+
+        bvh.update(&[]);
+        assert_eq!(bvh.nodes[0].bounds(), Aabb::EMPTY);
+        assert_eq!(bvh.nodes[0].bounds().surface_area(), 0.0);
+    }
+
+    #[test]
+    fn refit_finds_what_a_rebuild_finds() {
+        let mut primitives = scattered_spheres(200);
+        let mut bvh = Bvh::build(&primitives);
+        let moved = 17;
+        let centre = Vec3::new(3.0, -2.0, 4.0);
+        primitives[moved] = sphere(moved as u32, centre, 0.5);
+
+        let nodes_before = bvh.nodes.len();
+        bvh.update(&primitives);
+        // A sphere moving inside the scene is refitted, not rebuilt
+        assert_eq!(bvh.nodes.len(), nodes_before);
+        assert_valid(&bvh, &primitives);
+
+        let rebuilt = Bvh::build(&primitives);
+        assert_eq!(bvh.nodes[0].bounds(), rebuilt.nodes[0].bounds());
+        assert!(query(&bvh, &primitives, centre).contains(&(moved as u32)));
+        for (_, bounds) in scattered_spheres(50) {
+            let point = bounds.centroid();
+            let brute_force: Vec<u32> = (0..primitives.len() as u32)
+                .filter(|&i| {
+                    let bounds = primitives[i as usize].1;
+                    bounds.min.cmple(point).all() && bounds.max.cmpge(point).all()
+                })
+                .collect();
+            assert_eq!(query(&bvh, &primitives, point), brute_force);
+            assert_eq!(query(&rebuilt, &primitives, point), brute_force);
+        }
+    }
+}
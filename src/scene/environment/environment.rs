@@ -0,0 +1,392 @@
+use std::{
+    f32::consts::PI,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::{
+    compute_shader::{
+        compute_buffers::{ENVIRONMENT_BUFFER, ENVIRONMENT_MAP_BUFFER},
+        lib::buffers_interface::ComputeBuffers,
+    },
+    scene::{loaders::load_error::SceneLoadError, scene::Scene},
+};
+
+// 128 MiB of `EnvironmentMap::gpu_texels`, the largest storage buffer binding every wgpu backend
+// allows
+pub const MAX_ENVIRONMENT_MAP_TEXELS: u32 = 4096 * 2048;
+
+// Degrees per press of [ and ]
+const ROTATION_STEP: f32 = 15.0;
+// Degrees the sun moves per press of , and .
+const SUN_ELEVATION_STEP: f32 = 5.0;
+const SUN_AZIMUTH_STEP: f32 = 15.0;
+
+pub struct EnvironmentPlugin;
+impl Plugin for EnvironmentPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<EnvironmentChangedEvent>();
+        app.add_systems(Update, (adjust_environment, adjust_time_of_day));
+        app.add_systems(
+            PostUpdate,
+            (update_environment_buffer, update_environment_map_buffers),
+        );
+    }
+}
+
+// Sent when the environment was edited without marking the `Scene` changed, which would rebuild
+// the BVH and mesh buffers for nothing. Restarts the accumulation.
+#[derive(Event)]
+pub struct EnvironmentChangedEvent();
+
+shader_types! {
+    // Gradient sky or environment map used for rays that leave the scene.
+    // Mirrors `struct Environment` in raytracer.wgsl.
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct Environment {
+        pub sky_horizon: Vec3,
+        pub intensity: f32,
+        pub sky_zenith: Vec3,
+        // Radians the map is turned by around +Y, from +Z towards +X
+        pub rotation: f32,
+        pub ground: Vec3,
+        // Size of the `Scene::environment_map` in texels, zero without one
+        pub map_width: u32,
+        pub map_height: u32,
+        // Written by `PhysicalSky::apply`, the sun has no size without a `Scene::sky`
+        pub sun_direction: Vec3,
+        pub sun_cos_radius: f32,
+        pub sun_radiance: Vec3,
+        pub perez_a: Vec3,
+        pub perez_b: Vec3,
+        pub perez_c: Vec3,
+        pub perez_d: Vec3,
+        pub perez_e: Vec3,
+        // Luminance and chromaticity at the zenith, divided by the Perez function there
+        pub sky_zenith_yxy: Vec3,
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::gradient(
+            Vec3::new(0.9, 1.0, 1.0),
+            Vec3::new(0.37, 0.47, 0.81),
+            Vec3::new(0.41, 0.39, 0.37),
+        )
+    }
+}
+
+impl Environment {
+    pub fn gradient(sky_horizon: Vec3, sky_zenith: Vec3, ground: Vec3) -> Self {
+        Environment {
+            sky_horizon,
+            intensity: 1.0,
+            sky_zenith,
+            rotation: 0.0,
+            ground,
+            map_width: 0,
+            map_height: 0,
+            sun_direction: Vec3::Y,
+            sun_cos_radius: 0.0,
+            sun_radiance: Vec3::ZERO,
+            perez_a: Vec3::ZERO,
+            perez_b: Vec3::ZERO,
+            perez_c: Vec3::ZERO,
+            perez_d: Vec3::ZERO,
+            perez_e: Vec3::ZERO,
+            sky_zenith_yxy: Vec3::ZERO,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity.max(0.0);
+        self
+    }
+
+    // Degrees
+    pub fn with_rotation(mut self, rotation: f32) -> Self {
+        self.rotation = rotation.to_radians();
+        self
+    }
+}
+
+// Equirectangular image looked up instead of the gradient, with +Z in the middle and longitude
+// growing towards +X like the equirectangular camera projection. The cumulative distributions
+// let the path tracer pick directions in proportion to how much light the texels send.
+#[derive(Debug, Clone)]
+pub struct EnvironmentMap {
+    // Absolute, saved scene files reference it relative to themselves
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    // Linear rgb in rows from the top, alpha is unused
+    pub pixels: Vec<Vec4>,
+    // Over the rows, the last entry is one
+    pub marginal_cdf: Vec<f32>,
+    // Over the texels of each row, `width` entries per row that each end at one
+    pub conditional_cdf: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    // Radiance .hdr or OpenEXR images
+    pub fn load(path: &Path) -> Result<Self, SceneLoadError> {
+        let image = image::open(path)
+            .map_err(|error| SceneLoadError::Image {
+                path: path.to_path_buf(),
+                error,
+            })?
+            .into_rgb32f();
+        if (image.width() + 1) * image.height() > MAX_ENVIRONMENT_MAP_TEXELS {
+            return Err(SceneLoadError::Invalid {
+                path: path.to_path_buf(),
+                message: format!(
+                    "the environment map has {}x{} texels, too many to fit on the GPU",
+                    image.width(),
+                    image.height()
+                ),
+            });
+        }
+
+        let pixels = image
+            .pixels()
+            .map(|pixel| Vec3::from(pixel.0).max(Vec3::ZERO).extend(1.0))
+            .collect();
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        Ok(EnvironmentMap::new(
+            path,
+            image.width(),
+            image.height(),
+            pixels,
+        ))
+    }
+
+    pub fn new(path: PathBuf, width: u32, height: u32, pixels: Vec<Vec4>) -> Self {
+        let mut conditional_cdf = Vec::with_capacity(pixels.len());
+        let mut row_weights = Vec::with_capacity(height as usize);
+        for (y, row) in pixels.chunks(width.max(1) as usize).enumerate() {
+            // Rows near the poles cover less of the sphere
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let weights: Vec<f32> = row
+                .iter()
+                .map(|pixel| luminance(pixel.truncate()) * sin_theta)
+                .collect();
+            row_weights.push(weights.iter().sum::<f32>());
+            conditional_cdf.extend(cumulative(&weights));
+        }
+
+        EnvironmentMap {
+            path,
+            width,
+            height,
+            pixels,
+            marginal_cdf: cumulative(&row_weights),
+            conditional_cdf,
+        }
+    }
+
+    // Packed into a single storage buffer, the compute stage is short on them. The texels come
+    // first with the conditional distribution in their alpha, followed by one entry per row with
+    // the marginal distribution in x.
+    pub fn gpu_texels(&self) -> Vec<Vec4> {
+        let texels = self
+            .pixels
+            .iter()
+            .zip(&self.conditional_cdf)
+            .map(|(pixel, cdf)| pixel.truncate().extend(*cdf));
+        let rows = self
+            .marginal_cdf
+            .iter()
+            .map(|cdf| Vec4::new(*cdf, 0.0, 0.0, 0.0));
+        texels.chain(rows).collect()
+    }
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// Running sums of `weights` divided by their total, uniform when they are all zero
+fn cumulative(weights: &[f32]) -> Vec<f32> {
+    let total: f32 = weights.iter().sum();
+    let count = weights.len() as f32;
+    let mut sum = 0.0;
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| {
+            sum += weight;
+            if total > 0.0 {
+                sum / total
+            } else {
+                (i + 1) as f32 / count
+            }
+        })
+        .collect()
+}
+
+// [ and ] turn the environment, with Shift held they halve and double its intensity
+fn adjust_environment(
+    input_keyboard: Res<Input<KeyCode>>,
+    mut scene: ResMut<Scene>,
+    mut ev_environment_changed: EventWriter<EnvironmentChangedEvent>,
+) {
+    let steps = input_keyboard.just_pressed(KeyCode::BracketRight) as i32
+        - input_keyboard.just_pressed(KeyCode::BracketLeft) as i32;
+    if steps == 0 {
+        return;
+    }
+
+    let shift = input_keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let environment = &mut scene.bypass_change_detection().environment;
+    if shift {
+        environment.intensity *= 2f32.powi(steps);
+        println!("environment intensity {}", environment.intensity);
+    } else {
+        let rotation = environment.rotation.to_degrees() + steps as f32 * ROTATION_STEP;
+        environment.rotation = rotation.rem_euclid(360.0).to_radians();
+        println!(
+            "environment rotated by {:.0} degrees",
+            rotation.rem_euclid(360.0)
+        );
+    }
+    ev_environment_changed.send(EnvironmentChangedEvent());
+}
+
+// , and . move the sun of the physical sky up and down, with Shift held they turn it around. Only
+// the sky coefficients change, the environment map buffers are left alone.
+fn adjust_time_of_day(
+    input_keyboard: Res<Input<KeyCode>>,
+    mut scene: ResMut<Scene>,
+    mut ev_environment_changed: EventWriter<EnvironmentChangedEvent>,
+) {
+    let steps = input_keyboard.just_pressed(KeyCode::Period) as i32
+        - input_keyboard.just_pressed(KeyCode::Comma) as i32;
+    if steps == 0 {
+        return;
+    }
+    let Some(mut sky) = scene.sky else {
+        return;
+    };
+
+    if input_keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        sky.sun_azimuth = (sky.sun_azimuth + steps as f32 * SUN_AZIMUTH_STEP).rem_euclid(360.0);
+    } else {
+        sky.sun_elevation =
+            (sky.sun_elevation + steps as f32 * SUN_ELEVATION_STEP).clamp(0.0, 90.0);
+    }
+    println!(
+        "sun at {:.0} degrees elevation and {:.0} degrees azimuth",
+        sky.sun_elevation, sky.sun_azimuth
+    );
+    scene.bypass_change_detection().set_sky(Some(sky));
+    ev_environment_changed.send(EnvironmentChangedEvent());
+}
+
+// Small enough to set every frame, only changed bytes are uploaded. This also picks up the edits
+// made without marking the scene changed.
+fn update_environment_buffer(scene: Res<Scene>, mut compute_buffers: ResMut<ComputeBuffers>) {
+    compute_buffers.set_value_at(ENVIRONMENT_BUFFER, scene.environment);
+}
+
+// Separate from the rest of the scene, a map is only uploaded when another one is loaded and not
+// every time something else in the scene changes
+fn update_environment_map_buffers(
+    scene: Res<Scene>,
+    mut compute_buffers: ResMut<ComputeBuffers>,
+    // The buffers start out empty, like they are without a map
+    mut uploaded: Local<Option<Arc<EnvironmentMap>>>,
+) {
+    let unchanged = match (&*uploaded, &scene.environment_map) {
+        (Some(uploaded), Some(map)) => Arc::ptr_eq(uploaded, map),
+        (None, None) => true,
+        _ => false,
+    };
+    if unchanged {
+        return;
+    }
+    *uploaded = scene.environment_map.clone();
+
+    let texels = scene
+        .environment_map
+        .as_ref()
+        .map(|map| map.gpu_texels())
+        .unwrap_or_default();
+    compute_buffers.set_value_at(ENVIRONMENT_MAP_BUFFER, texels);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 8;
+    const HEIGHT: u32 = 4;
+
+    fn map(pixels: Vec<Vec4>) -> EnvironmentMap {
+        EnvironmentMap::new(PathBuf::from("test.hdr"), WIDTH, HEIGHT, pixels)
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-5, "{value} is not {expected}");
+    }
+
+    #[test]
+    fn black_map_is_sampled_uniformly() {
+        let map = map(vec![Vec4::W; (WIDTH * HEIGHT) as usize]);
+        for (y, cdf) in map.marginal_cdf.iter().enumerate() {
+            assert_close(*cdf, (y + 1) as f32 / HEIGHT as f32);
+        }
+        for row in map.conditional_cdf.chunks(WIDTH as usize) {
+            for (x, cdf) in row.iter().enumerate() {
+                assert_close(*cdf, (x + 1) as f32 / WIDTH as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn every_row_distribution_ends_at_one() {
+        // A gradient with a black row, which falls back to uniform on its own
+        let pixels = (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                let value = if y == 2 { 0.0 } else { (x * y + 1) as f32 };
+                Vec3::splat(value).extend(1.0)
+            })
+            .collect();
+        let map = map(pixels);
+        assert_eq!(map.conditional_cdf.len(), (WIDTH * HEIGHT) as usize);
+        for row in map.conditional_cdf.chunks(WIDTH as usize) {
+            assert!(row.windows(2).all(|pair| pair[0] <= pair[1]), "{row:?}");
+            assert_close(row[WIDTH as usize - 1], 1.0);
+        }
+        assert_close(*map.marginal_cdf.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn bright_texel_takes_its_row() {
+        let bright = (1, 5);
+        let pixels = (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let value = if (i / WIDTH, i % WIDTH) == bright {
+                    1000.0
+                } else {
+                    0.01
+                };
+                Vec3::splat(value).extend(1.0)
+            })
+            .collect();
+        let map = map(pixels);
+
+        let row_probability = |y: usize| match y {
+            0 => map.marginal_cdf[0],
+            y => map.marginal_cdf[y] - map.marginal_cdf[y - 1],
+        };
+        assert!(row_probability(bright.0 as usize) > 0.99);
+
+        let row = &map.conditional_cdf[(bright.0 * WIDTH) as usize..][..WIDTH as usize];
+        let texel = row[bright.1 as usize] - row[bright.1 as usize - 1];
+        assert!(texel > 0.99, "{row:?}");
+    }
+}
@@ -0,0 +1,151 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+
+use crate::scene::environment::environment::Environment;
+
+// Radians, as seen from the ground
+const SUN_ANGULAR_RADIUS: f32 = 0.00465;
+// Preetham's luminance is in kcd/m², scaled down to the brightness the gradient sky and the lights
+// are tuned for
+const SKY_LUMINANCE_SCALE: f32 = 0.04;
+// Range the model was fitted over
+const MIN_TURBIDITY: f32 = 1.7;
+const MAX_TURBIDITY: f32 = 10.0;
+// Micrometres, the wavelengths the red, green and blue transmittance of the sun is taken at
+const WAVELENGTHS: Vec3 = Vec3::new(0.680, 0.550, 0.440);
+
+// Analytic daylight from "A Practical Analytic Model for Daylight" by Preetham, Shirley and Smits,
+// with a sun disc the path tracer samples directly. Only the Perez coefficients and the zenith
+// values depend on the parameters, they are worked out here whenever the sun moves and the shader
+// evaluates the sky from them for every ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalSky {
+    // Degrees above the horizon, the model does not go below it
+    pub sun_elevation: f32,
+    // Degrees from +Z towards +X
+    pub sun_azimuth: f32,
+    // Haziness of the atmosphere, 2 is a clear day and 10 a hazy one
+    pub turbidity: f32,
+    // Irradiance of the sun above the atmosphere, like the intensity of a directional light
+    pub sun_intensity: f32,
+}
+
+impl Default for PhysicalSky {
+    fn default() -> Self {
+        PhysicalSky::new(45.0, 30.0, 3.0)
+    }
+}
+
+impl PhysicalSky {
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32) -> Self {
+        PhysicalSky {
+            sun_elevation: sun_elevation.clamp(0.0, 90.0),
+            sun_azimuth,
+            turbidity: turbidity.clamp(MIN_TURBIDITY, MAX_TURBIDITY),
+            sun_intensity: 5.0,
+        }
+    }
+
+    pub fn with_sun_intensity(mut self, sun_intensity: f32) -> Self {
+        self.sun_intensity = sun_intensity.max(0.0);
+        self
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        let elevation = self.sun_elevation.clamp(0.0, 90.0).to_radians();
+        let azimuth = self.sun_azimuth.to_radians();
+        Vec3::new(
+            azimuth.sin() * elevation.cos(),
+            elevation.sin(),
+            azimuth.cos() * elevation.cos(),
+        )
+    }
+
+    // Writes what the shader evaluates the sky and the sun with into `environment`
+    pub fn apply(&self, environment: &mut Environment) {
+        let t = self.turbidity.clamp(MIN_TURBIDITY, MAX_TURBIDITY);
+        let theta_sun = (90.0 - self.sun_elevation.clamp(0.0, 90.0)).to_radians();
+
+        // Luminance Y and chromaticity x and y in each component
+        let perez = [
+            Vec3::new(
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ),
+            Vec3::new(
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ),
+            Vec3::new(
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ),
+            Vec3::new(
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ),
+            Vec3::new(
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ),
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let thetas = Vec4::new(theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0);
+        let chromaticity = |t2: Vec4, t1: Vec4, t0: Vec4| {
+            t * t * thetas.dot(t2) + t * thetas.dot(t1) + thetas.dot(t0)
+        };
+        let zenith_x = chromaticity(
+            Vec4::new(0.00166, -0.00375, 0.00209, 0.0),
+            Vec4::new(-0.02903, 0.06377, -0.03202, 0.00394),
+            Vec4::new(0.11693, -0.21196, 0.06052, 0.25886),
+        );
+        let zenith_y = chromaticity(
+            Vec4::new(0.00275, -0.00610, 0.00317, 0.0),
+            Vec4::new(-0.04214, 0.08970, -0.04153, 0.00516),
+            Vec4::new(0.15346, -0.26756, 0.06670, 0.26688),
+        );
+
+        // The Perez function looking straight up, the shader scales by how it differs from this
+        let [a, b, c, d, e] = perez;
+        let cos_theta_sun = theta_sun.cos();
+        let at_zenith = (Vec3::ONE + a * b.exp())
+            * (Vec3::ONE + c * (d * theta_sun).exp() + e * cos_theta_sun * cos_theta_sun);
+        let zenith = Vec3::new(zenith_luminance * SKY_LUMINANCE_SCALE, zenith_x, zenith_y);
+
+        // Radiance that makes the disc give `sun_intensity` times what gets through the atmosphere
+        let sun_cos_radius = SUN_ANGULAR_RADIUS.cos();
+        let solid_angle = 2.0 * PI * (1.0 - sun_cos_radius);
+        let sun_radiance = self.sun_intensity * self.transmittance(theta_sun) / solid_angle;
+
+        environment.sun_direction = self.sun_direction();
+        environment.sun_cos_radius = sun_cos_radius;
+        environment.sun_radiance = sun_radiance;
+        environment.perez_a = a;
+        environment.perez_b = b;
+        environment.perez_c = c;
+        environment.perez_d = d;
+        environment.perez_e = e;
+        environment.sky_zenith_yxy = zenith / at_zenith;
+    }
+
+    // Of sunlight through the atmosphere at `theta_sun` from the zenith, from Rayleigh and
+    // aerosol scattering. Preetham's ozone and water vapour absorption are left out.
+    fn transmittance(&self, theta_sun: f32) -> Vec3 {
+        let t = self.turbidity.clamp(MIN_TURBIDITY, MAX_TURBIDITY);
+        // Relative to straight up, grows towards the horizon
+        let optical_mass =
+            1.0 / (theta_sun.cos() + 0.15 * (93.885 - theta_sun.to_degrees()).powf(-1.253));
+        let rayleigh = 0.008735 * WAVELENGTHS.powf(-4.08);
+        let beta = 0.04608 * t - 0.04586;
+        let aerosol = beta * WAVELENGTHS.powf(-1.3);
+        (-optical_mass * (rayleigh + aerosol)).exp()
+    }
+}
@@ -0,0 +1,73 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+// Matches the LIGHT_* constants in raytracer.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightType {
+    Point = 0,
+    Directional = 1,
+    Spot = 2,
+}
+
+shader_types! {
+    // Punctual light following the KHR_lights_punctual conventions: point and spot intensities are
+    // in candela, directional ones in lux, and `direction` is where the light shines towards.
+    // Mirrors `struct Light` in raytracer.wgsl.
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct Light {
+        pub position: Vec3,
+        pub light_type: u32,
+        pub direction: Vec3,
+        // Distance at which the light is cut off, zero means unlimited
+        pub range: f32,
+        pub color: Vec3,
+        pub intensity: f32,
+        pub inner_cone_cos: f32,
+        pub outer_cone_cos: f32,
+    }
+}
+
+impl Light {
+    pub fn point(position: Vec3, color: Vec3, intensity: f32) -> Self {
+        Light {
+            position,
+            light_type: LightType::Point as u32,
+            direction: Vec3::NEG_Y,
+            range: 0.0,
+            color,
+            intensity,
+            inner_cone_cos: -1.0,
+            outer_cone_cos: -1.0,
+        }
+    }
+
+    pub fn directional(direction: Vec3, color: Vec3, intensity: f32) -> Self {
+        Light {
+            light_type: LightType::Directional as u32,
+            direction: direction.normalize(),
+            ..Light::point(Vec3::ZERO, color, intensity)
+        }
+    }
+
+    // Cone angles are measured from the axis, in radians
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        color: Vec3,
+        intensity: f32,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    ) -> Self {
+        Light {
+            light_type: LightType::Spot as u32,
+            direction: direction.normalize(),
+            inner_cone_cos: inner_cone_angle.cos(),
+            outer_cone_cos: outer_cone_angle.cos(),
+            ..Light::point(position, color, intensity)
+        }
+    }
+
+    pub fn with_range(mut self, range: f32) -> Self {
+        self.range = range.max(0.0);
+        self
+    }
+}
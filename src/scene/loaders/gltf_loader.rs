@@ -0,0 +1,204 @@
+use std::{collections::HashMap, path::Path};
+
+use bevy::prelude::*;
+use gltf::khr_lights_punctual::Kind;
+
+use super::load_error::SceneLoadError;
+use crate::{
+    camera::camera_update::SceneCamera,
+    scene::{
+        lights::light::Light,
+        materials::material::Material,
+        meshes::mesh::{TriangleMesh, RIGHT_TO_LEFT_HANDED},
+        scene::Scene,
+    },
+};
+
+struct GltfContext<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    // glTF material index (None for the default material) to scene material index
+    materials: HashMap<Option<usize>, u32>,
+    camera: Option<SceneCamera>,
+}
+
+// Adds the meshes, materials and punctual lights of the default glTF scene to `scene` and
+// returns the first camera found while walking the node hierarchy.
+pub fn load_gltf(path: &Path, scene: &mut Scene) -> Result<Option<SceneCamera>, SceneLoadError> {
+    let (document, buffers, images) = gltf::import(path).map_err(|error| SceneLoadError::Gltf {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    let gltf_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| SceneLoadError::Invalid {
+            path: path.to_path_buf(),
+            message: "the file contains no scene".to_string(),
+        })?;
+
+    let mut context = GltfContext {
+        buffers: &buffers,
+        images: &images,
+        materials: HashMap::new(),
+        camera: None,
+    };
+    for node in gltf_scene.nodes() {
+        load_node(&node, RIGHT_TO_LEFT_HANDED, &mut context, scene).map_err(|message| {
+            SceneLoadError::Invalid {
+                path: path.to_path_buf(),
+                message,
+            }
+        })?;
+    }
+
+    Ok(context.camera)
+}
+
+fn load_node(
+    node: &gltf::Node,
+    parent: Mat4,
+    context: &mut GltfContext,
+    scene: &mut Scene,
+) -> Result<(), String> {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives() {
+            if let Some(triangle_mesh) = load_primitive(&primitive, transform, context, scene) {
+                triangle_mesh.validate().map_err(|message| {
+                    let name = mesh.name().unwrap_or("unnamed");
+                    format!("mesh \"{name}\" {message}")
+                })?;
+                scene.add_mesh(triangle_mesh);
+            }
+        }
+    }
+
+    // Cameras and lights look down their local -Z axis
+    let position = transform.transform_point3(Vec3::ZERO);
+    let forward = transform.transform_vector3(Vec3::NEG_Z).normalize();
+
+    if let Some(camera) = node.camera().filter(|_| context.camera.is_none()) {
+        let up = transform.transform_vector3(Vec3::Y).normalize();
+        let mut scene_camera = SceneCamera::new(position, forward, up);
+        if let gltf::camera::Projection::Perspective(perspective) = camera.projection() {
+            scene_camera.vertical_fov = perspective.yfov().to_degrees();
+        }
+        context.camera = Some(scene_camera);
+    }
+
+    if let Some(light) = node.light() {
+        let color = Vec3::from(light.color());
+        let intensity = light.intensity();
+        let mut scene_light = match light.kind() {
+            Kind::Directional => Light::directional(forward, color, intensity),
+            Kind::Point => Light::point(position, color, intensity),
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => Light::spot(
+                position,
+                forward,
+                color,
+                intensity,
+                inner_cone_angle,
+                outer_cone_angle,
+            ),
+        };
+        if let Some(range) = light.range() {
+            scene_light = scene_light.with_range(range);
+        }
+        scene.add_light(scene_light);
+    }
+
+    for child in node.children() {
+        load_node(&child, transform, context, scene)?;
+    }
+    Ok(())
+}
+
+fn load_primitive(
+    primitive: &gltf::Primitive,
+    transform: Mat4,
+    context: &mut GltfContext,
+    scene: &mut Scene,
+) -> Option<TriangleMesh> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        println!(
+            "skipping glTF primitive with unsupported mode {:?}",
+            primitive.mode()
+        );
+        return None;
+    }
+
+    let reader = primitive.reader(|buffer| Some(&context.buffers[buffer.index()]));
+    let positions: Vec<Vec3> = reader.read_positions()?.map(Vec3::from).collect();
+    let normals = reader
+        .read_normals()
+        .map(|normals| normals.map(Vec3::from).collect())
+        .unwrap_or_default();
+
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+    let indices = indices
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect();
+
+    let material = primitive.material();
+    let material_index = *context
+        .materials
+        .entry(material.index())
+        .or_insert_with(|| scene.add_material(material_from_gltf(&material, context.images)));
+
+    Some(TriangleMesh::new(positions, normals, indices, material_index).transformed(transform))
+}
+
+fn material_from_gltf(material: &gltf::Material, images: &[gltf::image::Data]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let base_color = Vec4::from(pbr.base_color_factor()).truncate();
+
+    // Textures are not sampled by the raytracer, their average color keeps the overall look
+    let texture_tint = pbr
+        .base_color_texture()
+        .and_then(|info| images.get(info.texture().source().index()))
+        .and_then(average_color)
+        .unwrap_or(Vec3::ONE);
+
+    let emission =
+        Vec3::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0);
+    let transmission = material
+        .transmission()
+        .map_or(0.0, |transmission| transmission.transmission_factor());
+
+    Material::new(
+        base_color * texture_tint,
+        pbr.roughness_factor(),
+        pbr.metallic_factor(),
+    )
+    .with_emission(emission)
+    .with_transmission(transmission, material.ior().unwrap_or(1.5))
+}
+
+// Mean linear color of an 8 bit sRGB image, None for formats we do not read
+fn average_color(image: &gltf::image::Data) -> Option<Vec3> {
+    let channels = match image.format {
+        gltf::image::Format::R8G8B8 => 3,
+        gltf::image::Format::R8G8B8A8 => 4,
+        _ => return None,
+    };
+
+    let pixel_count = (image.pixels.len() / channels).max(1) as f32;
+    let sum = image
+        .pixels
+        .chunks_exact(channels)
+        .fold(Vec3::ZERO, |sum, pixel| {
+            let srgb = Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0;
+            sum + srgb.powf(2.2)
+        });
+    Some(sum / pixel_count)
+}
@@ -0,0 +1,117 @@
+use std::{fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum SceneLoadError {
+    UnsupportedFormat(PathBuf),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Obj {
+        path: PathBuf,
+        error: tobj::LoadError,
+    },
+    Gltf {
+        path: PathBuf,
+        error: gltf::Error,
+    },
+    // The environment map
+    Image {
+        path: PathBuf,
+        error: image::ImageError,
+    },
+    Ron {
+        path: PathBuf,
+        error: ron::error::SpannedError,
+    },
+    Json {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+    },
+    Invalid {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneLoadError::UnsupportedFormat(path) => {
+                write!(f, "{}: unsupported scene format", path.display())
+            }
+            SceneLoadError::Io { path, error } => {
+                write!(f, "{}: {error}", path.display())
+            }
+            SceneLoadError::Obj { path, error } => {
+                write!(f, "{}: failed to load OBJ: {error}", path.display())
+            }
+            SceneLoadError::Gltf { path, error } => {
+                write!(f, "{}: failed to load glTF: {error}", path.display())
+            }
+            SceneLoadError::Image { path, error } => {
+                write!(f, "{}: failed to load image: {error}", path.display())
+            }
+            SceneLoadError::Ron { path, error } => {
+                let position = error.position;
+                write!(
+                    f,
+                    "{}:{}:{}: {}",
+                    path.display(),
+                    position.line,
+                    position.col,
+                    error.code
+                )
+            }
+            SceneLoadError::Json { path, error } => {
+                write!(f, "{}: {error}", path.display())
+            }
+            SceneLoadError::UnsupportedVersion { path, version } => {
+                write!(
+                    f,
+                    "{}: scene file version {version} is newer than the supported version {}",
+                    path.display(),
+                    crate::scene::scene_file::SCENE_FILE_VERSION
+                )
+            }
+            SceneLoadError::Invalid { path, message } => {
+                write!(f, "{}: {message}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneLoadError {}
+
+#[derive(Debug)]
+pub enum SceneSaveError {
+    UnsupportedFormat(PathBuf),
+    Io { path: PathBuf, error: io::Error },
+    Ron(ron::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for SceneSaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneSaveError::UnsupportedFormat(path) => {
+                write!(
+                    f,
+                    "{}: scenes can only be saved as .ron or .json",
+                    path.display()
+                )
+            }
+            SceneSaveError::Io { path, error } => {
+                write!(f, "{}: {error}", path.display())
+            }
+            SceneSaveError::Ron(error) => write!(f, "failed to serialize scene: {error}"),
+            SceneSaveError::Json(error) => write!(f, "failed to serialize scene: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for SceneSaveError {}
@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use bevy::prelude::*;
+
+use super::load_error::SceneLoadError;
+use crate::scene::{
+    materials::material::Material,
+    meshes::mesh::{TriangleMesh, RIGHT_TO_LEFT_HANDED},
+    scene::Scene,
+};
+
+// Adds every object of a Wavefront OBJ file and the materials of its MTL libraries to `scene`
+pub fn load_obj(path: &Path, scene: &mut Scene) -> Result<(), SceneLoadError> {
+    let (models, materials) =
+        tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS).map_err(|error| SceneLoadError::Obj {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+    // A missing or broken MTL file is not fatal, the affected meshes get the default material
+    let materials = materials.unwrap_or_else(|error| {
+        println!("{}: failed to load MTL: {error}", path.display());
+        Vec::new()
+    });
+    let material_indices: Vec<u32> = materials
+        .iter()
+        .map(|material| scene.add_material(material_from_mtl(material)))
+        .collect();
+    let mut default_material = None;
+
+    for model in models {
+        let mesh = model.mesh;
+        let material_index = match mesh.material_id.and_then(|id| material_indices.get(id)) {
+            Some(&index) => index,
+            None => {
+                *default_material.get_or_insert_with(|| scene.add_material(Material::default()))
+            }
+        };
+
+        let positions = mesh
+            .positions
+            .chunks_exact(3)
+            .map(Vec3::from_slice)
+            .collect();
+        let normals = mesh
+            .normals
+            .chunks_exact(3)
+            .map(|normal| Vec3::from_slice(normal).normalize_or_zero())
+            .collect();
+        let indices = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect();
+
+        let mesh = TriangleMesh::new(positions, normals, indices, material_index);
+        mesh.validate().map_err(|message| SceneLoadError::Invalid {
+            path: path.to_path_buf(),
+            message: format!("object \"{}\" {message}", model.name),
+        })?;
+        scene.add_mesh(mesh.transformed(RIGHT_TO_LEFT_HANDED));
+    }
+
+    Ok(())
+}
+
+// Maps the classic MTL parameters and the PBR extension (Pr, Pm) onto our material model
+fn material_from_mtl(mtl: &tobj::Material) -> Material {
+    let unknown = |key: &str| {
+        mtl.unknown_param
+            .get(key)
+            .and_then(|value| value.trim().parse::<f32>().ok())
+    };
+
+    let albedo = Vec3::from(mtl.diffuse.unwrap_or([0.8; 3]));
+    // Blinn-Phong exponent to roughness, as used by most exporters
+    let roughness = unknown("Pr").unwrap_or_else(|| {
+        mtl.shininess
+            .map_or(1.0, |shininess| (2.0 / (shininess.max(0.0) + 2.0)).sqrt())
+    });
+    let metallic = unknown("Pm").unwrap_or(0.0);
+    let transmission = unknown("Tr").unwrap_or(1.0 - mtl.dissolve.unwrap_or(1.0));
+
+    Material::new(albedo, roughness, metallic)
+        .with_emission(Vec3::from(mtl.emissive.unwrap_or([0.0; 3])))
+        .with_transmission(transmission, mtl.optical_density.unwrap_or(1.5))
+}
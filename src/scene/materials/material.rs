@@ -0,0 +1,51 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+shader_types! {
+    // Mirrors `struct Material` in raytracer.wgsl
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct Material {
+        pub albedo: Vec3,
+        pub roughness: f32,
+        pub emission: Vec3,
+        pub metallic: f32,
+        pub ior: f32,
+        pub transmission: f32,
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material::new(Vec3::splat(0.8), 1.0, 0.0)
+    }
+}
+
+impl Material {
+    pub fn new(albedo: Vec3, roughness: f32, metallic: f32) -> Self {
+        Material {
+            albedo,
+            roughness: roughness.clamp(0.0, 1.0),
+            emission: Vec3::ZERO,
+            metallic: metallic.clamp(0.0, 1.0),
+            ior: 1.5,
+            transmission: 0.0,
+        }
+    }
+
+    pub fn with_emission(mut self, emission: Vec3) -> Self {
+        self.emission = emission.max(Vec3::ZERO);
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: f32, ior: f32) -> Self {
+        self.transmission = transmission.clamp(0.0, 1.0);
+        self.ior = ior.max(1.0);
+        self
+    }
+}
+
+pub fn init_materials() -> Vec<Material> {
+    vec![
+        Material::new(Vec3::new(0.8, 0.2, 0.2), 1.0, 0.0),
+        Material::new(Vec3::new(0.9, 0.9, 0.9), 0.1, 1.0),
+    ]
+}
@@ -0,0 +1,159 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::scene::{bvh::bvh::Aabb, motion::Motion};
+
+// OBJ and glTF are right handed, the raytracer looks down +Z with +X to the right, so Z gets
+// mirrored
+pub const RIGHT_TO_LEFT_HANDED: Mat4 = Mat4::from_cols(Vec4::X, Vec4::Y, Vec4::NEG_Z, Vec4::W);
+
+#[derive(Debug, Clone, Default)]
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    // One per position, left empty for flat shading with the geometric normal
+    pub normals: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+    pub material_index: u32,
+    // The whole mesh moves as one while the camera's shutter is open
+    pub motion: Motion,
+}
+
+shader_types! {
+    // Mirrors `struct Triangle` in raytracer.wgsl, `indices` are global into the vertex and normal
+    // buffers
+    #[derive(Debug, Clone, Copy, ShaderType)]
+    pub struct Triangle {
+        pub indices: UVec3,
+        pub material_index: u32,
+        // Into the motions of the meshes, one per mesh
+        pub mesh_index: u32,
+    }
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        indices: Vec<[u32; 3]>,
+        material_index: u32,
+    ) -> Self {
+        TriangleMesh {
+            positions,
+            normals,
+            indices,
+            material_index,
+            motion: Motion::STATIC,
+        }
+    }
+
+    // Loaders check what they read with this, the buffers are indexed without bounds checks
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.normals.is_empty() && self.normals.len() != self.positions.len() {
+            return Err("needs either no normals or one per position".to_string());
+        }
+        let vertex_count = self.positions.len() as u32;
+        if self
+            .indices
+            .iter()
+            .flatten()
+            .any(|&index| index >= vertex_count)
+        {
+            return Err("indexes past its positions".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn with_motion(mut self, motion: Motion) -> Self {
+        self.motion = motion;
+        self
+    }
+
+    // Centre of the bounds of the positions, what motions of a whole mesh turn around by default
+    pub fn centre(&self) -> Vec3 {
+        self.positions
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, &position| aabb.grow(position))
+            .centroid()
+    }
+
+    // Two triangles spanning the corners, which are given counter clockwise seen from the front
+    pub fn quad(corners: [Vec3; 4], material_index: u32) -> Self {
+        TriangleMesh::new(
+            corners.to_vec(),
+            Vec::new(),
+            vec![[0, 1, 2], [0, 2, 3]],
+            material_index,
+        )
+    }
+
+    pub fn transformed(mut self, transform: Mat4) -> Self {
+        let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+        for position in self.positions.iter_mut() {
+            *position = transform.transform_point3(*position);
+        }
+        for normal in self.normals.iter_mut() {
+            *normal = (normal_matrix * *normal).normalize_or_zero();
+        }
+
+        // Mirroring reverses the winding, swap it back so the front faces keep pointing outward
+        if transform.determinant() < 0.0 {
+            for triangle in self.indices.iter_mut() {
+                triangle.swap(1, 2);
+            }
+        }
+        self
+    }
+
+    // Everywhere the triangle is while the camera's shutter is open
+    pub fn triangle_aabb(&self, triangle_index: usize) -> Aabb {
+        self.motion.swept_bounds(
+            self.indices[triangle_index]
+                .iter()
+                .fold(Aabb::EMPTY, |aabb, &index| {
+                    aabb.grow(self.positions[index as usize])
+                }),
+        )
+    }
+}
+
+// The flattened vertex, normal and index buffers of every mesh in a scene and the motion of each
+// mesh. The resource holds what was uploaded last.
+#[derive(Resource, Default)]
+pub struct MeshBuffers {
+    pub vertices: Vec<Vec4>,
+    pub normals: Vec<Vec4>,
+    pub triangles: Vec<Triangle>,
+    pub motions: Vec<Motion>,
+}
+
+impl MeshBuffers {
+    pub fn new(meshes: &[TriangleMesh]) -> Self {
+        let mut buffers = MeshBuffers::default();
+        for (mesh_index, mesh) in meshes.iter().enumerate() {
+            let offset = buffers.vertices.len() as u32;
+            buffers
+                .vertices
+                .extend(mesh.positions.iter().map(|position| position.extend(1.0)));
+
+            // Zero normals make the shader fall back to the geometric normal
+            if mesh.normals.is_empty() {
+                buffers
+                    .normals
+                    .extend(std::iter::repeat_n(Vec4::ZERO, mesh.positions.len()));
+            } else {
+                buffers
+                    .normals
+                    .extend(mesh.normals.iter().map(|normal| normal.extend(0.0)));
+            }
+
+            buffers
+                .triangles
+                .extend(mesh.indices.iter().map(|indices| Triangle {
+                    indices: UVec3::from_array(*indices) + offset,
+                    material_index: mesh.material_index,
+                    mesh_index: mesh_index as u32,
+                }));
+            buffers.motions.push(mesh.motion);
+        }
+        buffers
+    }
+}
@@ -0,0 +1,126 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::scene::bvh::bvh::Aabb;
+
+shader_types! {
+    // Mirrors `struct MotionTransform` in raytracer.wgsl, scales and rotates around the pivot of
+    // the `Motion` it belongs to before translating
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct MotionTransform {
+        pub translation: Vec3,
+        pub scale: Vec3,
+        // Quaternion as xyzw, `Quat` has no shader layout
+        pub rotation: Vec4,
+    }
+
+    // Mirrors `struct Motion` in raytracer.wgsl. Where a primitive is while the camera's shutter
+    // is open, `start` applies when it opens and `end` when it closes, samples in between blend
+    // the two.
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct Motion {
+        pub pivot: Vec3,
+        pub start: MotionTransform,
+        pub end: MotionTransform,
+    }
+}
+
+impl MotionTransform {
+    pub const IDENTITY: MotionTransform = MotionTransform {
+        translation: Vec3::ZERO,
+        scale: Vec3::ONE,
+        rotation: Vec4::W,
+    };
+
+    pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        MotionTransform {
+            translation,
+            scale,
+            rotation: Vec4::from(rotation.normalize()),
+        }
+    }
+
+    pub fn quat(&self) -> Quat {
+        Quat::from_vec4(self.rotation)
+    }
+
+    // Translation and scale blend linearly, rotation along the shorter arc
+    pub fn lerp(&self, other: &MotionTransform, fraction: f32) -> MotionTransform {
+        MotionTransform {
+            translation: self.translation.lerp(other.translation, fraction),
+            scale: self.scale.lerp(other.scale, fraction),
+            rotation: Vec4::from(self.quat().slerp(other.quat(), fraction)),
+        }
+    }
+}
+
+impl Default for MotionTransform {
+    fn default() -> Self {
+        MotionTransform::IDENTITY
+    }
+}
+
+impl Motion {
+    // Stays where its geometry puts it
+    pub const STATIC: Motion = Motion {
+        pivot: Vec3::ZERO,
+        start: MotionTransform::IDENTITY,
+        end: MotionTransform::IDENTITY,
+    };
+
+    pub fn new(pivot: Vec3, start: MotionTransform, end: MotionTransform) -> Self {
+        Motion { pivot, start, end }
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.start == MotionTransform::IDENTITY && self.end == MotionTransform::IDENTITY
+    }
+
+    // `fraction` of the way from the shutter opening to it closing, `motion_at` in raytracer.wgsl
+    pub fn transform_at(&self, fraction: f32) -> MotionTransform {
+        self.start.lerp(&self.end, fraction)
+    }
+
+    pub fn transform_point(&self, transform: &MotionTransform, point: Vec3) -> Vec3 {
+        self.pivot
+            + transform.translation
+            + transform.quat() * (transform.scale * (point - self.pivot))
+    }
+
+    // Everywhere `bounds` is taken while the shutter is open. Without a change of rotation every
+    // point moves in a straight line and the boxes at both ends cover the way in between. Turning
+    // ones are bounded by the sphere around the pivot they stay inside of instead.
+    pub fn swept_bounds(&self, bounds: Aabb) -> Aabb {
+        if self.start.rotation == self.end.rotation {
+            let corners = |transform: MotionTransform| {
+                (0..8).map(move |i| {
+                    let corner = Vec3::select(
+                        BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
+                        bounds.max,
+                        bounds.min,
+                    );
+                    self.transform_point(&transform, corner)
+                })
+            };
+            return corners(self.start)
+                .chain(corners(self.end))
+                .fold(Aabb::EMPTY, Aabb::grow);
+        }
+
+        let radius = (bounds.min - self.pivot)
+            .abs()
+            .max((bounds.max - self.pivot).abs())
+            .length();
+        let ball = |transform: &MotionTransform| {
+            let centre = self.pivot + transform.translation;
+            let extent = Vec3::splat(radius * transform.scale.abs().max_element());
+            Aabb::new(centre - extent, centre + extent)
+        };
+        ball(&self.start).union(ball(&self.end))
+    }
+}
+
+impl Default for Motion {
+    fn default() -> Self {
+        Motion::STATIC
+    }
+}
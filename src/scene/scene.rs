@@ -1,42 +1,416 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
 use bevy::prelude::*;
 
-use crate::{scene::spheres::sphere::*, BufferType, ComputeBuffers};
+use crate::{
+    camera::{
+        camera_path::{ActiveCameraPath, CameraPath},
+        camera_update::SceneCamera,
+    },
+    compute_shader::render_settings::RenderSettings,
+    scene::{
+        animation::{MaterialAnimation, SphereAnimation},
+        bvh::bvh::*,
+        environment::{
+            environment::{Environment, EnvironmentMap},
+            sky::PhysicalSky,
+        },
+        lights::light::Light,
+        loaders::{
+            gltf_loader::load_gltf,
+            load_error::{SceneLoadError, SceneSaveError},
+            obj_loader::load_obj,
+        },
+        materials::material::{init_materials, Material},
+        meshes::mesh::*,
+        scene_file::{SceneFile, SceneFileFormat},
+        spheres::sphere::*,
+    },
+    ComputeBuffers, ErrorOverlay, BVH_NODES_BUFFER, BVH_PRIMITIVES_BUFFER, LIGHTS_BUFFER,
+    MATERIALS_BUFFER, MESH_MOTIONS_BUFFER, NORMALS_BUFFER, SPHERES_BUFFER, TRIANGLES_BUFFER,
+    VERTICES_BUFFER,
+};
 
 pub struct ScenePlugin;
 impl Plugin for ScenePlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Scene>();
-        app.add_systems(Update, update_scene_buffers);
+        app.init_resource::<Bvh>();
+        app.init_resource::<MeshBuffers>();
+        // Embedders can insert their own path before adding the plugin
+        if !app.world.contains_resource::<ScenePath>() {
+            app.insert_resource(ScenePath(std::env::args().nth(1).map(PathBuf::from)));
+        }
+        app.add_systems(Startup, load_scene_from_args);
+        app.add_systems(Update, save_scene_on_shortcut);
+        // PostUpdate so a scene animated or moved to another frame during Update is uploaded
+        // together with the camera and clock of that frame
+        app.add_systems(PostUpdate, update_scene_buffers);
     }
 }
 
 #[derive(Resource, Clone)]
 pub struct Scene {
     pub spheres: Vec<Sphere>,
+    pub materials: Vec<Material>,
+    pub meshes: Vec<TriangleMesh>,
+    pub lights: Vec<Light>,
+    pub environment: Environment,
+    // Shared between clones of the scene, it is only uploaded again when it is replaced
+    pub environment_map: Option<Arc<EnvironmentMap>>,
+    // Replaces the gradient when there is no environment map, set with `set_sky`
+    pub sky: Option<PhysicalSky>,
+    // Keyframes `animate` moves the spheres and materials along
+    pub sphere_animations: Vec<SphereAnimation>,
+    pub material_animations: Vec<MaterialAnimation>,
+    // Viewpoint and settings stored with the scene file, applied to the `SceneCamera` and
+    // `RenderSettings` resources when the scene is loaded
+    pub camera: Option<SceneCamera>,
+    // Played back with Space in the viewer and rendered frame by frame by `candela-render --frames`
+    pub camera_path: Option<CameraPath>,
+    pub render_settings: Option<RenderSettings>,
 }
 
+// File the scene was loaded from, if any
+#[derive(Resource, Clone, Default)]
+pub struct ScenePath(pub Option<PathBuf>);
+
+pub const SCENE_ERROR_SOURCE: &str = "scene";
+
+// Where Ctrl+S writes the scene when it was not loaded from a file
+const DEFAULT_SAVE_PATH: &str = "scene.saved.ron";
+
 impl Default for Scene {
     fn default() -> Self {
-        Scene {
+        let mut scene = Scene {
             spheres: init_spheres(),
-        }
+            materials: init_materials(),
+            meshes: Vec::new(),
+            lights: Vec::new(),
+            environment: Environment::default(),
+            environment_map: None,
+            sky: None,
+            sphere_animations: Vec::new(),
+            material_animations: Vec::new(),
+            camera: None,
+            camera_path: None,
+            render_settings: None,
+        };
+
+        let ground = scene.add_material(Material::new(Vec3::splat(0.6), 1.0, 0.0));
+        scene.add_mesh(TriangleMesh::quad(
+            [
+                Vec3::new(-20.0, -2.0, -5.0),
+                Vec3::new(-20.0, -2.0, 30.0),
+                Vec3::new(20.0, -2.0, 30.0),
+                Vec3::new(20.0, -2.0, -5.0),
+            ],
+            ground,
+        ));
+
+        // Both lights share one emissive material
+        let light = scene.add_material(Material::default().with_emission(Vec3::splat(5.0)));
+        scene.add_sphere(Sphere::new(Vec3::new(-2.5, 1.5, 4.0), 0.5, light));
+        scene.add_sphere(Sphere::new(Vec3::new(1.5, 3.0, 6.0), 0.5, light));
+
+        let glass =
+            scene.add_material(Material::new(Vec3::ONE, 0.0, 0.0).with_transmission(1.0, 1.5));
+        scene.add_sphere(Sphere::new(Vec3::new(-2.0, -0.5, 6.0), 0.75, glass));
+
+        scene
     }
 }
 
 impl Scene {
+    pub fn empty() -> Self {
+        Scene {
+            spheres: Vec::new(),
+            materials: Vec::new(),
+            meshes: Vec::new(),
+            lights: Vec::new(),
+            environment: Environment::default(),
+            environment_map: None,
+            sky: None,
+            sphere_animations: Vec::new(),
+            material_animations: Vec::new(),
+            camera: None,
+            camera_path: None,
+            render_settings: None,
+        }
+    }
+
+    // Picks the loader from the file extension
+    pub fn load(path: &Path) -> Result<Scene, SceneLoadError> {
+        Scene::load_model(path, &mut Vec::new())
+    }
+
+    // `loading` lists the scene files whose models are being loaded, to catch files that end up
+    // including themselves
+    pub fn load_model(path: &Path, loading: &mut Vec<PathBuf>) -> Result<Scene, SceneLoadError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        if let Some(format) = SceneFileFormat::from_path(path) {
+            return SceneFile::read(path, format)?.into_scene(path, loading);
+        }
+
+        let mut scene = Scene::empty();
+        match extension.as_deref() {
+            Some("obj") => load_obj(path, &mut scene)?,
+            Some("gltf" | "glb") => scene.camera = load_gltf(path, &mut scene)?,
+            _ => return Err(SceneLoadError::UnsupportedFormat(path.to_path_buf())),
+        }
+        Ok(scene)
+    }
+
+    // Writes a .ron or .json scene file, meshes are stored inline
+    pub fn save(&self, path: &Path) -> Result<(), SceneSaveError> {
+        let format = SceneFileFormat::from_path(path)
+            .ok_or_else(|| SceneSaveError::UnsupportedFormat(path.to_path_buf()))?;
+        SceneFile::from_scene(self, path).write(path, format)
+    }
+
+    // Appends the contents of `other`, keeping this scene's camera, environment and settings
+    pub fn merge(&mut self, other: Scene) {
+        let material_offset = self.materials.len() as u32;
+        let sphere_offset = self.spheres.len() as u32;
+        self.sphere_animations
+            .extend(other.sphere_animations.into_iter().map(|mut animation| {
+                animation.sphere_index += sphere_offset;
+                animation
+            }));
+        self.material_animations
+            .extend(other.material_animations.into_iter().map(|mut animation| {
+                animation.material_index += material_offset;
+                animation
+            }));
+        self.materials.extend(other.materials);
+        self.spheres
+            .extend(other.spheres.into_iter().map(|mut sphere| {
+                sphere.material_index += material_offset;
+                sphere
+            }));
+        self.meshes.extend(other.meshes.into_iter().map(|mut mesh| {
+            mesh.material_index += material_offset;
+            mesh
+        }));
+        self.lights.extend(other.lights);
+    }
+
+    // Keeps the size the shader reads the map with in sync. A map replaces the sky.
+    pub fn set_environment_map(&mut self, map: Option<Arc<EnvironmentMap>>) {
+        if map.is_some() {
+            self.set_sky(None);
+        }
+        let size = map
+            .as_ref()
+            .map_or(UVec2::ZERO, |map| UVec2::new(map.width, map.height));
+        self.environment.map_width = size.x;
+        self.environment.map_height = size.y;
+        self.environment_map = map;
+    }
+
+    // Keeps the sky coefficients the shader reads in sync. A sky replaces the environment map.
+    pub fn set_sky(&mut self, sky: Option<PhysicalSky>) {
+        match &sky {
+            Some(sky) => {
+                self.set_environment_map(None);
+                sky.apply(&mut self.environment);
+            }
+            None => self.environment.sun_cos_radius = 0.0,
+        }
+        self.sky = sky;
+    }
+
+    // Moves the animated spheres and materials to where their keyframes put them at `time`, the
+    // spheres' motion follows the keyframes over `camera`'s shutter. Returns whether any changed
+    pub fn animate(&mut self, time: f32, camera: &SceneCamera) -> bool {
+        let mut changed = false;
+        for animation in &self.sphere_animations {
+            if let Some(sphere) = self.spheres.get_mut(animation.sphere_index as usize) {
+                let previous = *sphere;
+                animation.apply(sphere, time, camera.shutter_open, camera.shutter_close);
+                changed |= *sphere != previous;
+            }
+        }
+        for animation in &self.material_animations {
+            if let Some(material) = self.materials.get_mut(animation.material_index as usize) {
+                let previous = *material;
+                animation.apply(material, time);
+                changed |= *material != previous;
+            }
+        }
+        changed
+    }
+
+    // Time of the last keyframe, None when nothing is animated
+    pub fn animation_end_time(&self) -> Option<f32> {
+        let sphere_end_times = self.sphere_animations.iter().map(SphereAnimation::end_time);
+        let material_end_times = self
+            .material_animations
+            .iter()
+            .map(MaterialAnimation::end_time);
+        sphere_end_times.chain(material_end_times).reduce(f32::max)
+    }
+
     pub fn add_sphere(&mut self, sphere: Sphere) {
         self.spheres.push(sphere);
     }
+
+    pub fn add_mesh(&mut self, mesh: TriangleMesh) {
+        self.meshes.push(mesh);
+    }
+
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    // Returns the index to pass as `material_index`, any number of primitives can share it.
+    pub fn add_material(&mut self, material: Material) -> u32 {
+        self.materials.push(material);
+        (self.materials.len() - 1) as u32
+    }
+
+    // Every primitive the BVH is built over, together with its bounds while the shutter is open.
+    // Triangles are numbered globally in mesh order, matching `MeshBuffers`
+    pub fn primitive_bounds(&self) -> Vec<(PrimitiveRef, Aabb)> {
+        let spheres = self.spheres.iter().enumerate().map(|(i, sphere)| {
+            (
+                PrimitiveRef::new(PrimitiveType::Sphere, i as u32),
+                sphere.aabb(),
+            )
+        });
+        let triangles = self
+            .meshes
+            .iter()
+            .flat_map(|mesh| (0..mesh.indices.len()).map(move |i| mesh.triangle_aabb(i)))
+            .enumerate()
+            .map(|(i, aabb)| (PrimitiveRef::new(PrimitiveType::Triangle, i as u32), aabb));
+
+        spheres.chain(triangles).collect()
+    }
+}
+
+// `candela path/to/scene.ron` (or .json/.obj/.gltf/.glb) replaces the default scene
+fn load_scene_from_args(
+    scene_path: Res<ScenePath>,
+    mut scene: ResMut<Scene>,
+    mut camera: ResMut<SceneCamera>,
+    mut settings: ResMut<RenderSettings>,
+    mut camera_path: ResMut<ActiveCameraPath>,
+    mut overlay: ResMut<ErrorOverlay>,
+) {
+    let Some(path) = &scene_path.0 else {
+        return;
+    };
+
+    match Scene::load(path) {
+        Ok(loaded) => apply_loaded_scene(
+            loaded,
+            &mut scene,
+            &mut camera,
+            &mut settings,
+            &mut camera_path,
+        ),
+        Err(error) => {
+            println!("{error}");
+            overlay.set(SCENE_ERROR_SOURCE, error.to_string());
+        }
+    }
+}
+
+// The camera, settings and camera path stored in the file only override the live ones when the
+// file changed them, so reloading an edited material does not undo the user's navigation.
+pub fn apply_loaded_scene(
+    loaded: Scene,
+    scene: &mut Scene,
+    camera: &mut SceneCamera,
+    settings: &mut RenderSettings,
+    camera_path: &mut ActiveCameraPath,
+) {
+    if let Some(loaded_camera) = loaded.camera.filter(|c| Some(*c) != scene.camera) {
+        *camera = loaded_camera;
+    }
+    if let Some(loaded_settings) = loaded
+        .render_settings
+        .filter(|s| Some(*s) != scene.render_settings)
+    {
+        *settings = loaded_settings;
+    }
+    if loaded.camera_path != scene.camera_path {
+        camera_path.0 = loaded.camera_path.clone();
+    }
+    *scene = loaded;
+}
+
+// Ctrl+S saves the current view to `<stem>.saved.ron` (or .json) next to the loaded file. Saving
+// renames materials, drops comments and inlines imported models, so the file the scene was loaded
+// from is never overwritten, only an earlier save of it.
+fn save_scene_on_shortcut(
+    input_keyboard: Res<Input<KeyCode>>,
+    scene_path: Res<ScenePath>,
+    scene: Res<Scene>,
+    camera: Res<SceneCamera>,
+    settings: Res<RenderSettings>,
+) {
+    let control = input_keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if !control || !input_keyboard.just_pressed(KeyCode::S) {
+        return;
+    }
+
+    let path = scene_path
+        .0
+        .as_deref()
+        .map(saved_scene_path)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SAVE_PATH));
+
+    let mut saved = scene.clone();
+    saved.camera = Some(*camera);
+    saved.render_settings = Some(*settings);
+    match saved.save(&path) {
+        Ok(()) => println!("saved scene to {}", path.display()),
+        Err(error) => println!("{error}"),
+    }
 }
 
-fn update_scene_buffers(
-    mut commands: Commands,
+fn saved_scene_path(loaded: &Path) -> PathBuf {
+    let extension = match SceneFileFormat::from_path(loaded) {
+        Some(SceneFileFormat::Json) => "json",
+        _ => "ron",
+    };
+    let stem = loaded
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("scene");
+    let stem = stem.strip_suffix(".saved").unwrap_or(stem);
+    loaded.with_file_name(format!("{stem}.saved.{extension}"))
+}
+
+pub fn update_scene_buffers(
     scene: Res<Scene>,
+    mut bvh: ResMut<Bvh>,
+    mut mesh_buffers: ResMut<MeshBuffers>,
     mut compute_buffers: ResMut<ComputeBuffers>,
 ) {
-    compute_buffers.set_value_at(
-        BufferType::Spheres as u32,
-        scene.spheres.clone(),
-        &mut commands,
-    );
+    if !scene.is_changed() {
+        return;
+    }
+
+    bvh.update(&scene.primitive_bounds());
+    compute_buffers.set_value_at(BVH_NODES_BUFFER, bvh.nodes.clone());
+    compute_buffers.set_value_at(BVH_PRIMITIVES_BUFFER, bvh.primitives.clone());
+    compute_buffers.set_value_at(SPHERES_BUFFER, scene.spheres.clone());
+    compute_buffers.set_value_at(MATERIALS_BUFFER, scene.materials.clone());
+    compute_buffers.set_value_at(LIGHTS_BUFFER, scene.lights.clone());
+
+    *mesh_buffers = MeshBuffers::new(&scene.meshes);
+    compute_buffers.set_value_at(VERTICES_BUFFER, mesh_buffers.vertices.clone());
+    compute_buffers.set_value_at(NORMALS_BUFFER, mesh_buffers.normals.clone());
+    compute_buffers.set_value_at(TRIANGLES_BUFFER, mesh_buffers.triangles.clone());
+    compute_buffers.set_value_at(MESH_MOTIONS_BUFFER, mesh_buffers.motions.clone());
 }
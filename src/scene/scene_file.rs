@@ -0,0 +1,1352 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::{
+        camera_path::{CameraKeyframe, CameraPath},
+        camera_update::{CameraProjection, SceneCamera},
+    },
+    compute_shader::render_settings::{DebugView, RenderSettings},
+    scene::{
+        animation::{MaterialAnimation, SphereAnimation, Track},
+        environment::{
+            environment::{Environment, EnvironmentMap},
+            sky::PhysicalSky,
+        },
+        lights::light::{Light, LightType},
+        loaders::load_error::{SceneLoadError, SceneSaveError},
+        materials::material::Material,
+        meshes::mesh::TriangleMesh,
+        motion::{Motion, MotionTransform},
+        scene::Scene,
+        spheres::sphere::Sphere,
+    },
+};
+
+// Bump when a change to the format can not be read by older versions
+pub const SCENE_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneFileFormat {
+    Ron,
+    Json,
+}
+
+impl SceneFileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ron" => Some(SceneFileFormat::Ron),
+            "json" => Some(SceneFileFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// On disk description of a scene. Vectors are written as plain [x, y, z] triples and materials
+// are referenced by name, so files stay readable and editable by hand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_path: Option<CameraPathDesc>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    pub spheres: Vec<SphereDesc>,
+    #[serde(default)]
+    pub meshes: Vec<MeshDesc>,
+    // OBJ or glTF files merged into the scene, relative to the scene file
+    #[serde(default)]
+    pub models: Vec<PathBuf>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    #[serde(default)]
+    pub environment: EnvironmentDesc,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub render: Option<RenderSettingsDesc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub position: [f32; 3],
+    pub front: [f32; 3],
+    #[serde(default = "default_up")]
+    pub up: [f32; 3],
+    #[serde(default)]
+    pub projection: ProjectionDesc,
+    #[serde(default = "default_vertical_fov")]
+    pub vertical_fov: f32,
+    #[serde(default = "default_orthographic_height")]
+    pub orthographic_height: f32,
+    #[serde(default = "default_sensor_height")]
+    pub sensor_height: f32,
+    // Millimeters, replaces `vertical_fov` when given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_length: Option<f32>,
+    #[serde(default)]
+    pub aperture_radius: f32,
+    #[serde(default = "default_focal_distance")]
+    pub focal_distance: f32,
+    #[serde(default)]
+    pub blade_count: u32,
+    // Seconds around the frame, see `SceneCamera::shutter_open`
+    #[serde(default)]
+    pub shutter_open: f32,
+    #[serde(default)]
+    pub shutter_close: f32,
+}
+
+// Generated paths are resolved against the scene's camera when the file is loaded and saved as
+// their keyframes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CameraPathDesc {
+    Keyframes {
+        keyframes: Vec<CameraKeyframeDesc>,
+        #[serde(default)]
+        looping: bool,
+    },
+    // See `CameraPath::orbit`
+    Orbit {
+        center: [f32; 3],
+        radius: f32,
+        #[serde(default)]
+        height: f32,
+        #[serde(default)]
+        start_angle: f32,
+        #[serde(default = "default_end_angle")]
+        end_angle: f32,
+        duration: f32,
+    },
+    // Once around the point the scene's camera is focused on, see `CameraPath::turntable`
+    Turntable {
+        duration: f32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraKeyframeDesc {
+    pub time: f32,
+    pub position: [f32; 3],
+    pub front: [f32; 3],
+    #[serde(default = "default_up")]
+    pub up: [f32; 3],
+    // Left out, they are taken from the scene's camera
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vertical_fov: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_distance: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaterialDesc {
+    pub albedo: [f32; 3],
+    pub roughness: f32,
+    pub metallic: f32,
+    pub emission: [f32; 3],
+    pub ior: f32,
+    pub transmission: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keyframes: Vec<MaterialKeyframeDesc>,
+}
+
+// Parameters left out keep following their other keyframes, or the values above without any
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialKeyframeDesc {
+    pub time: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub albedo: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roughness: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emission: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ior: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transmission: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SphereDesc {
+    pub position: [f32; 3],
+    pub radius: f32,
+    pub material: String,
+    // Keyframes replace it with their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<MotionDesc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keyframes: Vec<SphereKeyframeDesc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SphereKeyframeDesc {
+    pub time: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshDesc {
+    pub positions: Vec<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub normals: Vec<[f32; 3]>,
+    pub indices: Vec<[u32; 3]>,
+    pub material: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motion: Option<MotionDesc>,
+}
+
+// Where a sphere or mesh is moved to while the camera's shutter is open, see `Motion`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MotionDesc {
+    // What rotation and scale are around, the sphere's position or the centre of the mesh when
+    // left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pivot: Option<[f32; 3]>,
+    // When the shutter opens and when it closes
+    #[serde(default)]
+    pub start: TransformDesc,
+    #[serde(default)]
+    pub end: TransformDesc,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformDesc {
+    pub translation: [f32; 3],
+    // Degrees around X, Y and Z
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum LightDesc {
+    Point {
+        position: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        #[serde(default)]
+        range: f32,
+    },
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        #[serde(default)]
+        range: f32,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvironmentDesc {
+    pub sky_horizon: [f32; 3],
+    pub sky_zenith: [f32; 3],
+    pub ground: [f32; 3],
+    pub intensity: f32,
+    // Equirectangular .hdr or .exr image relative to the scene file, replaces the gradient
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub map: Option<PathBuf>,
+    // Degrees the map is turned by around the up axis
+    pub rotation: f32,
+    // Physical sky with a sun that replaces the gradient, an alternative to `map`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sky: Option<SkyDesc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SkyDesc {
+    // Degrees
+    pub sun_elevation: f32,
+    pub sun_azimuth: f32,
+    pub turbidity: f32,
+    pub sun_intensity: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettingsDesc {
+    pub max_bounces: u32,
+    pub samples_per_pixel: u32,
+    pub samples_per_frame: u32,
+    pub debug_view: DebugViewDesc,
+    pub next_event_estimation: bool,
+    pub depth_of_field: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DebugViewDesc {
+    Off,
+    Normals,
+    Albedo,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ProjectionDesc {
+    #[default]
+    Perspective,
+    Orthographic,
+    Equirectangular,
+    Fisheye,
+}
+
+// `SceneCamera::new` normalizes both and crosses them for the right axis
+fn check_orientation(front: [f32; 3], up: [f32; 3]) -> Result<(), String> {
+    let (front, up) = (Vec3::from(front), Vec3::from(up));
+    if front == Vec3::ZERO || up == Vec3::ZERO {
+        return Err("has a zero length front or up".to_string());
+    }
+    if front.normalize().cross(up.normalize()).length_squared() < 1e-8 {
+        return Err("looks along its up direction".to_string());
+    }
+    Ok(())
+}
+
+fn check_lens(vertical_fov: f32, focal_distance: f32) -> Result<(), String> {
+    if !(vertical_fov > 0.0 && vertical_fov < 180.0) {
+        return Err(format!(
+            "has a vertical fov of {vertical_fov} degrees, it has to be between 0 and 180"
+        ));
+    }
+    if focal_distance < 0.0 {
+        return Err("has a negative focal distance".to_string());
+    }
+    Ok(())
+}
+
+// Keyframes have to be listed in order
+fn check_keyframe_times(times: &[f32]) -> Result<(), String> {
+    match (1..times.len()).find(|&i| times[i] <= times[i - 1]) {
+        Some(i) => Err(format!("keyframe {i} is not later than the one before it")),
+        None => Ok(()),
+    }
+}
+
+// The keyframes that set a value, the others are skipped
+fn track<T>(keyframes: impl Iterator<Item = (f32, Option<T>)>) -> Track<T> {
+    Track {
+        keyframes: keyframes
+            .filter_map(|(time, value)| Some((time, value?)))
+            .collect(),
+    }
+}
+
+// Every time any of `times` has a keyframe at, in order, for writing the tracks back out
+fn merged_keyframe_times(times: impl Iterator<Item = f32>) -> Vec<f32> {
+    let mut times: Vec<f32> = times.collect();
+    times.sort_by(f32::total_cmp);
+    times.dedup();
+    times
+}
+
+// `path` as seen from `directory`, both are made absolute first. Stays absolute when they have
+// nothing in common, like paths on different drives.
+fn relative_path(path: &Path, directory: &Path) -> PathBuf {
+    let absolute = |path: &Path| {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+    };
+    let (path, directory) = (absolute(path), absolute(directory));
+
+    let common = path
+        .components()
+        .zip(directory.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative: PathBuf = directory
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .collect();
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_vertical_fov() -> f32 {
+    SceneCamera::default().vertical_fov
+}
+
+fn default_end_angle() -> f32 {
+    360.0
+}
+
+fn default_orthographic_height() -> f32 {
+    SceneCamera::default().orthographic_height
+}
+
+fn default_sensor_height() -> f32 {
+    SceneCamera::default().sensor_height
+}
+
+fn default_focal_distance() -> f32 {
+    SceneCamera::default().focal_distance
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        MaterialDesc::from(&Material::default())
+    }
+}
+
+impl Default for EnvironmentDesc {
+    fn default() -> Self {
+        EnvironmentDesc::from(&Environment::default())
+    }
+}
+
+impl Default for SkyDesc {
+    fn default() -> Self {
+        SkyDesc::from(&PhysicalSky::default())
+    }
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        TransformDesc::from(&MotionTransform::IDENTITY)
+    }
+}
+
+impl Default for RenderSettingsDesc {
+    fn default() -> Self {
+        RenderSettingsDesc::from(&RenderSettings::default())
+    }
+}
+
+impl From<&Material> for MaterialDesc {
+    fn from(material: &Material) -> Self {
+        MaterialDesc {
+            albedo: material.albedo.to_array(),
+            roughness: material.roughness,
+            metallic: material.metallic,
+            emission: material.emission.to_array(),
+            ior: material.ior,
+            transmission: material.transmission,
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+impl From<&MaterialDesc> for Material {
+    fn from(desc: &MaterialDesc) -> Self {
+        Material::new(Vec3::from(desc.albedo), desc.roughness, desc.metallic)
+            .with_emission(Vec3::from(desc.emission))
+            .with_transmission(desc.transmission, desc.ior)
+    }
+}
+
+impl From<&Environment> for EnvironmentDesc {
+    fn from(environment: &Environment) -> Self {
+        EnvironmentDesc {
+            sky_horizon: environment.sky_horizon.to_array(),
+            sky_zenith: environment.sky_zenith.to_array(),
+            ground: environment.ground.to_array(),
+            intensity: environment.intensity,
+            map: None,
+            rotation: environment.rotation.to_degrees(),
+            sky: None,
+        }
+    }
+}
+
+impl From<&EnvironmentDesc> for Environment {
+    fn from(desc: &EnvironmentDesc) -> Self {
+        Environment::gradient(
+            Vec3::from(desc.sky_horizon),
+            Vec3::from(desc.sky_zenith),
+            Vec3::from(desc.ground),
+        )
+        .with_intensity(desc.intensity)
+        .with_rotation(desc.rotation)
+    }
+}
+
+impl From<&PhysicalSky> for SkyDesc {
+    fn from(sky: &PhysicalSky) -> Self {
+        SkyDesc {
+            sun_elevation: sky.sun_elevation,
+            sun_azimuth: sky.sun_azimuth,
+            turbidity: sky.turbidity,
+            sun_intensity: sky.sun_intensity,
+        }
+    }
+}
+
+impl From<&SkyDesc> for PhysicalSky {
+    fn from(desc: &SkyDesc) -> Self {
+        PhysicalSky::new(desc.sun_elevation, desc.sun_azimuth, desc.turbidity)
+            .with_sun_intensity(desc.sun_intensity)
+    }
+}
+
+impl From<&RenderSettings> for RenderSettingsDesc {
+    fn from(settings: &RenderSettings) -> Self {
+        RenderSettingsDesc {
+            max_bounces: settings.max_bounces,
+            samples_per_pixel: settings.samples_per_pixel,
+            samples_per_frame: settings.samples_per_frame,
+            debug_view: match settings.debug_view {
+                DebugView::Off => DebugViewDesc::Off,
+                DebugView::Normals => DebugViewDesc::Normals,
+                DebugView::Albedo => DebugViewDesc::Albedo,
+            },
+            next_event_estimation: settings.next_event_estimation,
+            depth_of_field: settings.depth_of_field,
+        }
+    }
+}
+
+impl From<&RenderSettingsDesc> for RenderSettings {
+    fn from(desc: &RenderSettingsDesc) -> Self {
+        RenderSettings {
+            max_bounces: desc.max_bounces,
+            samples_per_pixel: desc.samples_per_pixel,
+            samples_per_frame: desc.samples_per_frame,
+            debug_view: match desc.debug_view {
+                DebugViewDesc::Off => DebugView::Off,
+                DebugViewDesc::Normals => DebugView::Normals,
+                DebugViewDesc::Albedo => DebugView::Albedo,
+            },
+            next_event_estimation: desc.next_event_estimation,
+            depth_of_field: desc.depth_of_field,
+        }
+    }
+}
+
+impl From<&SceneCamera> for CameraDesc {
+    fn from(camera: &SceneCamera) -> Self {
+        CameraDesc {
+            position: camera.position.to_array(),
+            front: camera.front.to_array(),
+            up: camera.up.to_array(),
+            projection: match camera.projection {
+                CameraProjection::Perspective => ProjectionDesc::Perspective,
+                CameraProjection::Orthographic => ProjectionDesc::Orthographic,
+                CameraProjection::Equirectangular => ProjectionDesc::Equirectangular,
+                CameraProjection::Fisheye => ProjectionDesc::Fisheye,
+            },
+            vertical_fov: camera.vertical_fov,
+            orthographic_height: camera.orthographic_height,
+            sensor_height: camera.sensor_height,
+            focal_length: None,
+            aperture_radius: camera.aperture_radius,
+            focal_distance: camera.focal_distance,
+            blade_count: camera.blade_count,
+            shutter_open: camera.shutter_open,
+            shutter_close: camera.shutter_close,
+        }
+    }
+}
+
+impl From<&CameraPath> for CameraPathDesc {
+    fn from(path: &CameraPath) -> Self {
+        CameraPathDesc::Keyframes {
+            keyframes: path
+                .keyframes
+                .iter()
+                .map(|keyframe| CameraKeyframeDesc {
+                    time: keyframe.time,
+                    position: keyframe.position.to_array(),
+                    front: keyframe.front.to_array(),
+                    up: keyframe.up.to_array(),
+                    vertical_fov: Some(keyframe.vertical_fov),
+                    focal_distance: Some(keyframe.focal_distance),
+                })
+                .collect(),
+            looping: path.looping,
+        }
+    }
+}
+
+impl MaterialDesc {
+    fn to_animation(&self, material_index: u32) -> Result<MaterialAnimation, String> {
+        let times: Vec<f32> = self
+            .keyframes
+            .iter()
+            .map(|keyframe| keyframe.time)
+            .collect();
+        check_keyframe_times(&times)?;
+        let keyframes = || self.keyframes.iter();
+        Ok(MaterialAnimation {
+            material_index,
+            albedo: track(keyframes().map(|k| (k.time, k.albedo.map(Vec3::from)))),
+            roughness: track(keyframes().map(|k| (k.time, k.roughness))),
+            metallic: track(keyframes().map(|k| (k.time, k.metallic))),
+            emission: track(keyframes().map(|k| (k.time, k.emission.map(Vec3::from)))),
+            ior: track(keyframes().map(|k| (k.time, k.ior))),
+            transmission: track(keyframes().map(|k| (k.time, k.transmission))),
+        })
+    }
+}
+
+impl MaterialKeyframeDesc {
+    fn from_animation(animation: &MaterialAnimation) -> Vec<Self> {
+        let times = merged_keyframe_times(
+            animation
+                .albedo
+                .times()
+                .chain(animation.roughness.times())
+                .chain(animation.metallic.times())
+                .chain(animation.emission.times())
+                .chain(animation.ior.times())
+                .chain(animation.transmission.times()),
+        );
+        times
+            .into_iter()
+            .map(|time| MaterialKeyframeDesc {
+                time,
+                albedo: animation
+                    .albedo
+                    .value_at_keyframe(time)
+                    .map(<[f32; 3]>::from),
+                roughness: animation.roughness.value_at_keyframe(time),
+                metallic: animation.metallic.value_at_keyframe(time),
+                emission: animation
+                    .emission
+                    .value_at_keyframe(time)
+                    .map(<[f32; 3]>::from),
+                ior: animation.ior.value_at_keyframe(time),
+                transmission: animation.transmission.value_at_keyframe(time),
+            })
+            .collect()
+    }
+}
+
+impl SphereDesc {
+    fn to_animation(&self, sphere_index: u32) -> Result<SphereAnimation, String> {
+        let times: Vec<f32> = self
+            .keyframes
+            .iter()
+            .map(|keyframe| keyframe.time)
+            .collect();
+        check_keyframe_times(&times)?;
+        if self
+            .keyframes
+            .iter()
+            .any(|keyframe| keyframe.radius.is_some_and(|radius| radius <= 0.0))
+        {
+            return Err("a keyframe has a non positive radius".to_string());
+        }
+        let keyframes = || self.keyframes.iter();
+        Ok(SphereAnimation {
+            sphere_index,
+            position: track(keyframes().map(|k| (k.time, k.position.map(Vec3::from)))),
+            radius: track(keyframes().map(|k| (k.time, k.radius))),
+        })
+    }
+}
+
+impl SphereKeyframeDesc {
+    fn from_animation(animation: &SphereAnimation) -> Vec<Self> {
+        let times =
+            merged_keyframe_times(animation.position.times().chain(animation.radius.times()));
+        times
+            .into_iter()
+            .map(|time| SphereKeyframeDesc {
+                time,
+                position: animation
+                    .position
+                    .value_at_keyframe(time)
+                    .map(<[f32; 3]>::from),
+                radius: animation.radius.value_at_keyframe(time),
+            })
+            .collect()
+    }
+}
+
+impl From<&MotionTransform> for TransformDesc {
+    fn from(transform: &MotionTransform) -> Self {
+        let (x, y, z) = transform.quat().to_euler(EulerRot::XYZ);
+        TransformDesc {
+            translation: transform.translation.to_array(),
+            rotation: [x, y, z].map(f32::to_degrees),
+            scale: transform.scale.to_array(),
+        }
+    }
+}
+
+impl TransformDesc {
+    // A zero scale could not be undone to intersect the primitive, a negative one would turn it
+    // inside out
+    fn to_transform(&self) -> Result<MotionTransform, String> {
+        if self.scale.iter().any(|&scale| scale <= 0.0) {
+            return Err("the motion has a non positive scale".to_string());
+        }
+        let [x, y, z] = self.rotation.map(f32::to_radians);
+        Ok(MotionTransform::new(
+            Vec3::from(self.translation),
+            Quat::from_euler(EulerRot::XYZ, x, y, z),
+            Vec3::from(self.scale),
+        ))
+    }
+}
+
+impl MotionDesc {
+    // `pivot` is the one the file may leave out
+    fn to_motion(&self, pivot: Vec3) -> Result<Motion, String> {
+        Ok(Motion::new(
+            self.pivot.map_or(pivot, Vec3::from),
+            self.start.to_transform()?,
+            self.end.to_transform()?,
+        ))
+    }
+
+    // None for primitives that stay in place
+    fn from_motion(motion: &Motion, pivot: Vec3) -> Option<Self> {
+        (!motion.is_static()).then(|| MotionDesc {
+            pivot: (motion.pivot != pivot).then_some(motion.pivot.to_array()),
+            start: TransformDesc::from(&motion.start),
+            end: TransformDesc::from(&motion.end),
+        })
+    }
+}
+
+impl CameraPathDesc {
+    // `camera` fills in what the keyframes leave out and is what a turntable circles around
+    fn to_path(&self, camera: &SceneCamera) -> Result<CameraPath, String> {
+        match *self {
+            CameraPathDesc::Keyframes {
+                ref keyframes,
+                looping,
+            } => {
+                if keyframes.is_empty() {
+                    return Err("the camera path has no keyframes".to_string());
+                }
+                let times: Vec<f32> = keyframes.iter().map(|keyframe| keyframe.time).collect();
+                check_keyframe_times(&times).map_err(|message| format!("camera {message}"))?;
+                for (i, desc) in keyframes.iter().enumerate() {
+                    check_orientation(desc.front, desc.up)
+                        .and_then(|()| {
+                            check_lens(
+                                desc.vertical_fov.unwrap_or(camera.vertical_fov),
+                                desc.focal_distance.unwrap_or(camera.focal_distance),
+                            )
+                        })
+                        .map_err(|message| format!("camera keyframe {i} {message}"))?;
+                }
+                Ok(CameraPath {
+                    keyframes: keyframes
+                        .iter()
+                        .map(|desc| CameraKeyframe {
+                            time: desc.time,
+                            position: Vec3::from(desc.position),
+                            front: Vec3::from(desc.front).normalize(),
+                            up: Vec3::from(desc.up).normalize(),
+                            vertical_fov: desc.vertical_fov.unwrap_or(camera.vertical_fov),
+                            focal_distance: desc.focal_distance.unwrap_or(camera.focal_distance),
+                        })
+                        .collect(),
+                    looping,
+                })
+            }
+            CameraPathDesc::Orbit {
+                center,
+                radius,
+                height,
+                start_angle,
+                end_angle,
+                duration,
+            } => {
+                if radius <= 0.0 || duration <= 0.0 {
+                    return Err("the camera orbit needs a positive radius and duration".to_string());
+                }
+                Ok(CameraPath::orbit(
+                    Vec3::from(center),
+                    radius,
+                    height,
+                    start_angle,
+                    end_angle,
+                    duration,
+                    camera.vertical_fov,
+                ))
+            }
+            CameraPathDesc::Turntable { duration } => {
+                if duration <= 0.0 {
+                    return Err("the turntable needs a positive duration".to_string());
+                }
+                Ok(CameraPath::turntable(camera, duration))
+            }
+        }
+    }
+}
+
+impl TryFrom<&CameraDesc> for SceneCamera {
+    type Error = String;
+
+    fn try_from(desc: &CameraDesc) -> Result<Self, String> {
+        check_orientation(desc.front, desc.up)?;
+        if desc.aperture_radius < 0.0 {
+            return Err("has a negative aperture radius".to_string());
+        }
+        if desc
+            .focal_length
+            .is_some_and(|focal_length| focal_length <= 0.0)
+        {
+            return Err("has a non positive focal length".to_string());
+        }
+        if desc.shutter_open > desc.shutter_close {
+            return Err("has its shutter close before it opens".to_string());
+        }
+        let mut camera = SceneCamera {
+            projection: match desc.projection {
+                ProjectionDesc::Perspective => CameraProjection::Perspective,
+                ProjectionDesc::Orthographic => CameraProjection::Orthographic,
+                ProjectionDesc::Equirectangular => CameraProjection::Equirectangular,
+                ProjectionDesc::Fisheye => CameraProjection::Fisheye,
+            },
+            vertical_fov: desc.vertical_fov,
+            orthographic_height: desc.orthographic_height,
+            sensor_height: desc.sensor_height,
+            aperture_radius: desc.aperture_radius,
+            focal_distance: desc.focal_distance,
+            blade_count: desc.blade_count,
+            shutter_open: desc.shutter_open,
+            shutter_close: desc.shutter_close,
+            ..SceneCamera::new(
+                Vec3::from(desc.position),
+                Vec3::from(desc.front),
+                Vec3::from(desc.up),
+            )
+        };
+        if let Some(focal_length) = desc.focal_length {
+            camera.set_focal_length(focal_length);
+        }
+        check_lens(camera.vertical_fov, camera.focal_distance)?;
+        Ok(camera)
+    }
+}
+
+impl From<&Light> for LightDesc {
+    fn from(light: &Light) -> Self {
+        let color = light.color.to_array();
+        match light.light_type {
+            t if t == LightType::Directional as u32 => LightDesc::Directional {
+                direction: light.direction.to_array(),
+                color,
+                intensity: light.intensity,
+            },
+            t if t == LightType::Spot as u32 => LightDesc::Spot {
+                position: light.position.to_array(),
+                direction: light.direction.to_array(),
+                color,
+                intensity: light.intensity,
+                range: light.range,
+                inner_cone_angle: light.inner_cone_cos.acos(),
+                outer_cone_angle: light.outer_cone_cos.acos(),
+            },
+            _ => LightDesc::Point {
+                position: light.position.to_array(),
+                color,
+                intensity: light.intensity,
+                range: light.range,
+            },
+        }
+    }
+}
+
+impl From<&LightDesc> for Light {
+    fn from(desc: &LightDesc) -> Self {
+        match *desc {
+            LightDesc::Point {
+                position,
+                color,
+                intensity,
+                range,
+            } => Light::point(Vec3::from(position), Vec3::from(color), intensity).with_range(range),
+            LightDesc::Directional {
+                direction,
+                color,
+                intensity,
+            } => Light::directional(Vec3::from(direction), Vec3::from(color), intensity),
+            LightDesc::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                range,
+                inner_cone_angle,
+                outer_cone_angle,
+            } => Light::spot(
+                Vec3::from(position),
+                Vec3::from(direction),
+                Vec3::from(color),
+                intensity,
+                inner_cone_angle,
+                outer_cone_angle,
+            )
+            .with_range(range),
+        }
+    }
+}
+
+impl SceneFile {
+    pub fn read(path: &Path, format: SceneFileFormat) -> Result<SceneFile, SceneLoadError> {
+        let source = fs::read_to_string(path).map_err(|error| SceneLoadError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let file: SceneFile = match format {
+            SceneFileFormat::Ron => {
+                ron::from_str(&source).map_err(|error| SceneLoadError::Ron {
+                    path: path.to_path_buf(),
+                    error,
+                })?
+            }
+            SceneFileFormat::Json => {
+                serde_json::from_str(&source).map_err(|error| SceneLoadError::Json {
+                    path: path.to_path_buf(),
+                    error,
+                })?
+            }
+        };
+
+        if file.version > SCENE_FILE_VERSION {
+            return Err(SceneLoadError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version: file.version,
+            });
+        }
+        Ok(file)
+    }
+
+    pub fn write(&self, path: &Path, format: SceneFileFormat) -> Result<(), SceneSaveError> {
+        let contents = match format {
+            SceneFileFormat::Ron => {
+                let config = ron::ser::PrettyConfig::new().struct_names(false);
+                ron::ser::to_string_pretty(self, config).map_err(SceneSaveError::Ron)?
+            }
+            SceneFileFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(SceneSaveError::Json)?
+            }
+        };
+
+        fs::write(path, contents).map_err(|error| SceneSaveError::Io {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    // `path` is where the file goes, the environment map is referenced relative to it
+    pub fn from_scene(scene: &Scene, path: &Path) -> SceneFile {
+        let material_name = |index: u32| format!("material_{index}");
+        let sphere_animation = |index: usize| {
+            scene
+                .sphere_animations
+                .iter()
+                .find(|animation| animation.sphere_index as usize == index)
+        };
+        let material_animation = |index: usize| {
+            scene
+                .material_animations
+                .iter()
+                .find(|animation| animation.material_index as usize == index)
+        };
+
+        SceneFile {
+            version: SCENE_FILE_VERSION,
+            camera: scene.camera.as_ref().map(CameraDesc::from),
+            camera_path: scene.camera_path.as_ref().map(CameraPathDesc::from),
+            materials: scene
+                .materials
+                .iter()
+                .enumerate()
+                .map(|(i, material)| {
+                    let mut desc = MaterialDesc::from(material);
+                    if let Some(animation) = material_animation(i) {
+                        desc.keyframes = MaterialKeyframeDesc::from_animation(animation);
+                    }
+                    (material_name(i as u32), desc)
+                })
+                .collect(),
+            spheres: scene
+                .spheres
+                .iter()
+                .enumerate()
+                .map(|(i, sphere)| {
+                    let animation = sphere_animation(i);
+                    SphereDesc {
+                        position: sphere.position.to_array(),
+                        radius: sphere.radius,
+                        material: material_name(sphere.material_index),
+                        // Keyframed spheres get theirs from the keyframes when loaded
+                        motion: match animation {
+                            Some(_) => None,
+                            None => MotionDesc::from_motion(&sphere.motion, sphere.position),
+                        },
+                        keyframes: animation
+                            .map(SphereKeyframeDesc::from_animation)
+                            .unwrap_or_default(),
+                    }
+                })
+                .collect(),
+            // Imported models are written out inline, the saved file no longer needs the originals
+            meshes: scene
+                .meshes
+                .iter()
+                .map(|mesh| MeshDesc {
+                    positions: mesh.positions.iter().map(|p| p.to_array()).collect(),
+                    normals: mesh.normals.iter().map(|n| n.to_array()).collect(),
+                    indices: mesh.indices.clone(),
+                    material: material_name(mesh.material_index),
+                    motion: MotionDesc::from_motion(&mesh.motion, mesh.centre()),
+                })
+                .collect(),
+            models: Vec::new(),
+            lights: scene.lights.iter().map(LightDesc::from).collect(),
+            environment: EnvironmentDesc {
+                map: scene
+                    .environment_map
+                    .as_ref()
+                    .map(|map| relative_path(&map.path, path.parent().unwrap_or(Path::new("")))),
+                sky: scene.sky.as_ref().map(SkyDesc::from),
+                ..EnvironmentDesc::from(&scene.environment)
+            },
+            render: scene.render_settings.as_ref().map(RenderSettingsDesc::from),
+        }
+    }
+
+    pub fn into_scene(
+        self,
+        path: &Path,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<Scene, SceneLoadError> {
+        let invalid = |message: String| SceneLoadError::Invalid {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let mut scene = Scene::empty();
+        scene.camera = self
+            .camera
+            .as_ref()
+            .map(SceneCamera::try_from)
+            .transpose()
+            .map_err(|message| invalid(format!("the camera {message}")))?;
+        if let Some(desc) = &self.camera_path {
+            let camera = scene.camera.unwrap_or_default();
+            scene.camera_path = Some(desc.to_path(&camera).map_err(invalid)?);
+        }
+        scene.environment = Environment::from(&self.environment);
+        let directory = path.parent().unwrap_or(Path::new(""));
+        if self.environment.map.is_some() && self.environment.sky.is_some() {
+            return Err(invalid(
+                "the environment has both a map and a sky, only one can be used".to_string(),
+            ));
+        }
+        if let Some(map) = &self.environment.map {
+            let map = EnvironmentMap::load(&directory.join(map))?;
+            scene.set_environment_map(Some(Arc::new(map)));
+        }
+        scene.set_sky(self.environment.sky.as_ref().map(PhysicalSky::from));
+        scene.render_settings = self.render.as_ref().map(RenderSettings::from);
+
+        let mut material_indices = BTreeMap::new();
+        for (name, desc) in &self.materials {
+            let material_index = scene.add_material(Material::from(desc));
+            material_indices.insert(name.as_str(), material_index);
+            if !desc.keyframes.is_empty() {
+                let animation = desc
+                    .to_animation(material_index)
+                    .map_err(|message| invalid(format!("material \"{name}\": {message}")))?;
+                scene.material_animations.push(animation);
+            }
+        }
+        let material_index = |name: &str, owner: String| {
+            material_indices
+                .get(name)
+                .copied()
+                .ok_or_else(|| invalid(format!("{owner} uses undefined material \"{name}\"")))
+        };
+
+        for (i, desc) in self.spheres.iter().enumerate() {
+            if desc.radius <= 0.0 {
+                return Err(invalid(format!("sphere {i} has a non positive radius")));
+            }
+            let material = material_index(&desc.material, format!("sphere {i}"))?;
+            if !desc.keyframes.is_empty() {
+                let animation = desc
+                    .to_animation(scene.spheres.len() as u32)
+                    .map_err(|message| invalid(format!("sphere {i}: {message}")))?;
+                scene.sphere_animations.push(animation);
+            }
+            let mut sphere = Sphere::new(Vec3::from(desc.position), desc.radius, material);
+            if let Some(motion) = &desc.motion {
+                sphere.motion = motion
+                    .to_motion(sphere.position)
+                    .map_err(|message| invalid(format!("sphere {i}: {message}")))?;
+            }
+            scene.add_sphere(sphere);
+        }
+
+        for (i, desc) in self.meshes.iter().enumerate() {
+            let material = material_index(&desc.material, format!("mesh {i}"))?;
+            let mut mesh = TriangleMesh::new(
+                desc.positions.iter().copied().map(Vec3::from).collect(),
+                desc.normals.iter().copied().map(Vec3::from).collect(),
+                desc.indices.clone(),
+                material,
+            );
+            mesh.validate()
+                .map_err(|message| invalid(format!("mesh {i} {message}")))?;
+            if let Some(motion) = &desc.motion {
+                mesh.motion = motion
+                    .to_motion(mesh.centre())
+                    .map_err(|message| invalid(format!("mesh {i}: {message}")))?;
+            }
+            scene.add_mesh(mesh);
+        }
+
+        for (i, desc) in self.lights.iter().enumerate() {
+            if let LightDesc::Directional { direction, .. } | LightDesc::Spot { direction, .. } =
+                desc
+            {
+                if Vec3::from(*direction).length_squared() == 0.0 {
+                    return Err(invalid(format!("light {i} has a zero length direction")));
+                }
+            }
+            scene.add_light(Light::from(desc));
+        }
+
+        // Models bring their own materials, their cameras are ignored in favor of the file's
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        loading.push(canonical(path));
+        for model in &self.models {
+            let model_path = directory.join(model);
+            if loading.contains(&canonical(&model_path)) {
+                return Err(invalid(format!(
+                    "model \"{}\" includes the scene it is loaded into",
+                    model.display()
+                )));
+            }
+            scene.merge(Scene::load_model(&model_path, loading)?);
+        }
+        loading.pop();
+
+        Ok(scene)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file of its own for every test, they run in parallel
+    fn scene_path(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("candela-scene-file-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory.join(name)
+    }
+
+    fn load(name: &str, contents: &str) -> Result<Scene, SceneLoadError> {
+        let path = scene_path(name);
+        fs::write(&path, contents).unwrap();
+        Scene::load(&path)
+    }
+
+    fn test_scene() -> Scene {
+        let mut scene = Scene::empty();
+        scene.camera = Some(SceneCamera::new(
+            Vec3::new(0.0, 1.0, -4.0),
+            Vec3::Z,
+            Vec3::Y,
+        ));
+        let red = scene.add_material(Material::new(Vec3::new(0.8, 0.2, 0.2), 0.5, 0.0));
+        let glass =
+            scene.add_material(Material::new(Vec3::ONE, 0.0, 0.0).with_transmission(1.0, 1.5));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, red));
+        let moving = Motion::new(
+            Vec3::new(2.0, 0.0, 4.0),
+            MotionTransform::IDENTITY,
+            MotionTransform::new(Vec3::X, Quat::IDENTITY, Vec3::splat(2.0)),
+        );
+        scene.add_sphere(Sphere::new(Vec3::new(2.0, 0.5, 4.0), 0.5, glass).with_motion(moving));
+        scene.add_mesh(TriangleMesh::new(
+            vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            Vec::new(),
+            vec![[0, 1, 2]],
+            red,
+        ));
+        scene.add_light(Light::point(Vec3::new(2.0, 3.0, 0.0), Vec3::ONE, 20.0).with_range(10.0));
+        scene.add_light(Light::directional(Vec3::NEG_Y, Vec3::ONE, 2.0));
+        scene.environment = Environment::gradient(Vec3::X, Vec3::Y, Vec3::Z).with_intensity(0.5);
+        scene.render_settings = Some(RenderSettings {
+            samples_per_pixel: 64,
+            ..default()
+        });
+        scene
+    }
+
+    fn assert_round_trip(name: &str) {
+        let path = scene_path(name);
+        let scene = test_scene();
+        scene.save(&path).unwrap();
+        let loaded = Scene::load(&path).unwrap();
+
+        assert_eq!(loaded.materials, scene.materials);
+        assert_eq!(loaded.spheres, scene.spheres);
+        assert_eq!(loaded.lights, scene.lights);
+        assert_eq!(loaded.environment, scene.environment);
+        assert_eq!(loaded.render_settings, scene.render_settings);
+        assert!(loaded.camera == scene.camera);
+        assert_eq!(loaded.meshes.len(), 1);
+        assert_eq!(loaded.meshes[0].positions, scene.meshes[0].positions);
+        assert_eq!(loaded.meshes[0].indices, scene.meshes[0].indices);
+        // Saving the loaded scene again gives the same file
+        let first = serde_json::to_string(&SceneFile::from_scene(&scene, &path)).unwrap();
+        let second = serde_json::to_string(&SceneFile::from_scene(&loaded, &path)).unwrap();
+        assert_eq!(first, second);
+    }
+
+    fn invalid_message(result: Result<Scene, SceneLoadError>) -> String {
+        match result {
+            Err(SceneLoadError::Invalid { message, .. }) => message,
+            Err(error) => panic!("expected an invalid scene, got {error}"),
+            Ok(_) => panic!("expected an invalid scene, it loaded"),
+        }
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        assert_round_trip("round_trip.ron");
+    }
+
+    #[test]
+    fn json_round_trip() {
+        assert_round_trip("round_trip.json");
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let version = SCENE_FILE_VERSION + 1;
+        let result = load("newer_version.ron", &format!("(version: {version})"));
+        assert!(
+            matches!(result, Err(SceneLoadError::UnsupportedVersion { version: v, .. }) if v == version),
+            "{:?}",
+            result.err().map(|error| error.to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_field_is_rejected() {
+        let ron = load("unknown_field.ron", "(version: 1, sphere: [])");
+        assert!(matches!(ron, Err(SceneLoadError::Ron { .. })));
+        let json = load("unknown_field.json", r#"{"version": 1, "sphere": []}"#);
+        assert!(matches!(json, Err(SceneLoadError::Json { .. })));
+    }
+
+    #[test]
+    fn scene_including_itself_is_rejected() {
+        let message = invalid_message(load(
+            "includes_itself.json",
+            r#"{"version": 1, "models": ["includes_itself.json"]}"#,
+        ));
+        assert!(message.contains("includes the scene"), "{message}");
+    }
+
+    #[test]
+    fn zero_light_direction_is_rejected() {
+        let message = invalid_message(load(
+            "zero_direction.json",
+            r#"{
+                "version": 1,
+                "lights": [
+                    {"Directional": {"direction": [0, 0, 0], "color": [1, 1, 1], "intensity": 1}}
+                ]
+            }"#,
+        ));
+        assert!(message.contains("zero length direction"), "{message}");
+    }
+
+    #[test]
+    fn broken_cameras_are_rejected() {
+        let cases = [
+            (r#""front": [0, 0, 0]"#, "zero length front or up"),
+            (
+                r#""front": [0, 0, 1], "up": [0, 0, 0]"#,
+                "zero length front or up",
+            ),
+            (r#""front": [0, 2, 0]"#, "looks along its up direction"),
+            (r#""front": [0, 0, 1], "vertical_fov": 0"#, "vertical fov"),
+            (r#""front": [0, 0, 1], "vertical_fov": 180"#, "vertical fov"),
+            (
+                r#""front": [0, 0, 1], "aperture_radius": -0.1"#,
+                "negative aperture radius",
+            ),
+            (
+                r#""front": [0, 0, 1], "focal_distance": -1"#,
+                "negative focal distance",
+            ),
+            (
+                r#""front": [0, 0, 1], "shutter_open": 0.5, "shutter_close": -0.5"#,
+                "shutter close before it opens",
+            ),
+        ];
+        for (i, (fields, expected)) in cases.into_iter().enumerate() {
+            let message = invalid_message(load(
+                &format!("camera_{i}.json"),
+                &format!(r#"{{"version": 1, "camera": {{"position": [0, 0, 0], {fields}}}}}"#),
+            ));
+            assert!(message.contains(expected), "{fields}: {message}");
+        }
+    }
+
+    #[test]
+    fn broken_camera_keyframe_is_rejected() {
+        let message = invalid_message(load(
+            "camera_keyframe.json",
+            r#"{
+                "version": 1,
+                "camera_path": {"Keyframes": {"keyframes": [
+                    {"time": 0, "position": [0, 0, 0], "front": [0, 0, 1]},
+                    {"time": 1, "position": [0, 0, 1], "front": [0, 0, 1], "vertical_fov": 200}
+                ]}}
+            }"#,
+        ));
+        assert!(message.contains("camera keyframe 1"), "{message}");
+    }
+}
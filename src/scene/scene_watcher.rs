@@ -0,0 +1,90 @@
+use std::{path::Path, time::SystemTime};
+
+use bevy::prelude::*;
+
+use crate::{
+    camera::{camera_path::ActiveCameraPath, camera_update::SceneCamera},
+    compute_shader::render_settings::RenderSettings,
+    scene::scene::{apply_loaded_scene, Scene, ScenePath, SCENE_ERROR_SOURCE},
+    ErrorOverlay,
+};
+
+// How often the scene file's modification time is checked
+const POLL_INTERVAL_SECONDS: f32 = 0.5;
+
+pub struct SceneWatcherPlugin;
+impl Plugin for SceneWatcherPlugin {
+    fn build(&self, app: &mut App) {
+        // Registered after `ScenePlugin`, the file is loaded at startup with this version
+        let modified = app
+            .world
+            .get_resource::<ScenePath>()
+            .and_then(|scene_path| scene_path.0.as_deref())
+            .and_then(modified_time);
+        app.insert_resource(SceneWatcher {
+            modified,
+            timer: Timer::from_seconds(POLL_INTERVAL_SECONDS, TimerMode::Repeating),
+        });
+        app.add_systems(Update, reload_changed_scene);
+    }
+}
+
+#[derive(Resource)]
+pub struct SceneWatcher {
+    // Modification time of the scene file when it was last looked at
+    modified: Option<SystemTime>,
+    timer: Timer,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Polls the file given on the command line and reloads it through the `Scene` resource, which
+// re-uploads the buffers in `update_scene_buffers` and restarts the accumulation.
+#[allow(clippy::too_many_arguments)]
+fn reload_changed_scene(
+    time: Res<Time>,
+    scene_path: Res<ScenePath>,
+    mut watcher: ResMut<SceneWatcher>,
+    mut scene: ResMut<Scene>,
+    mut camera: ResMut<SceneCamera>,
+    mut settings: ResMut<RenderSettings>,
+    mut camera_path: ResMut<ActiveCameraPath>,
+    mut overlay: ResMut<ErrorOverlay>,
+) {
+    let Some(path) = &scene_path.0 else {
+        return;
+    };
+    if !watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    // Editors that save by replacing the file can leave it missing for a moment
+    let Some(modified) = modified_time(path) else {
+        return;
+    };
+    if watcher.modified.replace(modified) == Some(modified) {
+        return;
+    }
+
+    match Scene::load(path) {
+        Ok(loaded) => {
+            apply_loaded_scene(
+                loaded,
+                &mut scene,
+                &mut camera,
+                &mut settings,
+                &mut camera_path,
+            );
+            overlay.clear(SCENE_ERROR_SOURCE);
+            println!("reloaded {}", path.display());
+        }
+        Err(error) => {
+            println!("{error}");
+            overlay.set(SCENE_ERROR_SOURCE, error.to_string());
+        }
+    }
+}
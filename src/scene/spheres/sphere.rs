@@ -1,25 +1,47 @@
-use bevy::prelude::*;
-use bytemuck::{Pod, Zeroable};
+use bevy::{prelude::*, render::render_resource::ShaderType};
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
-pub struct Sphere {
-    pub position: Vec3,
-    pub radius: f32,
+use crate::scene::{bvh::bvh::Aabb, motion::Motion};
+
+shader_types! {
+    // Mirrors `struct Sphere` in raytracer.wgsl
+    #[derive(Debug, Clone, Copy, PartialEq, ShaderType)]
+    pub struct Sphere {
+        // At the frame's render time
+        pub position: Vec3,
+        pub radius: f32,
+        // Moves the sphere while the camera's shutter is open, non uniform scales stretch it
+        pub motion: Motion,
+        pub material_index: u32,
+    }
 }
 
 impl Sphere {
-    pub fn new(position: Vec3, radius: f32) -> Self {
+    pub fn new(position: Vec3, radius: f32, material_index: u32) -> Self {
         Sphere {
             position,
             radius: radius.abs(),
+            motion: Motion::STATIC,
+            material_index,
         }
     }
+
+    pub fn with_motion(mut self, motion: Motion) -> Self {
+        self.motion = motion;
+        self
+    }
+
+    // Everywhere the sphere is while the camera's shutter is open
+    pub fn aabb(&self) -> Aabb {
+        self.motion.swept_bounds(Aabb::new(
+            self.position - Vec3::splat(self.radius),
+            self.position + Vec3::splat(self.radius),
+        ))
+    }
 }
 
 pub fn init_spheres() -> Vec<Sphere> {
     vec![
-        Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0),
-        Sphere::new(Vec3::new(4.0, 0.0, 5.0), 2.0),
+        Sphere::new(Vec3::new(0.0, 0.0, 5.0), 1.0, 0),
+        Sphere::new(Vec3::new(4.0, 0.0, 5.0), 2.0, 1),
     ]
-}
\ No newline at end of file
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+
+pub struct OverlayPlugin;
+impl Plugin for OverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ErrorOverlay>();
+        app.add_systems(Startup, spawn_error_overlay);
+        app.add_systems(PostUpdate, update_error_overlay);
+    }
+}
+
+// Errors shown on top of the render until their source clears them, keyed by where they came
+// from so a fixed scene file does not hide a still broken shader and vice versa.
+#[derive(Resource, Default)]
+pub struct ErrorOverlay {
+    messages: BTreeMap<&'static str, String>,
+}
+
+impl ErrorOverlay {
+    pub fn set(&mut self, source: &'static str, message: impl Into<String>) {
+        self.messages.insert(source, message.into());
+    }
+
+    pub fn clear(&mut self, source: &'static str) {
+        self.messages.remove(source);
+    }
+}
+
+#[derive(Component)]
+struct ErrorOverlayText;
+
+fn spawn_error_overlay(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::rgb(1.0, 0.35, 0.3),
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            left: Val::Px(8.0),
+            ..default()
+        })
+        .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.75)),
+        ErrorOverlayText,
+    ));
+}
+
+fn update_error_overlay(
+    overlay: Res<ErrorOverlay>,
+    mut text: Query<(&mut Text, &mut Visibility), With<ErrorOverlayText>>,
+) {
+    if !overlay.is_changed() {
+        return;
+    }
+
+    for (mut text, mut visibility) in text.iter_mut() {
+        let messages: Vec<String> = overlay
+            .messages
+            .iter()
+            .map(|(source, message)| format!("[{source}] {message}"))
+            .collect();
+        text.sections[0].value = messages.join("\n");
+        *visibility = if messages.is_empty() {
+            Visibility::Hidden
+        } else {
+            Visibility::Visible
+        };
+    }
+}
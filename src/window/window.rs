@@ -36,7 +36,10 @@ fn update_window_size_main(
     previous_size: Res<PreviousWindowSize>,
     mut ev_window_resized: EventWriter<ResizedWindowEvent>
 ) {
-    let window = windows.single();
+    // The offline renderer runs without a window and sets `WindowSize` itself
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
     let resolution = Vec2::new(
         window.resolution.width(),
         window.resolution.height(),
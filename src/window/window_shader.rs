@@ -1,37 +1,24 @@
-use bevy::{
-    prelude::*,
-    render::render_resource::{
-        Extent3d, TextureDimension, TextureFormat, TextureUsages,
-    },
-};
+use bevy::prelude::*;
 
 use crate::{
-    compute_shader::lib::buffers_setup::ComputeImage, BufferType, ComputeBuffers,
-    ResizedWindowEvent, WindowSize,
+    compute_shader::lib::buffers_setup::{allocate_compute_textures, DISPLAY_TEXTURE},
+    ComputeBuffers, ComputeTextures, ResizedWindowEvent, WindowSize, SCREEN_ASPECT_RATIO_BUFFER,
+    SCREEN_RESOLUTION_BUFFER,
 };
 
 pub fn update_window_buffers(
-    mut commands: Commands,
     mut compute_buffers: ResMut<ComputeBuffers>,
     resolution: Res<WindowSize>,
 ) {
     let resolution = resolution.0;
     let aspect_ratio = resolution.x / resolution.y;
 
-    compute_buffers.set_value_at(
-        BufferType::ScreenResolution as u32,
-        vec![resolution],
-        &mut commands,
-    );
-    compute_buffers.set_value_at(
-        BufferType::ScreenAspectRatio as u32,
-        vec![aspect_ratio],
-        &mut commands,
-    );
+    compute_buffers.set_value_at(SCREEN_RESOLUTION_BUFFER, resolution);
+    compute_buffers.set_value_at(SCREEN_ASPECT_RATIO_BUFFER, aspect_ratio);
 }
 
 pub fn update_camera_texture_size(
-    mut compute_image: ResMut<ComputeImage>,
+    mut compute_textures: ResMut<ComputeTextures>,
     images: Option<ResMut<Assets<Image>>>,
     resolution: Res<WindowSize>,
     mut sprites: Query<(&mut Sprite, &mut Handle<Image>)>,
@@ -39,26 +26,18 @@ pub fn update_camera_texture_size(
 ) {
     if !ev_window_resized.is_empty() {
         if let Some(mut images) = images {
-            let mut image_texture = Image::new_fill(
-                Extent3d {
-                    width: resolution.0.x as u32,
-                    height: resolution.0.y as u32,
-                    depth_or_array_layers: 1,
-                },
-                TextureDimension::D2,
-                &[0, 0, 0, 255],
-                TextureFormat::Rgba8Unorm,
+            allocate_compute_textures(
+                &mut compute_textures,
+                &mut images,
+                resolution.0.as_uvec2(),
             );
-            image_texture.texture_descriptor.usage = TextureUsages::COPY_DST
-                | TextureUsages::STORAGE_BINDING
-                | TextureUsages::TEXTURE_BINDING;
-            let image_handle = images.add(image_texture);
 
             let mut sprite = sprites.single_mut();
             sprite.0.custom_size = Some(resolution.0);
-            *sprite.1 = image_handle.clone();
-
-            *compute_image = ComputeImage(image_handle);
+            *sprite.1 = compute_textures
+                .image(DISPLAY_TEXTURE)
+                .cloned()
+                .unwrap_or_default();
         } else {
             println!("AAAAAAAA FUCK SOMETHING'S WRONG HERE HELP RIGGED");
         }